@@ -282,7 +282,9 @@ return {
                     diagnostics.selene.with { -- Lua
                         extra_args = {
                             '--config',
-                            vim.env.HOME .. '/dotfiles/selene.toml',
+                            -- init.lua is linked from files/.config/nvim in the dotfile repo.
+                            vim.fn.fnamemodify(vim.fn.resolve(vim.fn.stdpath 'config' .. '/init.lua'), ':h:h:h:h')
+                                .. '/selene.toml',
                         },
                     },
                     diagnostics.shellcheck, -- Bash/sh
@@ -0,0 +1,189 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use crate::backup::{BackupStore, Run};
+use crate::error::{InstallError, Result};
+use crate::install::{file_exists, get_file_type};
+use crate::path::*;
+use crate::plan::{Action, Plan};
+use crate::repo::Repo;
+
+/// Plans moving `path` from the home directory into `layer` in `repo`, and replacing it with a
+/// symlink to where it ends up. The original is backed up to `store` first. An existing dotfile at
+/// the same path is only replaced if `force` is set. If `git_add` is set, the new dotfile is staged.
+pub fn plan_adopt(
+    home_dir: &HomePath,
+    repo: &Repo,
+    store: &BackupStore,
+    layer: &DotfilesPath,
+    path: &Path,
+    force: bool,
+    git_add: bool,
+) -> Result<Plan> {
+    let file = home_dir.with_rel_file(&relative_to_home(home_dir, repo, path)?);
+    let file_type = get_file_type(&file)?;
+    if file_type.is_symlink() {
+        return Err(cant_adopt(&file, "it's a symlink"));
+    } else if !file_type.is_file() {
+        return Err(cant_adopt(&file, "only regular files can be adopted"));
+    }
+
+    let dotfile = layer.with_rel_file(file.rel_file());
+    if file_exists(&dotfile) {
+        if get_file_type(&dotfile)?.is_dir() {
+            return Err(cant_adopt(&file, "there's a directory in the way in the repo"));
+        } else if !force {
+            return Err(InstallError::DotfileExists(dotfile.to_path_buf()));
+        }
+        log::warn!("Replacing {} since --force was given", dotfile);
+    }
+
+    let backup = store.new_backup();
+    let mut plan = Plan::new();
+    plan.push(Action::CreateBackup(backup.clone(), Run::new("adopt", repo)));
+    plan.push(Action::Backup(file.clone(), backup));
+    plan.push(Action::Adopt(file.clone(), dotfile.clone()));
+    plan.push(Action::Symlink {
+        target: dotfile.to_path_buf(),
+        link: file,
+    });
+    if git_add {
+        plan.push(Action::GitAdd {
+            repo_root: repo.root().to_path_buf(),
+            file: dotfile.to_path_buf(),
+        });
+    }
+    Ok(plan)
+}
+
+/// Works out where `path` is in `home_dir`. Relative paths are relative to the current directory.
+/// Symlinks in the parent directories are resolved, but not the file itself.
+fn relative_to_home(home_dir: &HomePath, repo: &Repo, path: &Path) -> Result<RelPath> {
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir()
+            .map_err(InstallError::io("get", "the current directory"))?
+            .join(path)
+    };
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(InstallError::CantAdopt {
+            path,
+            reason: "it's not a file".to_string(),
+        });
+    };
+    let path = canonicalize(parent)?.join(name);
+
+    if path.starts_with(canonicalize(repo.root())?) {
+        return Err(InstallError::CantAdopt {
+            path,
+            reason: "it's already in the dotfile repo".to_string(),
+        });
+    }
+    match path.strip_prefix(canonicalize(home_dir.as_path())?) {
+        Ok(rel_path) => Ok(RelPath::new(rel_path)),
+        Err(_) => Err(InstallError::CantAdopt {
+            path,
+            reason: format!("it's not in {}", home_dir),
+        }),
+    }
+}
+
+fn canonicalize(path: &Path) -> Result<PathBuf> {
+    path.canonicalize().map_err(InstallError::io("resolve", path))
+}
+
+fn cant_adopt(file: &HomeFile, reason: &str) -> InstallError {
+    InstallError::CantAdopt {
+        path: file.to_path_buf(),
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io};
+
+    use super::*;
+    use crate::test_util::Fixture;
+
+    #[test]
+    fn adopts_file() -> io::Result<()> {
+        let home = Fixture::new()?;
+        let home_dir = home.home_dir();
+        let repo = home.repo("dotfiles")?;
+        fs::create_dir(home.path().join(".config"))?;
+        fs::write(home.path().join(".config/foo"), "foo")?;
+
+        let store_dir = Fixture::new()?;
+        let store = store_dir.store();
+        let plan = plan_adopt(
+            &home_dir,
+            &repo,
+            &store,
+            repo.files(),
+            &home.path().join(".config/foo"),
+            false,
+            false,
+        )
+        .unwrap();
+        plan.execute().unwrap();
+
+        let dotfile = repo.files().as_path().join(".config/foo");
+        assert_eq!(fs::read_to_string(&dotfile)?, "foo");
+        assert_eq!(fs::read_link(home.path().join(".config/foo"))?, dotfile);
+        assert_eq!(store.backups().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_to_overwrite_dotfile() -> io::Result<()> {
+        let home = Fixture::new()?;
+        let home_dir = home.home_dir();
+        let repo = home.repo("dotfiles")?;
+        fs::write(home.path().join(".zshrc"), "new")?;
+        fs::write(repo.files().as_path().join(".zshrc"), "old")?;
+        let store_dir = Fixture::new()?;
+        let store = store_dir.store();
+        let adopt = |force| {
+            plan_adopt(
+                &home_dir,
+                &repo,
+                &store,
+                repo.files(),
+                &home.path().join(".zshrc"),
+                force,
+                false,
+            )
+        };
+
+        assert!(matches!(adopt(false), Err(InstallError::DotfileExists(_))));
+        adopt(true).unwrap().execute().unwrap();
+        assert_eq!(fs::read_to_string(repo.files().as_path().join(".zshrc"))?, "new");
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_files_outside_home() -> io::Result<()> {
+        let home = Fixture::new()?;
+        let other = Fixture::new()?;
+        let repo = home.repo("dotfiles")?;
+        fs::write(other.path().join("foo"), "foo")?;
+        fs::write(repo.files().as_path().join("bar"), "bar")?;
+        let store_dir = Fixture::new()?;
+        let store = store_dir.store();
+        let adopt = |path: &Path| plan_adopt(&home.home_dir(), &repo, &store, repo.files(), path, false, false);
+
+        assert!(matches!(
+            adopt(&other.path().join("foo")),
+            Err(InstallError::CantAdopt { .. })
+        ));
+        assert!(matches!(
+            adopt(&repo.files().as_path().join("bar")),
+            Err(InstallError::CantAdopt { .. })
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    fs::{self, File, Metadata, OpenOptions},
+    io::{self, BufReader, ErrorKind, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use flate2::{bufread::MultiGzDecoder, write::GzEncoder, Compression};
+use sha2::{Digest, Sha256};
+use tar::{Archive, Builder, Header, HeaderMode};
+
+use crate::backup::BackupFormat;
+use crate::error::{InstallError, Result};
+
+/// Appends the files at `files` in `home_dir` to the archive at `archive`, creating it if it doesn't
+/// exist. Every call adds a compressed tar archive to the end of the file, which [`for_each_file`]
+/// reads as if they were one. Returns the SHA-256 hash of each regular file, and `None` for
+/// everything else.
+pub(crate) fn append(
+    archive: &Path,
+    format: BackupFormat,
+    home_dir: &Path,
+    files: &[(PathBuf, Metadata)],
+) -> Result<Vec<Option<String>>> {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(archive)
+        .map_err(InstallError::io("open", archive))?;
+    let len = file
+        .metadata()
+        .map_err(InstallError::io("get metadata for", archive))?
+        .len();
+
+    let result = append_compressed(&mut file, archive, format, home_dir, files);
+    if result.is_err() {
+        // Cut off what was written, so that the archive isn't left with a broken stream in it.
+        if let Err(e) = file.set_len(len) {
+            log::error!("Failed to truncate {}: {}", archive.to_string_lossy(), e);
+        }
+    }
+    result
+}
+
+fn append_compressed(
+    file: &mut File,
+    archive: &Path,
+    format: BackupFormat,
+    home_dir: &Path,
+    files: &[(PathBuf, Metadata)],
+) -> Result<Vec<Option<String>>> {
+    let hashes = match format {
+        BackupFormat::TarZst => {
+            let encoder = zstd::Encoder::new(&mut *file, 0).map_err(InstallError::io("write", archive))?;
+            let (encoder, hashes) = append_tar(encoder, home_dir, files)?;
+            encoder.finish().map_err(InstallError::io("write", archive))?;
+            hashes
+        }
+        BackupFormat::TarGz => {
+            let encoder = GzEncoder::new(&mut *file, Compression::default());
+            let (encoder, hashes) = append_tar(encoder, home_dir, files)?;
+            encoder.finish().map_err(InstallError::io("write", archive))?;
+            hashes
+        }
+        BackupFormat::Dir => unreachable!("{} isn't an archive format", format),
+    };
+    file.sync_all().map_err(InstallError::io("write", archive))?;
+    Ok(hashes)
+}
+
+fn append_tar<W: Write>(writer: W, home_dir: &Path, files: &[(PathBuf, Metadata)]) -> Result<(W, Vec<Option<String>>)> {
+    let mut builder = Builder::new(writer);
+    let mut hashes = Vec::with_capacity(files.len());
+
+    for (rel_path, metadata) in files {
+        let path = home_dir.join(rel_path);
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(metadata, HeaderMode::Complete);
+
+        let hash = if metadata.is_file() {
+            let file = File::open(&path).map_err(InstallError::io("back up", &path))?;
+            let mut reader = HashingReader::new(file.take(metadata.len()));
+            builder
+                .append_data(&mut header, rel_path, &mut reader)
+                .map_err(InstallError::io("back up", &path))?;
+            if reader.len != metadata.len() {
+                let e = io::Error::new(ErrorKind::Other, "the file changed while it was being backed up");
+                return Err(InstallError::io("back up", &path)(e));
+            }
+            Some(reader.finish())
+        } else if metadata.is_symlink() {
+            let target = fs::read_link(&path).map_err(InstallError::io("read symlink", &path))?;
+            builder
+                .append_link(&mut header, rel_path, target)
+                .map_err(InstallError::io("back up", &path))?;
+            None
+        } else {
+            builder
+                .append_data(&mut header, rel_path, io::empty())
+                .map_err(InstallError::io("back up", &path))?;
+            None
+        };
+        hashes.push(hash);
+    }
+
+    let writer = builder
+        .into_inner()
+        .map_err(|e| InstallError::io("back up", home_dir)(e))?;
+    Ok((writer, hashes))
+}
+
+/// Calls `f` with the path, header and contents of each regular file in the archive at `archive`,
+/// until it returns `false`.
+pub(crate) fn for_each_file<F>(archive: &Path, format: BackupFormat, mut f: F) -> io::Result<()>
+where
+    F: FnMut(&Path, &Header, &mut dyn Read) -> io::Result<bool>,
+{
+    let file = BufReader::new(File::open(archive)?);
+    let reader: Box<dyn Read> = match format {
+        BackupFormat::TarZst => Box::new(zstd::Decoder::with_buffer(file)?),
+        BackupFormat::TarGz => Box::new(MultiGzDecoder::new(file)),
+        BackupFormat::Dir => return Err(io::Error::new(ErrorKind::InvalidInput, "not an archive")),
+    };
+    let mut archive = Archive::new(reader);
+    // Every append adds an archive with its own end marker.
+    archive.set_ignore_zeros(true);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        let header = entry.header().clone();
+        if !f(&path, &header, &mut entry)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Hashes everything that's read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    fn finish(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
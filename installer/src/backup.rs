@@ -0,0 +1,1257 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fmt::{self, Display, Formatter},
+    fs::{self, File, Metadata, OpenOptions, Permissions},
+    io::{self, ErrorKind, Read, Write},
+    os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+    process,
+    str::FromStr,
+};
+
+use chrono::{Local, NaiveDateTime};
+use nix::unistd::{self, Uid, User};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::archive;
+use crate::error::{InstallError, Result};
+use crate::install::{self, file_exists};
+use crate::path::*;
+use crate::plan::{Action, Plan};
+use crate::repo::Repo;
+use crate::restore;
+
+/// Where the store is, relative to the XDG data directory.
+const STORE_DIR: &str = "dotfiles-installer/backups";
+const OBJECTS_DIR: &str = "objects";
+const BACKUPS_DIR: &str = "runs";
+const MANIFEST_EXTENSION: &str = "toml";
+const BACKUP_NAME_FORMAT: &str = "%Y-%m-%d_%H:%M:%S";
+const README_FILE: &str = "README.md";
+const README_CONTENTS: &str = include_str!("backup_store_readme.md");
+/// How older versions of the installer named the backup directories that they made in the home
+/// directory.
+const LEGACY_BACKUP_FORMAT: &str = "dotfiles-backup_%Y-%m-%d_%H:%M:%S";
+
+/// Where the installer keeps its backups. `objects/` holds the contents of backed-up files, named by
+/// their SHA-256 hash so that identical files are only stored once, and `runs/` holds a manifest for
+/// each backup that lists what's in it.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BackupStore {
+    root: PathBuf,
+    /// The format of new backups.
+    format: BackupFormat,
+}
+
+/// How the contents of the files in a backup are stored.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum BackupFormat {
+    /// In `objects/` in the store, so that identical files are only stored once.
+    #[default]
+    #[serde(rename = "dir")]
+    Dir,
+    /// In a zstd-compressed tar archive next to the manifest.
+    #[serde(rename = "tar.zst")]
+    TarZst,
+    /// In a gzip-compressed tar archive next to the manifest.
+    #[serde(rename = "tar.gz")]
+    TarGz,
+}
+
+/// A backup of the files that one install replaced.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Backup {
+    store: BackupStore,
+    name: String,
+}
+
+/// A backup directory that an older version of the installer made in the home directory. It holds
+/// plain copies of the files that an install replaced, at the same paths as in the home directory,
+/// and a README.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LegacyBackup {
+    path: PathBuf,
+    timestamp: NaiveDateTime,
+}
+
+/// A file, symlink, or directory in a backup.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// Where the file was, relative to the home directory.
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub kind: EntryKind,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    #[serde(default)]
+    pub atime: i64,
+    #[serde(default)]
+    pub atime_nsec: i64,
+    /// The extended attributes in the `user` namespace, with hex-encoded values. Symlinks can't have
+    /// any.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum EntryKind {
+    /// A regular file, whose contents are in the object named `sha256`.
+    File {
+        sha256: String,
+        mode: u32,
+    },
+    Symlink {
+        target: PathBuf,
+    },
+    /// A directory. The things in it have entries of their own.
+    Dir {
+        mode: u32,
+    },
+}
+
+/// The run of the installer that made a backup.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Run {
+    /// What the installer was doing, like `install` or `adopt`.
+    pub command: String,
+    /// When the run started, in RFC 3339 format.
+    pub started: String,
+    /// The version of the installer.
+    pub version: String,
+    pub hostname: String,
+    pub user: String,
+    pub pid: u32,
+    /// The root of the dotfile repo.
+    pub repo: PathBuf,
+}
+
+/// How much is in a backup.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Summary {
+    /// The number of files and symlinks in the backup.
+    pub files: usize,
+    /// The total size of the files in bytes. Files with the same contents are each counted, even
+    /// though they're only stored once.
+    pub size: u64,
+}
+
+/// Something wrong with a backup, found by [`Backup::verify`].
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Damage {
+    /// The contents of the file at `path` are gone.
+    MissingContents { path: PathBuf, sha256: String },
+    /// The contents of the file at `path` don't match their hash anymore.
+    CorruptContents { path: PathBuf, sha256: String },
+    /// The backup's archive is damaged, so that some files in it can't be read.
+    BrokenArchive(String),
+    /// There's more than one entry for the same path.
+    DuplicateEntry(PathBuf),
+}
+
+/// The contents of a backup's manifest.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default)]
+    format: BackupFormat,
+    run: Option<Run>,
+    #[serde(default, rename = "entry")]
+    entries: Vec<Entry>,
+}
+
+impl BackupStore {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            format: BackupFormat::default(),
+        }
+    }
+
+    /// Makes new backups in the store use `format`.
+    pub fn with_format(self, format: BackupFormat) -> Self {
+        Self { format, ..self }
+    }
+
+    /// Finds the store in `$XDG_DATA_HOME`, or in `~/.local/share` if that isn't set.
+    pub fn find(home_dir: &HomePath) -> Self {
+        let data_dir = env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .unwrap_or_else(|| home_dir.as_path().join(".local/share"));
+        Self::new(data_dir.join(STORE_DIR))
+    }
+
+    pub fn as_path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Finds all backups in the store, sorted from oldest to newest.
+    pub fn backups(&self) -> Result<Vec<Backup>> {
+        let dir = self.root.join(BACKUPS_DIR);
+        let entries = match dir.read_dir() {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(InstallError::io("read directory", &dir)(e)),
+        };
+
+        let mut backups = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(InstallError::io("get directory entry in", &dir))?.path();
+            let Some(name) = path
+                .file_stem()
+                .filter(|_| path.extension() == Some(MANIFEST_EXTENSION.as_ref()))
+                .and_then(|name| name.to_str())
+            else {
+                continue;
+            };
+            let backup = Backup {
+                store: self.clone(),
+                name: name.to_string(),
+            };
+            if backup.timestamp().is_some() {
+                backups.push(backup);
+            }
+        }
+        backups.sort_by_key(|backup| (backup.timestamp(), backup.sequence()));
+        log::debug!("Found {} backups in {}", backups.len(), self);
+        Ok(backups)
+    }
+
+    /// Finds the backup called `name`, which can be either the name of the backup or the path to its
+    /// manifest. Finds the newest backup if `name` is `None`.
+    pub fn find_backup(&self, name: Option<&str>) -> Result<Backup> {
+        let mut backups = self.backups()?;
+        match name {
+            Some(name) => backups
+                .into_iter()
+                .find(|backup| backup.name == name || backup.manifest_path() == Path::new(name))
+                .ok_or_else(|| InstallError::BackupNotFound(name.to_string())),
+            None => backups.pop().ok_or_else(|| InstallError::NoBackups(self.root.clone())),
+        }
+    }
+
+    /// Picks the name of a new backup. Backups are named after when they were made, with a number at
+    /// the end if there's already a backup from the same second.
+    pub(crate) fn new_backup(&self) -> Backup {
+        let timestamp = Local::now().format(BACKUP_NAME_FORMAT).to_string();
+        let mut backup = Backup {
+            store: self.clone(),
+            name: timestamp.clone(),
+        };
+        for i in 2.. {
+            if !backup.manifest_path().exists() {
+                break;
+            }
+            backup.name = format!("{}.{}", timestamp, i);
+        }
+        backup
+    }
+
+    pub(crate) fn object_path(&self, hash: &str) -> PathBuf {
+        self.root.join(OBJECTS_DIR).join(&hash[..2]).join(&hash[2..])
+    }
+
+    /// Creates the store if it doesn't exist. Only the user can read it, since dotfiles can contain
+    /// secrets.
+    fn create(&self) -> Result<()> {
+        if !self.root.exists() {
+            log::info!("Creating backup store {}", self);
+            fs::create_dir_all(&self.root).map_err(InstallError::io("create directory", &self.root))?;
+            fs::set_permissions(&self.root, Permissions::from_mode(0o700))
+                .map_err(InstallError::io("set permissions of", &self.root))?;
+        }
+        for dir in [OBJECTS_DIR, BACKUPS_DIR] {
+            let dir = self.root.join(dir);
+            fs::create_dir_all(&dir).map_err(InstallError::io("create directory", &dir))?;
+        }
+        let readme_file = self.root.join(README_FILE);
+        if !readme_file.exists() {
+            log::debug!("Creating readme file {}", readme_file.to_string_lossy());
+            fs::write(&readme_file, README_CONTENTS).map_err(InstallError::io("create readme file", &readme_file))?;
+        }
+        Ok(())
+    }
+
+    /// Stores the contents of the regular file at `path` and returns their hash. The file is copied
+    /// and hashed in one go, so that the object always matches its name even if the file changes.
+    fn store_object(&self, path: &Path) -> Result<String> {
+        let incoming = self.root.join(OBJECTS_DIR).join(format!(".incoming-{}", process::id()));
+        let hash = File::open(path)
+            .and_then(|mut reader| {
+                let mut writer = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(0o600)
+                    .open(&incoming)?;
+                let hash = copy_and_hash(&mut reader, &mut writer)?;
+                writer.sync_all()?;
+                Ok(hash)
+            })
+            .map_err(InstallError::io("back up", path))?;
+
+        let object = self.object_path(&hash);
+        if object.exists() {
+            log::trace!("{} is already stored as {}", path.to_string_lossy(), hash);
+            fs::remove_file(&incoming).map_err(InstallError::io("remove", &incoming))?;
+        } else {
+            log::trace!("Storing {} as {}", path.to_string_lossy(), hash);
+            let dir = object.parent().unwrap();
+            fs::create_dir_all(dir).map_err(InstallError::io("create directory", dir))?;
+            fs::rename(&incoming, &object).map_err(InstallError::io("store", &object))?;
+        }
+        Ok(hash)
+    }
+
+    /// Copies the object called `hash` to a new file at `to`, checking that it still matches its hash
+    /// on the way.
+    pub(crate) fn copy_object(&self, hash: &str, to: &Path) -> io::Result<()> {
+        copy_checked(&mut File::open(self.object_path(hash))?, hash, to)
+    }
+
+    /// Deletes the objects that no backup refers to anymore.
+    fn remove_unused_objects(&self) -> Result<()> {
+        let mut used = BTreeSet::new();
+        for backup in self.backups()? {
+            let manifest = backup.read_manifest()?;
+            if manifest.format != BackupFormat::Dir {
+                continue;
+            }
+            for entry in manifest.entries {
+                if let EntryKind::File { sha256, .. } = entry.kind {
+                    used.insert(sha256);
+                }
+            }
+        }
+
+        let objects_dir = self.root.join(OBJECTS_DIR);
+        let hashes = install::recurse_through_dir(&objects_dir, |path| {
+            let hash = path
+                .strip_prefix(&objects_dir)
+                .unwrap()
+                .to_string_lossy()
+                .replace('/', "");
+            Ok((path, hash))
+        })?;
+        for (path, hash) in hashes {
+            if !used.contains(&hash) {
+                log::debug!("Deleting unused object {}", hash);
+                fs::remove_file(&path).map_err(InstallError::io("remove", &path))?;
+                // Clean up the directory that the object was in if it's empty now.
+                let _ = fs::remove_dir(path.parent().unwrap());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Backup {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn store(&self) -> &BackupStore {
+        &self.store
+    }
+
+    /// Gets the time when the backup was made from its name.
+    pub fn timestamp(&self) -> Option<NaiveDateTime> {
+        let timestamp = self.name.split('.').next().unwrap();
+        NaiveDateTime::parse_from_str(timestamp, BACKUP_NAME_FORMAT).ok()
+    }
+
+    /// Gets the number that tells apart backups made in the same second.
+    fn sequence(&self) -> u32 {
+        self.name
+            .split_once('.')
+            .and_then(|(_, sequence)| sequence.parse().ok())
+            .unwrap_or(1)
+    }
+
+    /// Gets the path to the archive that holds the contents of the backup's files if it's in one of
+    /// the archive formats.
+    pub fn archive_path(&self, format: BackupFormat) -> PathBuf {
+        self.store
+            .root
+            .join(BACKUPS_DIR)
+            .join(format!("{}.{}", self.name, format))
+    }
+
+    pub fn manifest_path(&self) -> PathBuf {
+        self.store
+            .root
+            .join(BACKUPS_DIR)
+            .join(format!("{}.{}", self.name, MANIFEST_EXTENSION))
+    }
+
+    /// Gets everything in the backup, sorted so that directories come before what's in them.
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        let mut entries = self.read_manifest()?.entries;
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    /// Gets the run of the installer that made the backup, if its manifest says.
+    pub fn run(&self) -> Result<Option<Run>> {
+        Ok(self.read_manifest()?.run)
+    }
+
+    /// Counts the files in the backup and adds up their sizes.
+    pub fn summary(&self) -> Result<Summary> {
+        let manifest = self.read_manifest()?;
+        if manifest.format != BackupFormat::Dir {
+            let archive = self.archive_path(manifest.format);
+            let mut summary = Summary { files: 0, size: 0 };
+            archive::for_each_file(&archive, manifest.format, |_, header, _| {
+                summary.size += header.size()?;
+                Ok(true)
+            })
+            .map_err(InstallError::io("read", &archive))?;
+            summary.files = manifest
+                .entries
+                .iter()
+                .filter(|entry| !matches!(entry.kind, EntryKind::Dir { .. }))
+                .count();
+            return Ok(summary);
+        }
+
+        let mut summary = Summary { files: 0, size: 0 };
+        for entry in manifest.entries {
+            match entry.kind {
+                EntryKind::File { sha256, .. } => {
+                    let object = self.store.object_path(&sha256);
+                    summary.files += 1;
+                    summary.size += match object.metadata() {
+                        Ok(metadata) => metadata.len(),
+                        Err(e) if e.kind() == ErrorKind::NotFound => 0,
+                        Err(e) => return Err(InstallError::io("get metadata for", &object)(e)),
+                    };
+                }
+                EntryKind::Symlink { .. } => summary.files += 1,
+                EntryKind::Dir { .. } => {}
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Checks that the contents of every file in the backup are still in the store and haven't
+    /// changed since they were backed up.
+    pub fn verify(&self) -> Result<Vec<Damage>> {
+        let manifest = self.read_manifest()?;
+        let mut damage = Vec::new();
+        let mut seen = BTreeSet::new();
+        for entry in &manifest.entries {
+            if !seen.insert(&entry.path) {
+                damage.push(Damage::DuplicateEntry(entry.path.clone()));
+            }
+        }
+
+        if manifest.format == BackupFormat::Dir {
+            self.verify_objects(manifest.entries, &mut damage)?;
+        } else {
+            self.verify_archive(manifest.format, manifest.entries, &mut damage);
+        }
+        Ok(damage)
+    }
+
+    fn verify_objects(&self, entries: Vec<Entry>, damage: &mut Vec<Damage>) -> Result<()> {
+        for entry in entries {
+            let EntryKind::File { sha256, .. } = entry.kind else {
+                continue;
+            };
+
+            let object = self.store.object_path(&sha256);
+            let hash = match File::open(&object) {
+                Ok(mut reader) => copy_and_hash(&mut reader, &mut io::sink()),
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    damage.push(Damage::MissingContents {
+                        path: entry.path,
+                        sha256,
+                    });
+                    continue;
+                }
+                Err(e) => Err(e),
+            }
+            .map_err(InstallError::io("read", &object))?;
+            if hash != sha256 {
+                damage.push(Damage::CorruptContents {
+                    path: entry.path,
+                    sha256,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the files in the backup's archive against `entries` in one pass over the archive.
+    fn verify_archive(&self, format: BackupFormat, entries: Vec<Entry>, damage: &mut Vec<Damage>) {
+        let mut expected: BTreeMap<PathBuf, String> = entries
+            .into_iter()
+            .filter_map(|entry| match entry.kind {
+                EntryKind::File { sha256, .. } => Some((entry.path, sha256)),
+                _ => None,
+            })
+            .collect();
+
+        let archive = self.archive_path(format);
+        let result = archive::for_each_file(&archive, format, |path, _, reader| {
+            if let Some(sha256) = expected.remove(path) {
+                if copy_and_hash(reader, &mut io::sink())? != sha256 {
+                    damage.push(Damage::CorruptContents {
+                        path: path.to_path_buf(),
+                        sha256,
+                    });
+                }
+            }
+            Ok(true)
+        });
+        if let Err(e) = result {
+            damage.push(Damage::BrokenArchive(e.to_string()));
+        }
+
+        for (path, sha256) in expected {
+            damage.push(Damage::MissingContents { path, sha256 });
+        }
+    }
+
+    /// Copies the contents of the file in `entry` to a new file at `to`, checking that they still
+    /// match their hash on the way.
+    pub(crate) fn copy_file(&self, entry: &Entry, to: &Path) -> io::Result<()> {
+        let EntryKind::File { sha256, .. } = &entry.kind else {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "not a regular file"));
+        };
+        let format = self
+            .read_manifest()
+            .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?
+            .format;
+        if format == BackupFormat::Dir {
+            return self.store.copy_object(sha256, to);
+        }
+
+        let mut found = false;
+        archive::for_each_file(&self.archive_path(format), format, |path, _, reader| {
+            if path != entry.path {
+                return Ok(true);
+            }
+            found = true;
+            copy_checked(reader, sha256, to)?;
+            Ok(false)
+        })?;
+        if found {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                ErrorKind::NotFound,
+                "the file isn't in the backup's archive",
+            ))
+        }
+    }
+
+    fn read_manifest(&self) -> Result<Manifest> {
+        let path = self.manifest_path();
+        let text = fs::read_to_string(&path).map_err(InstallError::io("read", &path))?;
+        let manifest: Manifest = toml::from_str(&text).map_err(|e| {
+            let offset = e.span().map_or(0, |span| span.start);
+            InstallError::parse(&path, &text, offset, e.message())
+        })?;
+        // Restoring an entry outside of the home directory could overwrite anything.
+        if let Some(entry) = manifest.entries.iter().find(|entry| {
+            !entry
+                .path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        }) {
+            let message = format!("{} isn't a path in the home directory", entry.path.to_string_lossy());
+            return Err(InstallError::parse(&path, &text, 0, message));
+        }
+        Ok(manifest)
+    }
+
+    /// Writes `manifest` atomically, so that a crash can't leave a half-written manifest behind.
+    fn write_manifest(&self, manifest: &Manifest) -> Result<()> {
+        let path = self.manifest_path();
+        let text = toml::to_string(manifest)
+            .map_err(|e| InstallError::io("write", &path)(io::Error::new(ErrorKind::InvalidData, e.to_string())))?;
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, text).map_err(InstallError::io("write", &temp_path))?;
+        fs::rename(&temp_path, &path).map_err(InstallError::io("write", &path))
+    }
+
+    /// Gets the entries for `file` and everything in it.
+    pub(crate) fn entries_for(&self, file: &RelPath) -> Result<Vec<Entry>> {
+        let mut entries = self.entries()?;
+        entries.retain(|entry| entry.path.starts_with(file.as_path_buf()));
+        Ok(entries)
+    }
+
+    /// Creates the backup with a manifest that only says which `run` made it, and the store if it
+    /// doesn't exist yet.
+    pub(crate) fn create(&self, run: &Run) -> Result<()> {
+        self.store.create()?;
+        log::info!("Creating backup {} in {}", self, self.store);
+        let path = self.manifest_path();
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(InstallError::io("create", &path))?;
+        self.write_manifest(&Manifest {
+            format: self.store.format,
+            run: Some(run.clone()),
+            entries: Vec::new(),
+        })
+    }
+
+    /// Deletes the backup, along with any objects that only it used.
+    pub(crate) fn delete(&self) -> Result<()> {
+        let path = self.manifest_path();
+        let format = self.read_manifest()?.format;
+        if format != BackupFormat::Dir {
+            let archive = self.archive_path(format);
+            match fs::remove_file(&archive) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(InstallError::io("remove", &archive)(e)),
+                _ => {}
+            }
+        }
+        fs::remove_file(&path).map_err(InstallError::io("remove", &path))?;
+        self.store.remove_unused_objects()
+    }
+
+    /// Adds the file, symlink, or directory tree at `file` to the backup.
+    pub(crate) fn add(&self, file: &HomeFile) -> Result<()> {
+        log::trace!("Backing up {} to {}", file, self);
+        let mut manifest = self.read_manifest()?;
+        let home_dir = file.prefix().as_path();
+        let mut files = Vec::new();
+        walk_tree(home_dir, file.rel_file().as_path_buf(), &mut files)?;
+
+        let hashes = if manifest.format == BackupFormat::Dir {
+            files
+                .iter()
+                .map(|(rel_path, metadata)| {
+                    let path = home_dir.join(rel_path);
+                    metadata.is_file().then(|| self.store.store_object(&path)).transpose()
+                })
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            let archive = self.archive_path(manifest.format);
+            archive::append(&archive, manifest.format, home_dir, &files)?
+        };
+
+        for ((rel_path, metadata), sha256) in files.into_iter().zip(hashes) {
+            manifest
+                .entries
+                .push(Entry::new(home_dir, rel_path, &metadata, sha256)?);
+        }
+        self.write_manifest(&manifest)
+    }
+
+    /// Checks that the file, symlink, or directory tree at `file` is exactly the same as in the
+    /// backup. The contents of the files are compared last, several files at a time.
+    pub(crate) fn matches(&self, file: &HomeFile) -> Result<bool> {
+        let entries = self.entries_for(file.rel_file())?;
+        let format = self.read_manifest()?.format;
+        let home_dir = file.prefix().as_path();
+
+        let mut contents = Vec::new();
+        for entry in &entries {
+            let path = home_dir.join(&entry.path);
+            if !self.entry_matches(format, &path, entry, &mut contents)? {
+                log::warn!("{} doesn't match its backup", path.to_string_lossy());
+                return Ok(false);
+            }
+        }
+
+        // Check that there isn't anything else in directories.
+        let mut count = 1;
+        if file.to_path_buf().is_dir() && !file.to_path_buf().is_symlink() {
+            count += count_tree(&file.to_path_buf())?;
+        }
+        if count != entries.len() {
+            log::warn!("{} has different files in it than its backup", file);
+            return Ok(false);
+        }
+        install::all_files_equal(&contents)
+    }
+
+    /// Checks that what's at `path` matches `entry`. The contents of files in a backup in the `dir`
+    /// format aren't compared, but added to `contents` as a pair of the file and its object.
+    fn entry_matches(
+        &self,
+        format: BackupFormat,
+        path: &Path,
+        entry: &Entry,
+        contents: &mut Vec<(PathBuf, PathBuf)>,
+    ) -> Result<bool> {
+        let file_type = match path.symlink_metadata() {
+            Ok(metadata) => metadata.file_type(),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(InstallError::io("get metadata for", path)(e)),
+        };
+        match &entry.kind {
+            EntryKind::File { .. } if !file_type.is_file() => Ok(false),
+            EntryKind::File { sha256, .. } if format == BackupFormat::Dir => {
+                contents.push((path.to_path_buf(), self.store.object_path(sha256)));
+                Ok(true)
+            }
+            // The contents are in an archive, so compare hashes instead.
+            EntryKind::File { sha256, .. } => {
+                let hash = File::open(path)
+                    .and_then(|mut reader| copy_and_hash(&mut reader, &mut io::sink()))
+                    .map_err(InstallError::io("read", path))?;
+                Ok(hash == *sha256)
+            }
+            EntryKind::Symlink { target } => Ok(file_type.is_symlink()
+                && fs::read_link(path).map_err(InstallError::io("read symlink", path))? == *target),
+            EntryKind::Dir { .. } => Ok(file_type.is_dir()),
+        }
+    }
+}
+
+impl Display for BackupStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.root.to_string_lossy())
+    }
+}
+
+impl Display for BackupFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BackupFormat::Dir => "dir",
+            BackupFormat::TarZst => "tar.zst",
+            BackupFormat::TarGz => "tar.gz",
+        })
+    }
+}
+
+impl FromStr for BackupFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "dir" => Ok(BackupFormat::Dir),
+            "tar.zst" => Ok(BackupFormat::TarZst),
+            "tar.gz" => Ok(BackupFormat::TarGz),
+            _ => Err(format!("unknown backup format {:?}", format)),
+        }
+    }
+}
+
+impl Display for Backup {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl Display for LegacyBackup {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.to_string_lossy())
+    }
+}
+
+impl LegacyBackup {
+    /// Finds the legacy backups in `home_dir`, sorted from oldest to newest.
+    pub fn find(home_dir: &HomePath) -> Result<Vec<Self>> {
+        let dir = home_dir.as_path();
+        let mut backups = Vec::new();
+        for entry in dir.read_dir().map_err(InstallError::io("read directory", dir))? {
+            let path = entry.map_err(InstallError::io("get directory entry in", dir))?.path();
+            let Some(timestamp) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| NaiveDateTime::parse_from_str(name, LEGACY_BACKUP_FORMAT).ok())
+            else {
+                continue;
+            };
+            if path.is_dir() && !path.is_symlink() {
+                backups.push(Self { path, timestamp });
+            }
+        }
+        backups.sort_by_key(|backup| backup.timestamp);
+        log::debug!("Found {} legacy backups in {}", backups.len(), home_dir);
+        Ok(backups)
+    }
+
+    pub fn as_path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn timestamp(&self) -> NaiveDateTime {
+        self.timestamp
+    }
+
+    /// Lists what's in the backup the same way that the manifest of a backup in the store does,
+    /// hashing each file. The README isn't part of the backup.
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        let mut files = Vec::new();
+        let mut children = self
+            .path
+            .read_dir()
+            .map_err(InstallError::io("read directory", &self.path))?
+            .map(|entry| {
+                Ok(entry
+                    .map_err(InstallError::io("get directory entry in", &self.path))?
+                    .file_name())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        children.sort();
+        for child in children.into_iter().filter(|child| child != README_FILE) {
+            walk_tree(&self.path, Path::new(&child), &mut files)?;
+        }
+
+        files
+            .into_iter()
+            .map(|(rel_path, metadata)| {
+                let path = self.path.join(&rel_path);
+                let sha256 = metadata
+                    .is_file()
+                    .then(|| File::open(&path).and_then(|mut file| copy_and_hash(&mut file, &mut io::sink())))
+                    .transpose()
+                    .map_err(InstallError::io("read", &path))?;
+                Entry::new(&self.path, rel_path, &metadata, sha256)
+            })
+            .collect()
+    }
+
+    /// Gets the entries for `file` and everything in it.
+    pub(crate) fn entries_for(&self, file: &RelPath) -> Result<Vec<Entry>> {
+        let mut entries = self.entries()?;
+        entries.retain(|entry| entry.path.starts_with(file.as_path_buf()));
+        Ok(entries)
+    }
+
+    /// Counts the files in the backup and adds up their sizes.
+    pub fn summary(&self) -> Result<Summary> {
+        let mut summary = Summary { files: 0, size: 0 };
+        for entry in self.entries()? {
+            match entry.kind {
+                EntryKind::File { .. } => {
+                    let path = self.path.join(&entry.path);
+                    summary.files += 1;
+                    summary.size += path
+                        .symlink_metadata()
+                        .map_err(InstallError::io("get metadata for", &path))?
+                        .len();
+                }
+                EntryKind::Symlink { .. } => summary.files += 1,
+                EntryKind::Dir { .. } => {}
+            }
+        }
+        Ok(summary)
+    }
+
+    pub(crate) fn delete(&self) -> Result<()> {
+        fs::remove_dir_all(&self.path).map_err(InstallError::io("remove", &self.path))
+    }
+}
+
+impl Entry {
+    /// Makes the entry for the file at `rel_path` in `home_dir`, which has `metadata`. `sha256` is
+    /// the hash of its contents if it's a regular file.
+    fn new(home_dir: &Path, rel_path: PathBuf, metadata: &Metadata, sha256: Option<String>) -> Result<Self> {
+        let mode = metadata.mode() & 0o7777;
+        let kind = match sha256 {
+            Some(sha256) => EntryKind::File { sha256, mode },
+            None if metadata.is_dir() => EntryKind::Dir { mode },
+            None => {
+                let path = home_dir.join(&rel_path);
+                EntryKind::Symlink {
+                    target: fs::read_link(&path).map_err(InstallError::io("read symlink", &path))?,
+                }
+            }
+        };
+        let xattrs = match kind {
+            EntryKind::Symlink { .. } => BTreeMap::new(),
+            _ => {
+                let path = home_dir.join(&rel_path);
+                read_xattrs(&path).map_err(InstallError::io("read extended attributes of", &path))?
+            }
+        };
+        Ok(Self {
+            path: rel_path,
+            kind,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            atime: metadata.atime(),
+            atime_nsec: metadata.atime_nsec(),
+            xattrs,
+        })
+    }
+
+    /// Decodes the values of the entry's extended attributes.
+    pub(crate) fn decoded_xattrs(&self) -> io::Result<Vec<(&str, Vec<u8>)>> {
+        self.xattrs
+            .iter()
+            .map(|(name, value)| {
+                let value = from_hex(value).ok_or_else(|| {
+                    io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid value for extended attribute {}", name),
+                    )
+                })?;
+                Ok((name.as_str(), value))
+            })
+            .collect()
+    }
+}
+
+/// Reads the extended attributes in the `user` namespace of the file at `path`, without following
+/// symlinks. File systems that don't support extended attributes have none.
+fn read_xattrs(path: &Path) -> io::Result<BTreeMap<String, String>> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if xattrs_unsupported(&e) => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+    let mut xattrs = BTreeMap::new();
+    for name in names {
+        let Some(name) = name.to_str().filter(|name| name.starts_with("user.")) else {
+            continue;
+        };
+        // The attribute might have been removed since it was listed.
+        if let Some(value) = xattr::get(path, name)? {
+            xattrs.insert(name.to_string(), to_hex(&value));
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Checks whether `e` means that a file system doesn't support extended attributes.
+pub(crate) fn xattrs_unsupported(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(nix::errno::Errno::EOPNOTSUPP as i32)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    bytes
+        .iter()
+        .flat_map(|byte| [DIGITS[usize::from(byte >> 4)], DIGITS[usize::from(byte & 0xf)]])
+        .map(char::from)
+        .collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+impl Run {
+    /// Describes the current run of the installer, which is doing `command` with `repo`.
+    pub fn new(command: &str, repo: &Repo) -> Self {
+        let uid = Uid::current();
+        Self {
+            command: command.to_string(),
+            started: Local::now().to_rfc3339(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            hostname: unistd::gethostname()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            user: User::from_uid(uid)
+                .ok()
+                .flatten()
+                .map_or_else(|| uid.to_string(), |user| user.name),
+            pid: process::id(),
+            repo: repo.root().to_path_buf(),
+        }
+    }
+}
+
+impl Display for Damage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Damage::MissingContents { path, sha256 } => {
+                write!(
+                    f,
+                    "{}: the contents with hash {} are missing",
+                    path.to_string_lossy(),
+                    sha256
+                )
+            }
+            Damage::CorruptContents { path, sha256 } => write!(
+                f,
+                "{}: the contents don't match their hash {}",
+                path.to_string_lossy(),
+                sha256
+            ),
+            Damage::BrokenArchive(error) => write!(f, "the archive can't be read: {}", error),
+            Damage::DuplicateEntry(path) => write!(f, "{}: there's more than one entry for it", path.to_string_lossy()),
+        }
+    }
+}
+
+/// Copies everything from `reader` to `writer`, and returns the SHA-256 hash of what was copied.
+fn copy_and_hash<R: Read + ?Sized, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        writer.write_all(&buffer[..n])?;
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Copies everything from `reader` to a new file at `to`, and deletes it again if what was copied
+/// doesn't have the hash `sha256`.
+fn copy_checked<R: Read + ?Sized>(reader: &mut R, sha256: &str, to: &Path) -> io::Result<()> {
+    let mut writer = OpenOptions::new().write(true).create_new(true).open(to)?;
+    if copy_and_hash(reader, &mut writer)? != sha256 {
+        drop(writer);
+        fs::remove_file(to)?;
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("the backed-up contents with hash {} are corrupt", sha256),
+        ));
+    }
+    Ok(())
+}
+
+/// Finds the file, symlink, or directory tree at `rel_path` in `home_dir`, with directories before
+/// the things in them.
+fn walk_tree(home_dir: &Path, rel_path: &Path, found: &mut Vec<(PathBuf, Metadata)>) -> Result<()> {
+    let path = home_dir.join(rel_path);
+    let metadata = path
+        .symlink_metadata()
+        .map_err(InstallError::io("get metadata for", &path))?;
+    let file_type = metadata.file_type();
+    if !file_type.is_symlink() && !file_type.is_file() && !file_type.is_dir() {
+        return Err(InstallError::UnknownFileType { path, file_type });
+    }
+    found.push((rel_path.to_path_buf(), metadata));
+
+    if file_type.is_dir() {
+        let mut children = path
+            .read_dir()
+            .map_err(InstallError::io("read directory", &path))?
+            .map(|entry| {
+                Ok(entry
+                    .map_err(InstallError::io("get directory entry in", &path))?
+                    .file_name())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        children.sort();
+        for child in children {
+            walk_tree(home_dir, &rel_path.join(child), found)?;
+        }
+    }
+    Ok(())
+}
+
+/// Counts everything in the directory tree at `dir`, not counting `dir` itself.
+fn count_tree(dir: &Path) -> Result<usize> {
+    let mut count = 0;
+    for entry in dir.read_dir().map_err(InstallError::io("read directory", dir))? {
+        let entry = entry.map_err(InstallError::io("get directory entry in", dir))?;
+        let is_dir = entry
+            .file_type()
+            .map_err(InstallError::io("get file type for", entry.path()))?
+            .is_dir();
+        count += 1;
+        if is_dir {
+            count += count_tree(&entry.path())?;
+        }
+    }
+    Ok(count)
+}
+
+/// Plans backing up the files at `files` in `home_dir` to `backup`, which is made by `run`. Files
+/// that don't exist and links into `owned_dirs`, which the installer made itself, aren't backed up.
+/// The backup is only created if there's something to put in it.
+pub(crate) fn plan_backup(
+    plan: &mut Plan,
+    home_dir: &HomePath,
+    backup: &Backup,
+    run: &Run,
+    files: &[RelPath],
+    owned_dirs: &[&Path],
+) -> Result<()> {
+    let mut to_back_up = Vec::new();
+    for file in files {
+        let file = home_dir.with_rel_file(file);
+        if !file_exists(&file) {
+            log::debug!("Not backing up {} because it doesn't exist", file);
+        } else if restore::links_into_any(&file, owned_dirs)? {
+            log::debug!("Not backing up {} because the installer made it", file);
+        } else {
+            to_back_up.push(file);
+        }
+    }
+
+    if !to_back_up.is_empty() {
+        plan.push(Action::CreateBackup(backup.clone(), run.clone()));
+    }
+    for file in to_back_up {
+        plan.push(Action::Backup(file, backup.clone()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix;
+
+    use super::*;
+    use crate::test_util::{self, Fixture};
+
+    #[test]
+    fn identical_files_are_stored_once() -> io::Result<()> {
+        let home = Fixture::new()?;
+        let store_dir = Fixture::new()?;
+        let home_dir = home.home_dir();
+        let store = store_dir.store();
+        let run = test_util::run();
+        fs::create_dir(home.path().join("dir"))?;
+        fs::write(home.path().join("a"), "foo")?;
+        fs::write(home.path().join("dir/b"), "foo")?;
+        unix::fs::symlink("a", home.path().join("dir/link"))?;
+
+        let first = store.new_backup();
+        first.create(&run).unwrap();
+        first.add(&home_dir.with_rel_file(&RelPath::new("a"))).unwrap();
+        let second = store.new_backup();
+        second.create(&run).unwrap();
+        second.add(&home_dir.with_rel_file(&RelPath::new("dir"))).unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(store.backups().unwrap(), [first.clone(), second.clone()]);
+        let objects = install::recurse_through_dir(store_dir.path().join(OBJECTS_DIR), Ok).unwrap();
+        assert_eq!(objects.len(), 1);
+        let paths: Vec<_> = second.entries().unwrap().into_iter().map(|entry| entry.path).collect();
+        assert_eq!(paths, [Path::new("dir"), Path::new("dir/b"), Path::new("dir/link")]);
+        assert!(second.matches(&home_dir.with_rel_file(&RelPath::new("dir"))).unwrap());
+
+        fs::write(home.path().join("dir/c"), "bar")?;
+        assert!(!second.matches(&home_dir.with_rel_file(&RelPath::new("dir"))).unwrap());
+        fs::remove_file(home.path().join("dir/c"))?;
+        fs::write(home.path().join("dir/b"), "bar")?;
+        assert!(!second.matches(&home_dir.with_rel_file(&RelPath::new("dir"))).unwrap());
+
+        second.delete().unwrap();
+        assert_eq!(store.backups().unwrap(), [first]);
+        assert_eq!(
+            install::recurse_through_dir(store_dir.path().join(OBJECTS_DIR), Ok)
+                .unwrap()
+                .len(),
+            1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn verify_finds_missing_and_corrupt_objects() -> io::Result<()> {
+        let home = Fixture::new()?;
+        let store_dir = Fixture::new()?;
+        let home_dir = home.home_dir();
+        let store = store_dir.store();
+        let run = test_util::run();
+        fs::write(home.path().join("a"), "foo")?;
+        fs::write(home.path().join("b"), "bar")?;
+        let backup = store.new_backup();
+        backup.create(&run).unwrap();
+        backup.add(&home_dir.with_rel_file(&RelPath::new("a"))).unwrap();
+        backup.add(&home_dir.with_rel_file(&RelPath::new("b"))).unwrap();
+        assert_eq!(backup.run().unwrap(), Some(run));
+        assert_eq!(backup.verify().unwrap(), []);
+
+        let entries = backup.entries().unwrap();
+        let sha256 = |entry: &Entry| match &entry.kind {
+            EntryKind::File { sha256, .. } => sha256.clone(),
+            kind => panic!("{:?} isn't a file", kind),
+        };
+        let (a, b) = (sha256(&entries[0]), sha256(&entries[1]));
+        fs::write(store.object_path(&a), "changed")?;
+        fs::remove_file(store.object_path(&b))?;
+
+        assert_eq!(
+            backup.verify().unwrap(),
+            [
+                Damage::CorruptContents {
+                    path: PathBuf::from("a"),
+                    sha256: a.clone(),
+                },
+                Damage::MissingContents {
+                    path: PathBuf::from("b"),
+                    sha256: b,
+                },
+            ]
+        );
+        let restored = home.path().join("restored");
+        assert!(store.copy_object(&a, &restored).is_err());
+        assert!(!restored.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn manifests_cant_point_outside_the_home_dir() -> io::Result<()> {
+        let store_dir = Fixture::new()?;
+        let backup = store_dir.store().new_backup();
+        backup.create(&test_util::run()).unwrap();
+        let manifest = "[[entry]]\npath = \"../outside\"\ntype = \"symlink\"\ntarget = \"/etc\"\n\
+                        uid = 0\ngid = 0\nmtime = 0\nmtime_nsec = 0\n";
+        fs::write(backup.manifest_path(), manifest)?;
+
+        assert!(matches!(backup.entries(), Err(InstallError::Parse { .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn archives_can_be_verified_and_restored() -> io::Result<()> {
+        for format in [BackupFormat::TarZst, BackupFormat::TarGz] {
+            let home = Fixture::new()?;
+            let store_dir = Fixture::new()?;
+            let home_dir = home.home_dir();
+            let store = store_dir.store().with_format(format);
+            fs::create_dir(home.path().join("dir"))?;
+            fs::write(home.path().join("dir/file"), "foo")?;
+            unix::fs::symlink("file", home.path().join("dir/link"))?;
+            fs::write(home.path().join("other"), "bar")?;
+            let dir = home_dir.with_rel_file(&RelPath::new("dir"));
+            let other = home_dir.with_rel_file(&RelPath::new("other"));
+
+            // Two adds make two compressed streams in the archive.
+            let backup = store.new_backup();
+            backup.create(&test_util::run()).unwrap();
+            backup.add(&dir).unwrap();
+            backup.add(&other).unwrap();
+            assert!(backup.archive_path(format).is_file());
+            assert!(install::recurse_through_dir(store_dir.path().join(OBJECTS_DIR), Ok)
+                .unwrap()
+                .is_empty());
+            assert_eq!(backup.verify().unwrap(), []);
+            assert_eq!(backup.summary().unwrap(), Summary { files: 3, size: 6 });
+            assert!(backup.matches(&dir).unwrap());
+
+            fs::remove_dir_all(dir.to_path_buf())?;
+            fs::remove_file(other.to_path_buf())?;
+            restore::restore_file(&backup, &dir).unwrap();
+            restore::restore_file(&backup, &other).unwrap();
+            assert_eq!(fs::read_to_string(home.path().join("dir/file"))?, "foo");
+            assert_eq!(fs::read_link(home.path().join("dir/link"))?, Path::new("file"));
+            assert_eq!(fs::read_to_string(home.path().join("other"))?, "bar");
+
+            let archive = backup.archive_path(format);
+            let len = archive.metadata()?.len();
+            OpenOptions::new().write(true).open(&archive)?.set_len(len / 2)?;
+            assert!(!backup.verify().unwrap().is_empty());
+
+            backup.delete().unwrap();
+            assert!(!archive.exists());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    io::ErrorKind,
+    path::Path,
+};
+
+use nix::errno::Errno;
+
+use crate::error::{InstallError, Result};
+use crate::fold;
+use crate::manifest::Link;
+use crate::path::*;
+use crate::restore;
+
+/// Something in the home directory that's in the way of a link because it has the wrong type.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Conflict {
+    pub file: HomeFile,
+    pub kind: ConflictKind,
+}
+
+/// The kinds of type conflicts. Each kind has a fixed policy, see [`Conflict::replaces`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ConflictKind {
+    /// A directory where a link has to go. It's backed up with everything in it and replaced.
+    DirForFile,
+    /// A symlink to a directory where a link has to go. The symlink is backed up and replaced, and
+    /// the directory it points to is left alone.
+    SymlinkToDirForFile,
+    /// A file, or a symlink to something that isn't a directory, where a link needs a parent
+    /// directory. It's backed up and replaced with a directory.
+    FileForDir,
+    /// A symlink to a directory where a link needs a parent directory. The symlink is kept, and the
+    /// link goes into the directory that it points to.
+    SymlinkToDirForDir,
+}
+
+impl Conflict {
+    /// Whether the file in the way gets backed up and replaced. Otherwise it's kept and used as it is.
+    pub fn replaces(&self) -> bool {
+        self.kind != ConflictKind::SymlinkToDirForDir
+    }
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let file = self.file.rel_file();
+        match self.kind {
+            ConflictKind::DirForFile => write!(f, "{} is a directory, but a dotfile goes there", file),
+            ConflictKind::SymlinkToDirForFile => {
+                write!(f, "{} is a symlink to a directory, but a dotfile goes there", file)
+            }
+            ConflictKind::FileForDir => write!(f, "{} is a file, but dotfiles go in it", file),
+            ConflictKind::SymlinkToDirForDir => write!(
+                f,
+                "{} is a symlink to a directory, so dotfiles in it go where it points",
+                file
+            ),
+        }
+    }
+}
+
+/// Finds the type conflicts that installing `links` into `home_dir` runs into. Symlinks into
+/// `owned_dirs` and things inside the folded directories in `unfolded` don't count, since the
+/// installer made them and replaces them anyway. Each conflicting file is only reported once, even
+/// if several links run into it.
+pub fn find_conflicts(
+    home_dir: &HomePath,
+    links: &[Link],
+    unfolded: &[HomeFile],
+    owned_dirs: &[&Path],
+) -> Result<Vec<Conflict>> {
+    let mut conflicts = BTreeMap::new();
+    for link in links {
+        let file = home_dir.with_rel_file(&link.target);
+        if fold::is_inside_any(&file, unfolded) {
+            continue;
+        }
+        if let Some(conflict) = find_conflict(&file, owned_dirs)? {
+            conflicts.entry(conflict.file.to_path_buf()).or_insert(conflict);
+        }
+        let parents: Vec<_> = fold::parent_dirs(&link.target).collect();
+        for dir in parents.into_iter().rev() {
+            let dir = home_dir.with_rel_file(&RelPath::new(dir));
+            match find_dir_conflict(&dir, owned_dirs)? {
+                DirState::Ok => {}
+                DirState::Missing => break,
+                DirState::Conflict(kind) => {
+                    let conflict = Conflict { file: dir, kind };
+                    let replaces = conflict.replaces();
+                    conflicts.entry(conflict.file.to_path_buf()).or_insert(conflict);
+                    if replaces {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // A file that's replaced by a directory takes care of any conflicts below it.
+    let replaced: Vec<_> = conflicts
+        .values()
+        .filter(|conflict| conflict.kind == ConflictKind::FileForDir)
+        .map(|conflict| conflict.file.clone())
+        .collect();
+    Ok(conflicts
+        .into_values()
+        .filter(|conflict| {
+            !replaced
+                .iter()
+                .any(|file| conflict.file != *file && conflict.file.to_path_buf().starts_with(file.to_path_buf()))
+        })
+        .collect())
+}
+
+enum DirState {
+    Ok,
+    Missing,
+    Conflict(ConflictKind),
+}
+
+/// Checks whether `file` is in the way of a link because it's a directory or a symlink to one.
+fn find_conflict(file: &HomeFile, owned_dirs: &[&Path]) -> Result<Option<Conflict>> {
+    let path = file.to_path_buf();
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        // Something further up is in the way, which is found when looking at the parents.
+        Err(e) if e.kind() == ErrorKind::NotFound || e.raw_os_error() == Some(Errno::ENOTDIR as i32) => {
+            return Ok(None)
+        }
+        Err(e) => return Err(InstallError::io("get metadata for", &path)(e)),
+    };
+    let kind = if metadata.is_dir() {
+        ConflictKind::DirForFile
+    } else if metadata.is_symlink() && path.is_dir() && !restore::links_into_any(file, owned_dirs)? {
+        ConflictKind::SymlinkToDirForFile
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(Conflict {
+        file: file.clone(),
+        kind,
+    }))
+}
+
+/// Checks whether links can go into `dir`.
+fn find_dir_conflict(dir: &HomeFile, owned_dirs: &[&Path]) -> Result<DirState> {
+    let path = dir.to_path_buf();
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(DirState::Missing),
+        Err(e) => return Err(InstallError::io("get metadata for", &path)(e)),
+    };
+    Ok(if metadata.is_dir() {
+        DirState::Ok
+    } else if metadata.is_symlink() && restore::links_into_any(dir, owned_dirs)? {
+        // A folded directory or the submodule link, which the installer takes care of itself.
+        DirState::Missing
+    } else if metadata.is_symlink() && path.is_dir() {
+        DirState::Conflict(ConflictKind::SymlinkToDirForDir)
+    } else {
+        DirState::Conflict(ConflictKind::FileForDir)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io, os::unix};
+
+    use super::*;
+    use crate::manifest::LinkMode;
+    use crate::test_util::Fixture;
+
+    fn link(target: &str) -> Link {
+        Link {
+            source: DotfilesPath::new("/dotfiles/files").with_rel_file(&RelPath::new(target)),
+            target: RelPath::new(target),
+            mode: LinkMode::Symlink,
+        }
+    }
+
+    #[test]
+    fn finds_each_conflict_once() -> io::Result<()> {
+        let home = Fixture::new()?;
+        let elsewhere = Fixture::new()?;
+        let home_dir = home.home_dir();
+        fs::create_dir_all(home.path().join("dir/sub"))?;
+        fs::write(home.path().join("file"), "")?;
+        unix::fs::symlink(elsewhere.path(), home.path().join("linked-dir"))?;
+        unix::fs::symlink("/dotfiles/files/owned", home.path().join("owned"))?;
+        let links = [
+            link("dir"),
+            link("file/a/b"),
+            link("file/c"),
+            link("linked-dir"),
+            link("linked-dir/a"),
+            link("owned/a"),
+            link("missing/a"),
+        ];
+
+        let conflicts = find_conflicts(&home_dir, &links, &[], &[Path::new("/dotfiles/files")]).unwrap();
+
+        let conflict = |file: &str, kind| Conflict {
+            file: home_dir.with_rel_file(&RelPath::new(file)),
+            kind,
+        };
+        assert_eq!(
+            conflicts,
+            [
+                conflict("dir", ConflictKind::DirForFile),
+                conflict("file", ConflictKind::FileForDir),
+                conflict("linked-dir", ConflictKind::SymlinkToDirForFile),
+            ]
+        );
+        Ok(())
+    }
+}
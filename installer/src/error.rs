@@ -0,0 +1,212 @@
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    fs::FileType,
+    io,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+};
+
+use log::SetLoggerError;
+use nix::errno::Errno;
+
+pub type Result<T, E = InstallError> = std::result::Result<T, E>;
+
+/// Everything that can go wrong in the installer.
+#[derive(Debug)]
+pub enum InstallError {
+    /// An I/O operation on a path failed. `action` describes the operation, e.g. "remove".
+    Io {
+        action: &'static str,
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// A file in the home directory doesn't match its backup, so it isn't safe to delete.
+    BackupMismatch { file: PathBuf, backup: String },
+    /// A file that should be in a backup isn't.
+    NotInBackup { file: PathBuf, backup: String },
+    /// A file has a type that we don't know how to handle, like a socket or a FIFO.
+    UnknownFileType { path: PathBuf, file_type: FileType },
+    /// A path that should be a directory isn't one.
+    NotADirectory(PathBuf),
+    /// A command exited with an error or was killed by a signal.
+    CommandFailed { command: String, status: ExitStatus },
+    /// A git command failed. `message` is what git printed on stderr.
+    Git {
+        args: Vec<String>,
+        dir: PathBuf,
+        message: String,
+    },
+    /// A command's name isn't valid Unicode.
+    InvalidCommand(PathBuf),
+    /// The dotfile repository couldn't be found.
+    RepoNotFound,
+    /// The current user's home directory couldn't be found.
+    HomeNotFound(Option<Errno>),
+    /// A profile isn't the name of a single directory in `hosts/`.
+    InvalidProfile(String),
+    /// A file in the repo, like the manifest or a template, is invalid. `line` and `column` start at 1.
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /// A dotfile that the manifest says is required doesn't exist.
+    MissingDotfile(PathBuf),
+    /// A file in the home directory can't be moved into the repo.
+    CantAdopt { path: PathBuf, reason: String },
+    /// There's already a dotfile at the path that a file would be moved to.
+    DotfileExists(PathBuf),
+    /// There are no backups in the backup store.
+    NoBackups(PathBuf),
+    /// There's no backup with the given name.
+    BackupNotFound(String),
+    /// There's no submodule with the given name.
+    SubmoduleNotFound(String),
+    /// Some submodules couldn't be updated.
+    SubmoduleUpdatesFailed(Vec<String>),
+    /// A submodule has a target in the home directory, but can't be linked there.
+    CantLinkSubmodule { name: String, reason: String },
+    /// The repo isn't in a state where it's safe to sync branches, like in the middle of a merge.
+    NotSafeToSync(String),
+    /// Syncing branches ran into conflicts that have to be fixed by hand. The message says how.
+    SyncConflict(String),
+    /// Some backups are missing files or have corrupt files in them.
+    DamagedBackups(Vec<String>),
+    /// An install failed, and everything it did was undone.
+    RolledBack(Box<InstallError>),
+    /// An install failed, and some of what it did couldn't be undone.
+    RollbackFailed(Box<InstallError>),
+    /// Logging couldn't be set up.
+    Logging(SetLoggerError),
+}
+
+impl InstallError {
+    /// Returns a function for use with `map_err` that turns an I/O error into an `InstallError`.
+    pub fn io<P: AsRef<Path>>(action: &'static str, path: P) -> impl FnOnce(io::Error) -> Self {
+        let path = path.as_ref().to_path_buf();
+        move |source| Self::Io { action, path, source }
+    }
+
+    /// Makes a [`InstallError::Parse`] for an error at byte `offset` in `text`, which is the contents
+    /// of the file at `path`.
+    pub fn parse<P: AsRef<Path>, S: Into<String>>(path: P, text: &str, offset: usize, message: S) -> Self {
+        let before = &text[..offset.min(text.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before[before.rfind('\n').map_or(0, |i| i + 1)..].chars().count() + 1;
+        Self::Parse {
+            path: path.as_ref().to_path_buf(),
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for InstallError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            InstallError::Io { action, path, source } => {
+                write!(f, "Failed to {} {}: {}", action, path.to_string_lossy(), source)
+            }
+            InstallError::BackupMismatch { file, backup } => write!(
+                f,
+                "Not deleting {} because it doesn't match its copy in backup {}",
+                file.to_string_lossy(),
+                backup
+            ),
+            InstallError::NotInBackup { file, backup } => {
+                write!(f, "{} isn't in backup {}", file.to_string_lossy(), backup)
+            }
+            InstallError::UnknownFileType { path, file_type } => {
+                write!(f, "{} has the unknown type {:?}", path.to_string_lossy(), file_type)
+            }
+            InstallError::NotADirectory(path) => write!(
+                f,
+                "{} does not seem to be a directory! This may be caused by missing permissions.",
+                path.to_string_lossy()
+            ),
+            InstallError::CommandFailed { command, status } => match status.signal() {
+                Some(signal) => write!(f, "{} was killed by signal {}", command, signal),
+                None => write!(f, "{} returned an error: {}", command, status),
+            },
+            InstallError::Git { args, dir, message } => write!(
+                f,
+                "git {} failed in {}: {}",
+                args.join(" "),
+                dir.to_string_lossy(),
+                message
+            ),
+            InstallError::InvalidCommand(path) => {
+                write!(
+                    f,
+                    "Can't run {} since its name isn't valid Unicode",
+                    path.to_string_lossy()
+                )
+            }
+            InstallError::RepoNotFound => write!(
+                f,
+                "Couldn't find the root of the dotfile repository! Run this binary inside the repo or pass --repo."
+            ),
+            InstallError::HomeNotFound(Some(errno)) => {
+                write!(f, "Failed to get user info for the current user: {}", errno)
+            }
+            InstallError::HomeNotFound(None) => write!(f, "The current user doesn't seem to exist"),
+            InstallError::InvalidProfile(profile) => write!(
+                f,
+                "\"{}\" isn't a valid profile, it has to be the name of a directory in hosts/",
+                profile
+            ),
+            InstallError::Parse {
+                path,
+                line,
+                column,
+                message,
+            } => write!(f, "{}:{}:{}: {}", path.to_string_lossy(), line, column, message),
+            InstallError::MissingDotfile(path) => write!(
+                f,
+                "{} is required by the manifest but doesn't exist",
+                path.to_string_lossy()
+            ),
+            InstallError::CantAdopt { path, reason } => {
+                write!(f, "Can't adopt {} because {}", path.to_string_lossy(), reason)
+            }
+            InstallError::DotfileExists(path) => write!(
+                f,
+                "{} already exists. Use --force to replace it",
+                path.to_string_lossy()
+            ),
+            InstallError::NoBackups(home_dir) => write!(f, "There are no backups in {}", home_dir.to_string_lossy()),
+            InstallError::BackupNotFound(name) => write!(f, "There's no backup called {}", name),
+            InstallError::SubmoduleNotFound(name) => write!(f, "There's no submodule called {}", name),
+            InstallError::SubmoduleUpdatesFailed(names) => {
+                write!(f, "Failed to update some submodules: {}", names.join(", "))
+            }
+            InstallError::CantLinkSubmodule { name, reason } => {
+                write!(f, "Can't link submodule {} because {}", name, reason)
+            }
+            InstallError::NotSafeToSync(reason) => write!(f, "Can't sync because {}", reason),
+            InstallError::SyncConflict(message) => write!(f, "{}", message),
+            InstallError::DamagedBackups(names) => write!(f, "Some backups are damaged: {}", names.join(", ")),
+            InstallError::RolledBack(e) => write!(f, "{}. Rolled back all changes", e),
+            InstallError::RollbackFailed(e) => {
+                write!(f, "{}. Failed to roll back some changes! See the errors above", e)
+            }
+            InstallError::Logging(e) => write!(f, "Failed to initialize logging: {}", e),
+        }
+    }
+}
+
+impl Error for InstallError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            InstallError::Io { source, .. } => Some(source),
+            InstallError::HomeNotFound(Some(errno)) => Some(errno),
+            InstallError::RolledBack(e) | InstallError::RollbackFailed(e) => Some(e.as_ref()),
+            InstallError::Logging(e) => Some(e),
+            _ => None,
+        }
+    }
+}
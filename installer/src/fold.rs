@@ -0,0 +1,274 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use crate::error::Result;
+use crate::install::{self, file_exists, get_file_type};
+use crate::manifest::{Link, LinkMode, Manifest};
+use crate::path::*;
+use crate::repo::Repo;
+use crate::restore;
+
+/// A file that marks the directory that it's in as one to fold. It isn't linked by itself.
+pub const FOLD_MARKER_FILE: &str = ".dotfiles-fold";
+
+/// Replaces the links in a directory with a single link to the directory in the repo where
+/// possible, like GNU Stow's tree folding. A directory in the home directory is folded if:
+///
+/// - its source directory contains a [`FOLD_MARKER_FILE`], or the manifest turns on folding for it,
+/// - every link in it is a symlink from the same source directory, and every dotfile in that
+///   directory is linked, so that the folded link doesn't show anything that shouldn't be there,
+/// - and it isn't already a real directory in the home directory.
+///
+/// Directories are folded as close to the home directory as possible.
+pub fn fold_links(home_dir: &HomePath, repo: &Repo, manifest: &Manifest, links: Vec<Link>) -> Result<Vec<Link>> {
+    let dirs: BTreeSet<&Path> = links.iter().flat_map(|link| parent_dirs(&link.target)).collect();
+
+    let mut folded: Vec<Link> = Vec::new();
+    for dir in dirs {
+        if folded.iter().any(|link| dir.starts_with(link.target.as_path_buf())) {
+            continue;
+        }
+        if let Some(source) = fold_source(home_dir, repo, manifest, &links, dir)? {
+            log::debug!("Folding {} into a link to {}", dir.to_string_lossy(), source);
+            folded.push(Link {
+                source,
+                target: RelPath::new(dir),
+                mode: LinkMode::Symlink,
+            });
+        }
+    }
+
+    // Put each folded link where the first of the links that it replaces was.
+    let mut result = Vec::with_capacity(links.len());
+    for link in links {
+        match folded
+            .iter()
+            .position(|dir| link.target.as_path_buf().starts_with(dir.target.as_path_buf()))
+        {
+            Some(i) if !result.contains(&folded[i]) => result.push(folded[i].clone()),
+            Some(_) => {}
+            None => result.push(link),
+        }
+    }
+    Ok(result)
+}
+
+/// Works out which directory in the repo `dir` can be folded into a link to, if any.
+fn fold_source(
+    home_dir: &HomePath,
+    repo: &Repo,
+    manifest: &Manifest,
+    links: &[Link],
+    dir: &Path,
+) -> Result<Option<Dotfile>> {
+    let mut source_dir: Option<Dotfile> = None;
+    let mut rest = BTreeSet::new();
+
+    for link in links.iter().filter(|link| link.target.as_path_buf().starts_with(dir)) {
+        let file = link.target.as_path_buf().strip_prefix(dir).unwrap();
+        let Some(source) = strip_path_suffix(link.source.rel_file().as_path_buf(), file) else {
+            return Ok(None);
+        };
+        if link.mode != LinkMode::Symlink || source.as_os_str().is_empty() {
+            return Ok(None);
+        }
+
+        let source = link.source.prefix().with_rel_file(&RelPath::new(source));
+        match &source_dir {
+            Some(source_dir) if *source_dir != source => return Ok(None),
+            Some(_) => {}
+            None => source_dir = Some(source),
+        }
+        rest.insert(file.to_path_buf());
+    }
+
+    let Some(source_dir) = source_dir else {
+        return Ok(None);
+    };
+    let marked = source_dir.to_path_buf().join(FOLD_MARKER_FILE).is_file();
+    if !marked && !manifest.folds(source_dir.rel_file().as_path_buf()) {
+        return Ok(None);
+    }
+
+    let files: BTreeSet<PathBuf> = install::find_dotfiles(&DotfilesPath::new(source_dir.to_path_buf()))?
+        .into_iter()
+        .map(|file| file.as_path_buf().clone())
+        .collect();
+    if files != rest {
+        log::debug!(
+            "Not folding {} since not everything in {} gets linked there",
+            dir.to_string_lossy(),
+            source_dir
+        );
+        return Ok(None);
+    }
+
+    let home_file = home_dir.with_rel_file(&RelPath::new(dir));
+    if file_exists(&home_file) && get_file_type(&home_file)?.is_dir() && !is_in_repo(&home_file, repo) {
+        log::debug!("Not folding {} since it's already a directory", home_file);
+        return Ok(None);
+    }
+
+    Ok(Some(source_dir))
+}
+
+/// Finds the directories above the targets of `links` that are symlinks into `owned_dirs`. These
+/// were folded by an earlier install, and have to be unfolded before `links` can be installed.
+pub(crate) fn folded_parents(home_dir: &HomePath, links: &[Link], owned_dirs: &[&Path]) -> Result<Vec<HomeFile>> {
+    let mut dirs = Vec::new();
+    for link in links {
+        for dir in parent_dirs(&link.target) {
+            let dir = home_dir.with_rel_file(&RelPath::new(dir));
+            if !dirs.contains(&dir) && restore::links_into_any(&dir, owned_dirs)? {
+                log::debug!("Unfolding {}", dir);
+                dirs.push(dir);
+            }
+        }
+    }
+    Ok(dirs)
+}
+
+/// Checks whether `file` is inside any of `dirs`.
+pub(crate) fn is_inside_any(file: &HomeFile, dirs: &[HomeFile]) -> bool {
+    dirs.iter().any(|dir| file.to_path_buf().starts_with(dir.to_path_buf()))
+}
+
+/// Gets the directories that `file` is in, not counting the home directory itself.
+pub(crate) fn parent_dirs(file: &RelPath) -> impl Iterator<Item = &Path> {
+    file.as_path_buf()
+        .ancestors()
+        .skip(1)
+        .filter(|dir| !dir.as_os_str().is_empty())
+}
+
+/// Removes `suffix` from the end of `path`, if `path` ends with it.
+fn strip_path_suffix<'a>(path: &'a Path, suffix: &Path) -> Option<&'a Path> {
+    if !path.ends_with(suffix) {
+        return None;
+    }
+    let mut prefix = path;
+    for _ in suffix.components() {
+        prefix = prefix.parent()?;
+    }
+    Some(prefix)
+}
+
+/// Checks whether `file` is really a directory in the repo that shows up in the home directory
+/// through a folded parent directory.
+fn is_in_repo(file: &HomeFile, repo: &Repo) -> bool {
+    file.to_path_buf().canonicalize().map_or(false, |path| {
+        repo.owned_dirs()
+            .iter()
+            .filter_map(|dir| dir.canonicalize().ok())
+            .any(|dir| path.starts_with(dir))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io, os::unix};
+
+    use super::*;
+    use crate::test_util::Fixture;
+
+    /// Makes a repo with the given files in `files/` and links for all of them.
+    fn make_repo(root: &Path, files: &[&str]) -> io::Result<(Repo, Vec<Link>)> {
+        let repo = Repo::new(root);
+        let links = files
+            .iter()
+            .map(|file| {
+                let source = repo.files().with_rel_file(&RelPath::new(file));
+                fs::create_dir_all(source.to_path_buf().parent().unwrap())?;
+                fs::write(source.to_path_buf(), "foo")?;
+                Ok(Link {
+                    source,
+                    target: RelPath::new(file),
+                    mode: LinkMode::Symlink,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok((repo, links))
+    }
+
+    fn targets(links: &[Link]) -> Vec<String> {
+        links.iter().map(|link| link.target.to_string()).collect()
+    }
+
+    #[test]
+    fn folds_marked_and_configured_dirs() -> io::Result<()> {
+        let repo_dir = Fixture::new()?;
+        let home = Fixture::new()?;
+        let home_dir = home.home_dir();
+        let (repo, links) = make_repo(
+            repo_dir.path(),
+            &[
+                ".config/nvim/init.lua",
+                ".config/nvim/lua/plugins.lua",
+                ".config/git/config",
+                ".zshrc",
+            ],
+        )?;
+        fs::write(repo.files().as_path().join(".config/nvim").join(FOLD_MARKER_FILE), "")?;
+        fs::create_dir(home.path().join(".config"))?;
+
+        let folded = fold_links(&home_dir, &repo, &Manifest::default(), links.clone()).unwrap();
+        assert_eq!(targets(&folded), [".config/nvim", ".config/git/config", ".zshrc"]);
+        assert_eq!(
+            folded[0].source,
+            repo.files().with_rel_file(&RelPath::new(".config/nvim"))
+        );
+
+        let manifest = Manifest::parse("dotfiles.toml", "[defaults]\nfold = true\n".to_string()).unwrap();
+        let folded = fold_links(&home_dir, &repo, &manifest, links).unwrap();
+        assert_eq!(targets(&folded), [".config/nvim", ".config/git", ".zshrc"]);
+        Ok(())
+    }
+
+    #[test]
+    fn shared_dirs_stay_unfolded() -> io::Result<()> {
+        let repo_dir = Fixture::new()?;
+        let home = Fixture::new()?;
+        let home_dir = home.home_dir();
+        let (repo, mut links) = make_repo(
+            repo_dir.path(),
+            &["a/x", "a/y", "b/x", "c/x", "c/y", "d/x", "d/y", "e/x"],
+        )?;
+        // Something in a/ that isn't linked.
+        fs::write(repo.files().as_path().join("a/not-linked"), "foo")?;
+        // b/ is a directory in the home directory.
+        fs::create_dir(home.path().join("b"))?;
+        // Something in c/ is copied.
+        links[3].mode = LinkMode::Copy;
+        // d/ has files from two layers.
+        let host_file = DotfilesPath::new(repo.hosts().join("host")).with_rel_file(&RelPath::new("d/y"));
+        fs::create_dir_all(host_file.to_path_buf().parent().unwrap())?;
+        fs::write(host_file.to_path_buf(), "foo")?;
+        links[6].source = host_file;
+
+        let manifest = Manifest::parse("dotfiles.toml", "[defaults]\nfold = true\n".to_string()).unwrap();
+        let folded = fold_links(&home_dir, &repo, &manifest, links).unwrap();
+
+        assert_eq!(targets(&folded), ["a/x", "a/y", "b/x", "c/x", "c/y", "d/x", "d/y", "e"]);
+        Ok(())
+    }
+
+    #[test]
+    fn finds_folded_parents() -> io::Result<()> {
+        let repo_dir = Fixture::new()?;
+        let home = Fixture::new()?;
+        let home_dir = home.home_dir();
+        let (repo, links) = make_repo(repo_dir.path(), &["a/b/x", "c/x"])?;
+        unix::fs::symlink(repo.files().as_path().join("a"), home.path().join("a"))?;
+        unix::fs::symlink("/somewhere/else", home.path().join("c"))?;
+
+        let parents = folded_parents(&home_dir, &links, &repo.owned_dirs()).unwrap();
+
+        let a = home_dir.with_rel_file(&RelPath::new("a"));
+        assert_eq!(parents, [a.clone()]);
+        assert!(is_inside_any(&home_dir.with_rel_file(&RelPath::new("a/b/x")), &parents));
+        assert!(!is_inside_any(&home_dir.with_rel_file(&RelPath::new("c/x")), &parents));
+        Ok(())
+    }
+}
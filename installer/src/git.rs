@@ -0,0 +1,62 @@
+use std::{
+    ffi::OsStr,
+    path::Path,
+    process::{Command, Output, Stdio},
+};
+
+use crate::error::{InstallError, Result};
+
+/// Runs git with `args` in `dir` and returns what it printed on stdout, without the trailing
+/// newline. Fails with git's error message if git fails.
+pub(crate) fn git<A: AsRef<OsStr>>(dir: &Path, args: &[A]) -> Result<String> {
+    let output = run(dir, args)?;
+    if !output.status.success() {
+        return Err(InstallError::Git {
+            args: args
+                .iter()
+                .map(|arg| arg.as_ref().to_string_lossy().into_owned())
+                .collect(),
+            dir: dir.to_path_buf(),
+            message: String::from_utf8_lossy(&output.stderr).trim_end().to_string(),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim_end().to_string())
+}
+
+/// Runs git with `args` in `dir` and checks whether it succeeded, for commands that fail to answer
+/// no, like `git cat-file -e`.
+pub(crate) fn git_succeeds<A: AsRef<OsStr>>(dir: &Path, args: &[A]) -> Result<bool> {
+    Ok(run(dir, args)?.status.success())
+}
+
+/// Runs git with `args` in `dir` with the terminal attached, for commands that might need the user,
+/// like `git commit` opening an editor or `git push` asking for a password.
+pub(crate) fn git_interactive<A: AsRef<OsStr>>(dir: &Path, args: &[A]) -> Result<()> {
+    let mut command = Command::new("git");
+    command.args(args).current_dir(dir);
+    log::debug!("Spawning {:?}", &command);
+    let status = command.status().map_err(InstallError::io("spawn", "git"))?;
+    log::debug!("git exited with {}", status);
+    if !status.success() {
+        let args: Vec<_> = args.iter().map(|arg| arg.as_ref().to_string_lossy()).collect();
+        return Err(InstallError::CommandFailed {
+            command: format!("git {}", args.join(" ")),
+            status,
+        });
+    }
+    Ok(())
+}
+
+fn run<A: AsRef<OsStr>>(dir: &Path, args: &[A]) -> Result<Output> {
+    let mut command = Command::new("git");
+    command
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        // Fail instead of waiting for a password that nobody is going to type.
+        .env("GIT_TERMINAL_PROMPT", "0");
+    log::debug!("Spawning {:?}", &command);
+    let output = command.output().map_err(InstallError::io("spawn", "git"))?;
+    log::debug!("git exited with {}", output.status);
+    Ok(output)
+}
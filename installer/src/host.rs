@@ -0,0 +1,68 @@
+use std::{
+    env,
+    path::{Component, Path},
+};
+
+use nix::unistd::{self, Uid, User};
+
+use crate::error::{InstallError, Result};
+
+/// The machine that the dotfiles are installed on. Manifest conditions and templates depend on it.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Host {
+    /// The hostname.
+    pub name: String,
+    /// The operating system, as in [`std::env::consts::OS`].
+    pub os: String,
+    /// The name of the current user.
+    pub user: String,
+    /// Which directory in `hosts/` to take host-specific dotfiles from. This is the hostname unless
+    /// it's been overridden.
+    pub profile: String,
+}
+
+impl Host {
+    /// Gets the details of the machine that we're running on. `profile` overrides the profile, which
+    /// defaults to the hostname. The profile has to be the name of a single directory in `hosts/`.
+    pub fn current(profile: Option<&str>) -> Result<Self> {
+        let name = unistd::gethostname()
+            .map_err(|e| InstallError::io("get", "hostname")(e.into()))?
+            .to_string_lossy()
+            .into_owned();
+        let user = User::from_uid(Uid::current())
+            .map_err(|e| InstallError::HomeNotFound(Some(e)))?
+            .ok_or(InstallError::HomeNotFound(None))?;
+        log::debug!("Running on {} ({}) as {}", name, env::consts::OS, user.name);
+        let profile = profile.unwrap_or(&name);
+        let mut components = Path::new(profile).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(InstallError::InvalidProfile(profile.to_string()));
+        }
+        Ok(Self {
+            profile: profile.to_string(),
+            name,
+            os: env::consts::OS.to_string(),
+            user: user.name,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn profiles_have_to_be_a_single_directory() {
+        for profile in ["", ".", "..", "/etc", "a/b", "./a"] {
+            assert!(
+                matches!(Host::current(Some(profile)), Err(InstallError::InvalidProfile(p)) if p == profile),
+                "{:?}",
+                profile
+            );
+        }
+        assert_eq!(Host::current(Some("laptop")).unwrap().profile, "laptop");
+    }
+}
@@ -1,442 +1,788 @@
 use std::{
-    env,
+    collections::BTreeMap,
     ffi::OsStr,
-    fs::{self, FileType},
-    io::{self, ErrorKind},
-    os::unix::{self, process::ExitStatusExt},
+    fs::{self, File, FileType},
+    io::{self, ErrorKind, Read},
+    num::NonZeroUsize,
+    os::unix::{self, fs::PermissionsExt},
     path::{Path, PathBuf},
     process::{Command, Stdio},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    thread,
 };
 
-use chrono::Local;
 use nix::{
     errno::Errno,
     unistd::{self, AccessFlags, Uid, User},
 };
 
-use crate::fail;
+use crate::backup::{self, Backup, BackupStore, Run};
+use crate::conflict::{self, ConflictKind};
+use crate::error::{InstallError, Result};
+use crate::fold::{self, FOLD_MARKER_FILE};
+use crate::host::Host;
+use crate::journal::{Journal, Undo};
+use crate::manifest::{Link, LinkMode, Manifest};
 use crate::path::*;
+use crate::plan::{Action, Plan};
+use crate::repo::Repo;
+use crate::restore;
+use crate::status;
+use crate::submodule;
+use crate::template::{self, Variables};
+
+/// Where older versions of the installer linked the whole submodules directory, before each
+/// submodule got a link of its own.
+const OLD_SUBMODULE_LINK: &str = ".dotfiles-submodules";
+
+/// Plans installing the dotfiles in `repo` into `home_dir` on `host`, backing up what they replace
+/// to `store`. Files that are in the way of a dotfile's parent directories are replaced too, see
+/// [`conflict::find_conflicts`].
+pub fn plan_install(home_dir: &HomePath, repo: &Repo, host: &Host, store: &BackupStore) -> Result<Plan> {
+    let manifest = Manifest::load(repo)?;
+    let owned_dirs = repo.owned_dirs();
+    let links = fold::fold_links(home_dir, repo, &manifest, find_links(repo, &manifest, host)?)?;
+    let unfolded = fold::folded_parents(home_dir, &links, &owned_dirs)?;
+    let vars = manifest.variables(host, home_dir);
+    let links = outdated_links(home_dir, links, &unfolded, &vars)?;
+    let mut replaced = Vec::new();
+    for conflict in conflict::find_conflicts(home_dir, &links, &unfolded, &owned_dirs)? {
+        log::info!("{}", conflict);
+        if conflict.kind == ConflictKind::FileForDir {
+            replaced.push(conflict.file);
+        }
+    }
+    let targets: Vec<_> = links
+        .iter()
+        .map(|link| link.target.clone())
+        .filter(|target| !fold::is_inside_any(&home_dir.with_rel_file(target), &unfolded))
+        .chain(replaced.iter().map(|file| file.rel_file().clone()))
+        .collect();
+
+    let backup = store.new_backup();
+    let mut plan = Plan::new();
+    plan.push(Action::CheckoutSubmodules(repo.root().to_path_buf()));
+    backup::plan_backup(
+        &mut plan,
+        home_dir,
+        &backup,
+        &Run::new("install", repo),
+        &targets,
+        &owned_dirs,
+    )?;
+    for file in replaced {
+        plan.push(Action::Remove(file, backup.clone()));
+    }
+    plan_unlink_old_submodule_link(&mut plan, home_dir, repo)?;
+    plan_links(&mut plan, home_dir, &backup, &links, &unfolded, &owned_dirs, &vars)?;
+    plan_install_scripts(&mut plan, repo.scripts(), repo.files(), repo.root())?;
+    Ok(plan)
+}
 
-const FILES_DIR: &str = "files";
-const SCRIPTS_DIR: &str = "installer/scripts";
-const BACKUP_DIR_FORMAT: &str = "dotfiles-backup_%Y-%m-%d_%H:%M:%S";
-const README_FILE: &str = "README.md";
-const README_CONTENTS: &str = include_str!("backup_dir_readme.md");
-const HOME_SUBMODULE_DIR: &str = ".dotfiles-submodules";
-const REPO_SUBMODULE_DIR: &str = "submodules";
+/// Finds all dotfiles in `repo` and works out where and how each of them gets installed on `host`
+/// according to `manifest`. The submodules that have a target are symlinked there too.
+pub fn find_links(repo: &Repo, manifest: &Manifest, host: &Host) -> Result<Vec<Link>> {
+    let files = merge_layers(repo, &repo.layers(&host.profile))?;
+    let mut links = manifest.links(&files, host)?;
+    let submodule_links = submodule_links(repo, &links)?;
+    links.extend(submodule_links);
+    Ok(links)
+}
 
-pub fn install() {
-    let home_dir = find_home_dir();
-    log::info!("Found home directory: {}", home_dir);
+/// Works out the links for the submodules in `repo` that have a target in `.gitmodules`. A
+/// submodule can't be linked on top of or into any of the dotfile `links`, or another submodule.
+fn submodule_links(repo: &Repo, links: &[Link]) -> Result<Vec<Link>> {
+    let submodules_dir = DotfilesPath::new(repo.submodules().as_path());
+    let mut submodule_links: Vec<Link> = Vec::new();
+
+    for submodule in submodule::load(repo.root())? {
+        let Some(target) = &submodule.target else {
+            log::debug!("Not linking submodule {} since it has no target", submodule.name);
+            continue;
+        };
+        let cant_link = |reason| InstallError::CantLinkSubmodule {
+            name: submodule.name.clone(),
+            reason,
+        };
+
+        let dir = submodule.dir(repo.root());
+        let source = match dir.strip_prefix(submodules_dir.as_path()) {
+            Ok(rel_dir) if !rel_dir.as_os_str().is_empty() => submodules_dir.with_rel_file(&RelPath::new(rel_dir)),
+            _ => return Err(cant_link(format!("it isn't in {}", submodules_dir))),
+        };
+        if let Some(other) = links.iter().chain(&submodule_links).find(|link| {
+            let other = link.target.as_path_buf();
+            other.starts_with(target) || target.starts_with(other)
+        }) {
+            return Err(cant_link(format!("{} is installed to {}", other.source, other.target)));
+        }
 
-    let (file_root, submodules_path, scripts_path) = find_repo_dirs();
-    let repo_root = file_root.as_path_buf().parent().unwrap();
-    log::info!("Found dotfile repo: {}", repo_root.to_string_lossy());
-    log::info!("Dotfile directory: {}", file_root);
+        submodule_links.push(Link {
+            source,
+            target: RelPath::new(target),
+            mode: LinkMode::Symlink,
+        });
+    }
 
-    log::info!("Checking out submodules");
-    checkout_submodules(repo_root);
-    log::info!("Checkout done");
+    Ok(submodule_links)
+}
 
-    log::debug!("Finding dotfiles");
-    let files = recurse_through_dir(file_root.as_path_buf(), |p| {
-        RelPath::new(p.strip_prefix(file_root.as_path_buf()).unwrap())
-    });
-    log::debug!(
-        "Found {}",
-        files.iter().map(|f| format!("{}", f)).collect::<Vec<_>>().join(" ")
-    );
+/// Plans removing the link to the whole submodules directory that older versions of the installer
+/// made in `home_dir`, if it's there.
+pub(crate) fn plan_unlink_old_submodule_link(plan: &mut Plan, home_dir: &HomePath, repo: &Repo) -> Result<()> {
+    let link = home_dir.with_rel_file(&RelPath::new(OLD_SUBMODULE_LINK));
+    if links_into(&link, repo.submodules().as_path())? {
+        plan.push(Action::Unlink(link));
+    }
+    Ok(())
+}
 
-    log::info!("Creating backup");
-    let backup_dir = create_backup(&home_dir, &files);
-    log::info!("Backup done");
-
-    log::info!("Installing symlinks");
-    create_symlinks(&home_dir, &file_root, &backup_dir, &files);
-    create_submodule_symlink(&home_dir, &backup_dir, &submodules_path);
-    log::info!("Symlinking done");
-
-    log::info!("Running install scripts");
-    run_install_scripts(scripts_path, &file_root, repo_root);
-    log::info!("Install scripts done");
-
-    log::info!("");
-    log::info!("Happy hacking!");
-}
-
-fn find_repo_dirs() -> (DotfilesPath, SubmodulesPath, InstallScriptsPath) {
-    log::debug!("Finding repo root");
-    let binary_path = env::current_exe().expect("Failed to get the path to the binary!");
-    // env::current_exe *seems* to always return an absolute path, but let's make sure.
-    assert!(binary_path.is_absolute());
-
-    for dir in binary_path.parent().unwrap().ancestors() {
-        log::debug!("Checking {}", dir.to_string_lossy());
-        if dir.join(".git").is_dir() {
-            let path_buf = dir.to_path_buf();
-            return (
-                DotfilesPath::new(path_buf.join(FILES_DIR)),
-                SubmodulesPath::new(path_buf.join(REPO_SUBMODULE_DIR)),
-                InstallScriptsPath::new(path_buf.join(SCRIPTS_DIR)),
-            );
+/// Leaves out the links that are already installed the way they should be, so that installing again
+/// doesn't touch them. Links inside the folded directories in `unfolded` are always kept since they
+/// have to be recreated once the directory has been unfolded.
+fn outdated_links(home_dir: &HomePath, links: Vec<Link>, unfolded: &[HomeFile], vars: &Variables) -> Result<Vec<Link>> {
+    let mut outdated = Vec::with_capacity(links.len());
+    for link in links {
+        let file = home_dir.with_rel_file(&link.target);
+        if !fold::is_inside_any(&file, unfolded)
+            && status::installed_state(&file, &link.source, link.mode, vars)?.is_ok()
+        {
+            log::debug!("Not touching {} since it's already installed", file);
+        } else {
+            outdated.push(link);
+        }
+    }
+    Ok(outdated)
+}
+
+/// Finds the dotfiles in every layer. A file in a later layer replaces any file at the same path in
+/// earlier layers, along with any file in earlier layers that's in the way of it, like a file where
+/// it needs a directory.
+fn merge_layers(repo: &Repo, layers: &[DotfilesPath]) -> Result<Vec<Dotfile>> {
+    let mut files: BTreeMap<RelPath, Dotfile> = BTreeMap::new();
+
+    for layer in layers {
+        for file in find_dotfiles(layer)? {
+            let path = file.as_path_buf();
+            files.retain(|other, dotfile| {
+                let other = other.as_path_buf();
+                let overridden = other.starts_with(path) || path.starts_with(other);
+                if overridden {
+                    log::info!(
+                        "Using {} from {} instead of {} from {}",
+                        file,
+                        repo.layer_name(layer),
+                        dotfile.rel_file(),
+                        repo.layer_name(dotfile.prefix())
+                    );
+                }
+                !overridden
+            });
+            files.insert(file.clone(), layer.with_rel_file(&file));
         }
     }
 
-    fail!("Couldn't find the root of the dotfile repository! Make sure to run this binary inside the repo.")
+    Ok(files.into_values().collect())
 }
 
-fn find_home_dir() -> HomePath {
-    log::debug!("Finding home directory");
-    const ERR: &str = "Failed to get user info for the current user";
-    HomePath::new(
-        User::from_uid(Uid::current())
-            .map_err(|e| fail!("{}: {}", ERR, e))
-            .unwrap()
-            .expect(ERR)
-            .dir,
-    )
+/// Finds all dotfiles in `file_root`, leaving out fold markers.
+pub fn find_dotfiles(file_root: &DotfilesPath) -> Result<Vec<RelPath>> {
+    log::debug!("Finding dotfiles");
+    let mut files = recurse_through_dir(file_root.as_path_buf(), |p| {
+        Ok(RelPath::new(p.strip_prefix(file_root.as_path_buf()).unwrap()))
+    })?;
+    files.retain(|file| file.as_path_buf().file_name() != Some(OsStr::new(FOLD_MARKER_FILE)));
+    log::debug!(
+        "Found {}",
+        files.iter().map(|f| format!("{}", f)).collect::<Vec<_>>().join(" ")
+    );
+    Ok(files)
 }
 
-fn make_home_submodule_dir(home_dir: &HomePath) -> HomeFile {
-    home_dir.with_rel_file(&RelPath::new(HOME_SUBMODULE_DIR))
+pub fn find_home_dir() -> Result<HomePath> {
+    log::debug!("Finding home directory");
+    let user = User::from_uid(Uid::current())
+        .map_err(|e| InstallError::HomeNotFound(Some(e)))?
+        .ok_or(InstallError::HomeNotFound(None))?;
+    Ok(HomePath::new(user.dir))
 }
 
-fn symlinks_equal(a: &HomeFile, b: &BackupFile) -> bool {
-    assert!(get_file_type(a).is_symlink());
-    assert!(get_file_type(b).is_symlink());
-
-    let a_target = a
-        .to_path_buf()
-        .read_link()
-        .map_err(|e| fail!("Failed to read symlink {}: {}", a, e))
-        .unwrap();
-    let b_target = b
-        .to_path_buf()
-        .read_link()
-        .map_err(|e| fail!("Failed to read symlink {}: {}", b, e))
-        .unwrap();
+fn symlinks_equal(a: &Path, b: &Path) -> Result<bool> {
+    let a_target = a.read_link().map_err(InstallError::io("read symlink", a))?;
+    let b_target = b.read_link().map_err(InstallError::io("read symlink", b))?;
 
     let equal = a_target == b_target;
     if equal {
         log::trace!(
             "Symlinks ({} -> {}) and ({} -> {}) are equal",
-            a,
+            a.to_string_lossy(),
             a_target.to_string_lossy(),
-            b,
+            b.to_string_lossy(),
             b_target.to_string_lossy()
         )
     } else {
         log::warn!(
             "Symlinks ({} -> {}) and ({} -> {}) have different targets",
-            a,
+            a.to_string_lossy(),
             a_target.to_string_lossy(),
-            b,
+            b.to_string_lossy(),
             b_target.to_string_lossy()
         )
     }
-    equal
+    Ok(equal)
 }
 
-fn files_equal_by_contents(a: &HomeFile, b: &BackupFile) -> bool {
-    assert!(get_file_type(a).is_file());
-    assert!(get_file_type(b).is_file());
+/// How much of each file [`files_equal_by_contents`] reads at a time.
+const COMPARE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Compares the contents of the files at `a` and `b` a chunk at a time, so that comparing big files
+/// doesn't take much memory. Files of different sizes aren't read at all.
+fn files_equal_by_contents(a: &Path, b: &Path) -> Result<bool> {
+    let mut a_file = File::open(a).map_err(InstallError::io("read", a))?;
+    let mut b_file = File::open(b).map_err(InstallError::io("read", b))?;
+    let a_len = a_file
+        .metadata()
+        .map_err(InstallError::io("get metadata for", a))?
+        .len();
+    let b_len = b_file
+        .metadata()
+        .map_err(InstallError::io("get metadata for", b))?
+        .len();
+    if a_len != b_len {
+        log::warn!(
+            "Files {} and {} have different sizes",
+            a.to_string_lossy(),
+            b.to_string_lossy()
+        );
+        return Ok(false);
+    }
 
-    let a_contents = fs::read(a.to_path_buf()).map_err(|e| fail!("Failed to read {}: {}", a, e));
-    let b_contents = fs::read(b.to_path_buf()).map_err(|e| fail!("Failed to read {}: {}", b, e));
-    (a_contents == b_contents)
-        .then(|| log::trace!("Files {} and {} are equal", a, b))
-        .ok_or_else(|| log::warn!("Files {} and {} have different contents", a, b))
-        .is_ok()
+    let mut a_buf = vec![0; COMPARE_CHUNK_SIZE];
+    let mut b_buf = vec![0; COMPARE_CHUNK_SIZE];
+    loop {
+        let a_read = read_chunk(&mut a_file, &mut a_buf).map_err(InstallError::io("read", a))?;
+        let b_read = read_chunk(&mut b_file, &mut b_buf).map_err(InstallError::io("read", b))?;
+        if a_buf[..a_read] != b_buf[..b_read] {
+            log::warn!(
+                "Files {} and {} have different contents",
+                a.to_string_lossy(),
+                b.to_string_lossy()
+            );
+            return Ok(false);
+        }
+        if a_read == 0 {
+            log::trace!("Files {} and {} are equal", a.to_string_lossy(), b.to_string_lossy());
+            return Ok(true);
+        }
+    }
 }
 
-fn files_equal(a: &HomeFile, b: &BackupFile) -> bool {
-    log::debug!("Checking that {} and {} are equal", a, b);
+/// Fills `buf` from `reader`, unless it runs out first. Returns how much was read.
+fn read_chunk<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
 
-    let a_type = get_file_type(a);
-    let b_type = get_file_type(b);
-    if a_type != b_type {
-        log::warn!("Files {} and {} have different file types", a, b);
-        return false;
+/// Compares the contents of each pair of files in `files`, several pairs at a time. Stops early once a
+/// pair is found to differ.
+fn all_contents_equal(files: &[(PathBuf, PathBuf)]) -> Result<bool> {
+    let threads = thread::available_parallelism()
+        .map_or(1, NonZeroUsize::get)
+        .min(files.len());
+    if threads <= 1 {
+        for (a, b) in files {
+            if !files_equal_by_contents(a, b)? {
+                return Ok(false);
+            }
+        }
+        return Ok(true);
+    }
+
+    let next = AtomicUsize::new(0);
+    let different = AtomicBool::new(false);
+    let compare = || -> Result<()> {
+        while !different.load(Ordering::Relaxed) {
+            let Some((a, b)) = files.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                break;
+            };
+            if !files_equal_by_contents(a, b)? {
+                different.store(true, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    };
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads).map(|_| scope.spawn(compare)).collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("Comparing files panicked"))
+    })?;
+    Ok(!different.load(Ordering::Relaxed))
+}
+
+/// Checks that the files, symlinks, or directory trees in each pair in `pairs` are the same. The
+/// structure of the trees and the sizes of the files in them are checked first, so that the contents
+/// of the files only have to be read if everything else matches. The contents of all the files are
+/// then compared together.
+pub(crate) fn all_files_equal(pairs: &[(PathBuf, PathBuf)]) -> Result<bool> {
+    let mut files = Vec::new();
+    for (a, b) in pairs {
+        log::debug!(
+            "Checking that {} and {} are equal",
+            a.to_string_lossy(),
+            b.to_string_lossy()
+        );
+        if !structure_equal(a, b, &mut files)? {
+            return Ok(false);
+        }
+    }
+    all_contents_equal(&files)
+}
+
+/// Checks that the trees at `a` and `b` have the same file types, symlink targets, and file sizes,
+/// adding the pairs of regular files whose contents still have to be compared to `files`.
+fn structure_equal(a: &Path, b: &Path, files: &mut Vec<(PathBuf, PathBuf)>) -> Result<bool> {
+    let a_metadata = a.symlink_metadata().map_err(InstallError::io("get metadata for", a))?;
+    let b_metadata = b.symlink_metadata().map_err(InstallError::io("get metadata for", b))?;
+    let a_type = a_metadata.file_type();
+    if a_type != b_metadata.file_type() {
+        log::warn!(
+            "Files {} and {} have different file types",
+            a.to_string_lossy(),
+            b.to_string_lossy()
+        );
+        return Ok(false);
     }
 
     if a_type.is_file() {
-        files_equal_by_contents(a, b)
+        if a_metadata.len() != b_metadata.len() {
+            log::warn!(
+                "Files {} and {} have different sizes",
+                a.to_string_lossy(),
+                b.to_string_lossy()
+            );
+            return Ok(false);
+        }
+        files.push((a.to_path_buf(), b.to_path_buf()));
+        Ok(true)
     } else if a_type.is_symlink() {
         symlinks_equal(a, b)
     } else if a_type.is_dir() {
-        recurse_through_dir(a.to_path_buf(), |p| {
-            let rel_path = RelPath::new(p.strip_prefix(a.to_path_buf()).unwrap());
-            let a = HomeFile::new(&HomePath::new(a.to_path_buf()), &rel_path);
-            let b = BackupFile::new(&BackupPath::new(b.to_path_buf()), &rel_path);
-            files_equal(&a, &b)
-        })
-        .iter()
-        .all(|&x| x)
+        for entry in a.read_dir().map_err(InstallError::io("read directory", a))? {
+            let name = entry
+                .map_err(InstallError::io("get directory entry in", a))?
+                .file_name();
+            if !structure_equal(&a.join(&name), &b.join(&name), files)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
     } else {
-        fail!(
-            "Can't compare files {} and {} with unknown file type {:?}",
-            a,
-            b,
-            a_type
-        );
+        Err(InstallError::UnknownFileType {
+            path: a.to_path_buf(),
+            file_type: a_type,
+        })
     }
 }
 
-fn verify_backup_and_remove(file: &RelPath, home_dir: &HomePath, backup_dir: &BackupPath) {
-    let home_file = home_dir.with_rel_file(file);
-    let backup_file = backup_dir.with_rel_file(file);
-
-    if !files_equal(&home_file, &backup_file) {
-        log::error!("Not deleting {} because it's not backed up properly", home_file);
-        fail!("Bailing out!");
+fn verify_backup_and_remove(home_file: &HomeFile, backup: &Backup) -> Result<()> {
+    if !backup.matches(home_file)? {
+        return Err(InstallError::BackupMismatch {
+            file: home_file.to_path_buf(),
+            backup: backup.name().to_string(),
+        });
     }
 
-    let home_file_type = get_file_type(&home_file);
+    let home_file_type = get_file_type(home_file)?;
     if home_file_type.is_symlink() || home_file_type.is_file() {
         log::debug!("Deleting {}", home_file);
-        fs::remove_file(home_file.to_path_buf())
-            .map_err(|e| fail!("Failed to remove {}: {}", home_file, e))
-            .unwrap();
+        fs::remove_file(home_file.to_path_buf()).map_err(InstallError::io("remove", home_file.to_path_buf()))
     } else if home_file_type.is_dir() {
         log::debug!("Deleting {} recursively", home_file);
-        fs::remove_dir_all(home_file.to_path_buf())
-            .map_err(|e| fail!("Failed to remove {}: {}", home_file, e))
-            .unwrap();
+        fs::remove_dir_all(home_file.to_path_buf()).map_err(InstallError::io("remove", home_file.to_path_buf()))
     } else {
-        fail!(
-            "Not deleting {} because it has the unknown type {:?}",
-            home_file,
-            home_file_type
-        );
+        Err(InstallError::UnknownFileType {
+            path: home_file.to_path_buf(),
+            file_type: home_file_type,
+        })
     }
 }
 
-fn copy_symlink(link: &HomeFile, destination: &BackupFile) -> io::Result<()> {
-    let target = fs::read_link(link.to_path_buf())?;
-    log::trace!(
-        "Coping symlink ({} -> {}) to {}",
-        link,
-        target.to_string_lossy(),
-        destination
-    );
-    unix::fs::symlink(&target, destination.to_path_buf())
+/// Checks that a file/directory exists. Note that unlike Path::exists(), this does not follow
+/// symlinks, meaning that it will return `true` for broken symlinks. Paths below a file don't exist
+/// either.
+pub(crate) fn file_exists<P: FilePath>(path: &P) -> bool {
+    !matches!(
+        path.to_path_buf().read_link(),
+        Err(e) if e.kind() == ErrorKind::NotFound || e.raw_os_error() == Some(Errno::ENOTDIR as i32)
+    )
 }
 
-/// Checks that a file/directory exists. Note that unlike Path::exists(), this does not follow
-/// symlinks, meaning that it will return `true` for broken symlinks.
-fn file_exists<P: FilePath>(path: &P) -> bool {
-    !matches!(path.to_path_buf().read_link(), Err(e) if e.kind() == ErrorKind::NotFound)
-}
-
-fn is_symlink<P: FilePath>(path: &P) -> bool {
-    file_exists(path)
-        && path
-            .to_path_buf()
-            .symlink_metadata()
-            .map_err(|e| fail!("Failed to get metadata for {}: {}", path, e))
-            .unwrap()
-            .file_type()
-            .is_symlink()
+pub(crate) fn is_symlink<P: FilePath>(path: &P) -> Result<bool> {
+    Ok(file_exists(path) && get_file_type(path)?.is_symlink())
 }
 
-fn get_file_type<P: FilePath>(path: &P) -> FileType {
-    let ret = path
+/// Checks whether `path` is a symlink to `dir` or something inside it. This is how we recognize
+/// links created by the installer.
+pub(crate) fn links_into<P: FilePath>(path: &P, dir: &Path) -> Result<bool> {
+    if !is_symlink(path)? {
+        return Ok(false);
+    }
+
+    let target = path
         .to_path_buf()
+        .read_link()
+        .map_err(InstallError::io("read symlink", path.to_path_buf()))?;
+    Ok(target.starts_with(dir))
+}
+
+pub(crate) fn get_file_type<P: FilePath>(path: &P) -> Result<FileType> {
+    path_file_type(&path.to_path_buf())
+}
+
+fn path_file_type(path: &Path) -> Result<FileType> {
+    let ret = path
         .symlink_metadata()
-        .map_err(|e| fail!("Failed to get metadata for {}: {}", path, e))
-        .unwrap()
+        .map_err(InstallError::io("get metadata for", path))?
         .file_type();
-    log::trace!("File {} has filetype {:?}", path, ret);
-    ret
+    log::trace!("File {} has filetype {:?}", path.to_string_lossy(), ret);
+    Ok(ret)
 }
 
-fn recurse_through_dir<P: AsRef<Path>, F, R>(path: P, mut op: F) -> Vec<R>
+pub(crate) fn recurse_through_dir<P: AsRef<Path>, F, R>(path: P, mut op: F) -> Result<Vec<R>>
 where
-    F: FnMut(PathBuf) -> R + Copy,
+    F: FnMut(PathBuf) -> Result<R> + Copy,
 {
     let path = path.as_ref();
     let mut ret = Vec::new();
 
-    for entry in path.read_dir().unwrap() {
-        let entry = entry
-            .map_err(|e| fail!("Failed to get directory entry in {}: {}", path.to_string_lossy(), e))
-            .unwrap();
+    for entry in path.read_dir().map_err(InstallError::io("read directory", path))? {
+        let entry = entry.map_err(InstallError::io("get directory entry in", path))?;
         let entry_path = entry.path();
         let is_dir = entry
             .file_type()
-            .map_err(|e| fail!("Failed to get file type for {}: {}", entry_path.to_string_lossy(), e))
-            .unwrap()
+            .map_err(InstallError::io("get file type for", &entry_path))?
             .is_dir();
         if is_dir {
-            ret.append(&mut recurse_through_dir(entry_path, op));
+            ret.append(&mut recurse_through_dir(entry_path, op)?);
         } else {
-            ret.push(op(entry_path));
+            ret.push(op(entry_path)?);
         }
     }
 
-    ret
+    Ok(ret)
 }
 
-fn create_backup(home_dir: &HomePath, files: &[RelPath]) -> BackupPath {
-    let backup_dir = create_backup_dir(home_dir);
-
-    for file in files {
-        log::info!("Backing up {}", file);
-        backup_file(file, home_dir, &backup_dir);
+/// Plans installing `links`, first unlinking the folded directories in `unfolded`. Whatever is in the
+/// way of a link is removed, except for links into `owned_dirs`, which are just unlinked since they
+/// don't get backed up.
+fn plan_links(
+    plan: &mut Plan,
+    home_dir: &HomePath,
+    backup: &Backup,
+    links: &[Link],
+    unfolded: &[HomeFile],
+    owned_dirs: &[&Path],
+    vars: &Variables,
+) -> Result<()> {
+    let mut created_dirs = Vec::new();
+
+    for dir in unfolded {
+        plan.push(Action::Unlink(dir.clone()));
     }
 
-    backup_submodules(home_dir, &backup_dir);
-
-    backup_dir
-}
-
-fn create_backup_dir(home_dir: &HomePath) -> BackupPath {
-    let timestamp = Local::now();
-    let backup_dir_name = timestamp.format(BACKUP_DIR_FORMAT).to_string();
-    let backup_dir = BackupPath::new(home_dir.with_rel_file(&RelPath::new(backup_dir_name)).to_path_buf());
-
-    log::info!("Creating backup directory {}", backup_dir);
-    fs::create_dir(backup_dir.as_path_buf())
-        .map_err(|e| fail!("Failed to create backup directory {}: {}", backup_dir, e))
-        .unwrap();
+    for link in links {
+        let source = link.source.clone();
+        let file = home_dir.with_rel_file(&link.target);
 
-    let readme_file = backup_dir.with_rel_file(&RelPath::new(README_FILE));
+        if file_exists(&file) && !fold::is_inside_any(&file, unfolded) {
+            plan.push(if restore::links_into_any(&file, owned_dirs)? {
+                Action::Unlink(file.clone())
+            } else {
+                Action::Remove(file.clone(), backup.clone())
+            });
+        } else {
+            plan_parent_dir(plan, &file, unfolded, &mut created_dirs);
+        }
 
-    log::debug!("Creating readme file {}", readme_file);
-    fs::write(readme_file.to_path_buf(), README_CONTENTS)
-        .map_err(|e| fail!("Failed to create readme file {}: {}", readme_file, e))
-        .unwrap();
+        plan.push(match link.mode {
+            LinkMode::Symlink => Action::Symlink {
+                target: source.to_path_buf(),
+                link: file,
+            },
+            LinkMode::Copy => Action::Copy(source, file),
+            LinkMode::Hardlink => Action::Hardlink(source, file),
+            LinkMode::Template => Action::Render {
+                contents: template::render_file(&source, vars)?,
+                template: source,
+                file,
+            },
+        });
+    }
 
-    backup_dir
+    Ok(())
 }
 
-fn backup_file(relative_file: &RelPath, home_dir: &HomePath, backup_dir: &BackupPath) {
-    let file = home_dir.with_rel_file(relative_file);
+/// Plans creating the parent directory of `file` if there isn't a directory there. `created_dirs`
+/// keeps track of the directories that earlier actions in the plan will have created by the time we
+/// get to `file`, directories in `unfolded` will have been unlinked, and files in the way of the
+/// directory will have been removed as conflicts.
+fn plan_parent_dir(plan: &mut Plan, file: &HomeFile, unfolded: &[HomeFile], created_dirs: &mut Vec<PathBuf>) {
+    let dir = file.parent().unwrap();
+    let exists = dir.to_path_buf().is_dir() && !fold::is_inside_any(&dir, unfolded);
+    if !exists && !created_dirs.iter().any(|d| d.starts_with(dir.to_path_buf())) {
+        created_dirs.push(dir.to_path_buf());
+        plan.push(Action::CreateDir(dir));
+    }
+}
 
-    if !file_exists(&file) {
-        log::debug!("Not backing up {} because it doesn't exist", file);
-        return;
+fn plan_install_scripts(
+    plan: &mut Plan,
+    dir: &InstallScriptsPath,
+    file_root: &DotfilesPath,
+    repo_root: &Path,
+) -> Result<()> {
+    if !dir.as_path().is_dir() {
+        return Err(InstallError::NotADirectory(dir.as_path().to_path_buf()));
     }
 
-    let backup_file = backup_dir.with_rel_file(relative_file);
-    log::trace!("Backing up {} to {}", file, backup_file);
+    let mut scripts = recurse_through_dir(dir.as_path(), Ok)?;
+    scripts.sort();
 
-    let backup_subdir = backup_file.parent().unwrap();
-    if !file_exists(&backup_subdir) {
-        log::debug!("Creating subdirectory {} in backup directory", backup_subdir);
-        fs::create_dir_all(backup_subdir.to_path_buf())
-            .map_err(|e| fail!("Failed to create directory {}: {}", backup_subdir, e))
-            .unwrap();
+    for script in scripts {
+        let name = command_name(&script)?;
+        match unistd::access(&script, AccessFlags::X_OK) {
+            Ok(()) => plan.push(Action::RunScript {
+                script,
+                file_root: file_root.clone(),
+                repo_root: repo_root.to_path_buf(),
+            }),
+            Err(Errno::EACCES) => {
+                log::warn!("Skipping {} because it does not have execute permissions", name);
+            }
+            Err(errno) => return Err(InstallError::io("check permissions of", &script)(errno.into())),
+        }
     }
 
-    if is_symlink(&file) {
-        copy_symlink(&file, &backup_file)
-            .map_err(|e| {
-                let link_target = fs::read_link(file.to_path_buf())
-                    .unwrap_or_else(|_| Path::new("<couldn't read symlink>").to_path_buf());
-                fail!(
-                    "Failed to copy symlink ({} -> {}) to {}: {}",
-                    file,
-                    link_target.to_string_lossy(),
-                    backup_file,
-                    e
-                )
-            })
-            .unwrap();
-    } else {
-        fs::copy(file.to_path_buf(), backup_file.to_path_buf())
-            .map_err(|e| fail!("Failed to copy {} to {}: {}", file, backup_file, e))
-            .unwrap();
-    }
+    Ok(())
 }
 
-fn backup_submodules(home_dir: &HomePath, backup_dir: &BackupPath) {
-    let submodule_dir = make_home_submodule_dir(home_dir);
-
-    if !file_exists(&submodule_dir) {
-        log::debug!(
-            "Not backing up submodule dir {} because it doesn't exist",
-            submodule_dir
-        );
-        return;
-    }
-
-    let submodule_dir_type = get_file_type(&submodule_dir);
-    log::debug!("Got submodule dir type {:?}", submodule_dir_type);
-
-    log::info!("Backing up submodules at ~/{}", submodule_dir.rel_file());
-    if submodule_dir_type.is_symlink() {
-        log::debug!("Backing up submodules by copying symlink");
-        backup_file(&RelPath::new(HOME_SUBMODULE_DIR), home_dir, backup_dir);
-    } else if submodule_dir_type.is_dir() {
-        log::debug!("Backing up submodules by copying file-by-file");
-        let files = recurse_through_dir(submodule_dir.to_path_buf(), |p| {
-            backup_file(
-                &RelPath::new(p.strip_prefix(home_dir.as_path_buf()).unwrap()),
-                home_dir,
-                backup_dir,
-            )
-        });
-        log::info!("Backed up {} files from submodule dir", files.len());
-    } else {
-        fail!(
-            "Can't back up submodule dir because it has the unknown type {:?}",
-            submodule_dir_type
-        );
+pub(crate) fn execute(action: &Action, journal: &mut Journal) -> Result<()> {
+    log::debug!("{}", action);
+    match action {
+        Action::CheckoutSubmodules(repo_root) => {
+            log::info!("Checking out missing submodules");
+            submodule::init_missing(repo_root)?;
+        }
+        Action::FixSubmodules(repo_root) => {
+            log::info!("Fixing the paths of the submodules");
+            submodule::fix_moved(repo_root)?;
+        }
+        Action::CreateBackup(backup, run) => {
+            backup.create(run)?;
+            journal.record(Undo::DeleteBackup(backup.clone()));
+        }
+        Action::DeleteBackup(backup) => {
+            log::info!("Deleting backup {}", backup);
+            backup.delete()?;
+        }
+        Action::DeleteLegacyBackup(backup) => {
+            log::info!("Deleting old backup {}", backup);
+            backup.delete()?;
+        }
+        Action::Backup(file, backup) => {
+            log::info!("Backing up {}", file.rel_file());
+            backup.add(file)?;
+        }
+        Action::Remove(file, backup) => {
+            verify_backup_and_remove(file, backup)?;
+            journal.record(Undo::Restore(backup.clone(), file.clone()));
+        }
+        Action::Unlink(link) => {
+            log::info!("Unlinking {}", link.rel_file());
+            let target =
+                fs::read_link(link.to_path_buf()).map_err(InstallError::io("read symlink", link.to_path_buf()))?;
+            fs::remove_file(link.to_path_buf()).map_err(InstallError::io("remove", link.to_path_buf()))?;
+            journal.record(Undo::Relink {
+                link: link.clone(),
+                target,
+            });
+        }
+        Action::RemoveCopy(file) => {
+            log::info!("Deleting {}", file.rel_file());
+            let path = file.to_path_buf();
+            let contents = fs::read(&path).map_err(InstallError::io("read", &path))?;
+            let mode = path
+                .metadata()
+                .map_err(InstallError::io("get metadata for", &path))?
+                .permissions()
+                .mode();
+            fs::remove_file(&path).map_err(InstallError::io("remove", &path))?;
+            journal.record(Undo::Write {
+                file: file.clone(),
+                contents,
+                mode,
+            });
+        }
+        Action::Restore(backup, file) => {
+            log::info!("Restoring {}", file.rel_file());
+            create_dir_all(&file.parent().unwrap(), journal)?;
+            restore::restore_file(backup, file)?;
+            journal.record(Undo::Delete(file.clone()));
+        }
+        Action::RestoreLegacy(backup, file) => {
+            log::info!("Restoring {}", file.rel_file());
+            create_dir_all(&file.parent().unwrap(), journal)?;
+            restore::restore_legacy_file(backup, file)?;
+            journal.record(Undo::Delete(file.clone()));
+        }
+        Action::RemoveEmptyDir(dir) => {
+            log::info!("Deleting empty directory {}", dir.rel_file());
+            remove_empty_dir(dir, journal)?;
+        }
+        Action::CreateDir(dir) => create_dir_all(dir, journal)?,
+        Action::Symlink { target, link } => {
+            log::info!("Linking {}", link.rel_file());
+            create_symlink(target, link)?;
+            journal.record(Undo::Delete(link.clone()));
+        }
+        Action::Copy(source, file) => {
+            log::info!("Copying {}", file.rel_file());
+            fs::copy(source.to_path_buf(), file.to_path_buf())
+                .map_err(InstallError::io("copy", source.to_path_buf()))?;
+            journal.record(Undo::Delete(file.clone()));
+        }
+        Action::Hardlink(source, file) => {
+            log::info!("Hard linking {}", file.rel_file());
+            fs::hard_link(source.to_path_buf(), file.to_path_buf())
+                .map_err(InstallError::io("create hard link", file.to_path_buf()))?;
+            journal.record(Undo::Delete(file.clone()));
+        }
+        Action::Render {
+            template,
+            file,
+            contents,
+        } => {
+            log::info!("Rendering {}", file.rel_file());
+            write_rendered(template, file, contents)?;
+            journal.record(Undo::Delete(file.clone()));
+        }
+        Action::Adopt(file, dotfile) => {
+            log::info!("Moving {} into the repo", file.rel_file());
+            create_dir_all(&dotfile.parent().unwrap(), journal)?;
+            move_file(&file.to_path_buf(), &dotfile.to_path_buf())
+                .map_err(InstallError::io("move", file.to_path_buf()))?;
+            journal.record(Undo::MoveBack(dotfile.clone(), file.clone()));
+        }
+        Action::GitAdd { repo_root, file } => {
+            log::info!("Staging {}", file.to_string_lossy());
+            run_command(
+                "git",
+                &[OsStr::new("add"), OsStr::new("--"), file.as_os_str()],
+                repo_root,
+            )?;
+        }
+        Action::RunScript {
+            script,
+            file_root,
+            repo_root,
+        } => {
+            log::info!("Running {}", command_name(script)?);
+            run_command(script, &[file_root.as_path()], repo_root)?;
+        }
     }
+    Ok(())
 }
 
-fn create_symlinks(home_dir: &HomePath, file_dir: &DotfilesPath, backup_dir: &BackupPath, files: &[RelPath]) {
-    for file in files {
-        log::debug!("Installing {}", file);
+/// Creates `dir` and any missing parents, journaling each directory that gets created.
+fn create_dir_all<P: FilePath>(dir: &P, journal: &mut Journal) -> Result<()> {
+    let dir = dir.to_path_buf();
+    let mut missing: Vec<_> = dir.ancestors().take_while(|d| !d.exists()).collect();
+    missing.reverse();
+
+    for d in missing {
+        log::debug!("Creating directory {}", d.to_string_lossy());
+        fs::create_dir(d).map_err(InstallError::io("create directory", d))?;
+        journal.record(Undo::DeleteDir(d.to_path_buf()));
+    }
 
-        let target = file_dir.with_rel_file(file);
-        let link_name = home_dir.with_rel_file(file);
-        let link_dir = link_name.parent().unwrap();
+    Ok(())
+}
 
-        if file_exists(&link_name) {
-            log::debug!("Deleting {}", link_name);
-            verify_backup_and_remove(file, home_dir, backup_dir)
-        } else if !file_exists(&link_dir) {
-            log::debug!("Creating directory {}", link_dir);
-            fs::create_dir_all(link_dir.to_path_buf())
-                .map_err(|e| fail!("Failed to create directory {}: {}", link_dir, e))
-                .unwrap();
+/// Removes `dir` and the empty directories in it. Fails if there's anything else in it.
+fn remove_empty_dir(dir: &HomeFile, journal: &mut Journal) -> Result<()> {
+    let path = dir.to_path_buf();
+    for entry in path.read_dir().map_err(InstallError::io("read directory", &path))? {
+        let name = entry
+            .map_err(InstallError::io("get directory entry in", &path))?
+            .file_name();
+        let child = dir
+            .prefix()
+            .with_rel_file(&RelPath::new(dir.rel_file().as_path_buf().join(name)));
+        if get_file_type(&child)?.is_dir() {
+            remove_empty_dir(&child, journal)?;
         }
+    }
+    fs::remove_dir(&path).map_err(InstallError::io("remove directory", &path))?;
+    journal.record(Undo::CreateDir(dir.clone()));
+    Ok(())
+}
 
-        log::info!("Linking {}", link_name.rel_file());
-        create_symlink(&target, &link_name);
+/// Moves `from` to `to`, copying it if they're on different file systems.
+pub(crate) fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.raw_os_error() == Some(Errno::EXDEV as i32) => {
+            log::debug!("Copying {} since it's on another file system", from.to_string_lossy());
+            fs::copy(from, to)?;
+            fs::remove_file(from)
+        }
+        result => result,
     }
 }
 
-fn create_symlink(target: &Dotfile, link_name: &HomeFile) {
-    unix::fs::symlink(target.to_path_buf(), link_name.to_path_buf())
-        .map_err(|e| fail!("Failed to create link from {} to {}: {}", link_name, target, e))
-        .unwrap();
+/// Writes a rendered template to `file`, giving it the same permissions as the template.
+fn write_rendered(template: &Dotfile, file: &HomeFile, contents: &str) -> Result<()> {
+    let path = file.to_path_buf();
+    let permissions = template
+        .to_path_buf()
+        .metadata()
+        .map_err(InstallError::io("get metadata for", template.to_path_buf()))?
+        .permissions();
+    fs::write(&path, contents).map_err(InstallError::io("write", &path))?;
+    fs::set_permissions(&path, permissions).map_err(InstallError::io("set permissions of", &path))
+}
+
+fn create_symlink(target: &Path, link_name: &HomeFile) -> Result<()> {
+    unix::fs::symlink(target, link_name.to_path_buf()).map_err(InstallError::io("create link", link_name.to_path_buf()))
 }
 
-fn command_name(command: &Path) -> &str {
+fn command_name(command: &Path) -> Result<&str> {
     command
         .file_name()
-        .unwrap_or_else(|| fail!("Tried to run a command which doesn't point to a file"))
+        .unwrap_or(command.as_os_str())
         .to_str()
-        .unwrap_or_else(|| fail!("Tried to run a command which isn't valid Unicode"))
+        .ok_or_else(|| InstallError::InvalidCommand(command.to_path_buf()))
 }
 
-fn run_command<C, A, D>(command: C, args: &[A], cwd: D)
+fn run_command<C, A, D>(command: C, args: &[A], cwd: D) -> Result<()>
 where
     C: AsRef<Path>,
     A: AsRef<OsStr>,
     D: AsRef<Path>,
 {
-    let name = command_name(command.as_ref());
+    let name = command_name(command.as_ref())?;
     let mut cmd = Command::new(command.as_ref());
     cmd.args(args).current_dir(cwd).stdin(Stdio::inherit());
     log::debug!("Spawning {:?}", &cmd);
-    let output = cmd
-        .output()
-        .map_err(|e| fail!("Failed to spawn {}: {}", name, e))
-        .unwrap();
+    let output = cmd.output().map_err(InstallError::io("spawn", command.as_ref()))?;
     log::debug!("{} exited with {}", name, output.status);
 
     if !output.stdout.is_empty() {
@@ -458,182 +804,165 @@ where
     }
 
     if !output.status.success() {
-        if let Some(signal) = output.status.signal() {
-            log::error!("{} was killed by signal {}", name, signal);
-        }
-        fail!("{} returned an error: {}", name, output.status);
-    }
-}
-
-fn checkout_submodules<P: AsRef<Path>>(repo_root: P) {
-    run_command("git", &["submodule", "update", "--init", "--recursive"], repo_root)
-}
-
-fn create_submodule_symlink(home_dir: &HomePath, backup_dir: &BackupPath, repo_submodule_dir: &SubmodulesPath) {
-    let home_submodule_dir = make_home_submodule_dir(home_dir);
-
-    if file_exists(&home_submodule_dir) {
-        let submodule_dir_type = get_file_type(&home_submodule_dir);
-
-        if submodule_dir_type.is_symlink() || submodule_dir_type.is_file() {
-            log::debug!("Deleting {} because it's a file or symlink", home_submodule_dir);
-            verify_backup_and_remove(home_submodule_dir.rel_file(), home_dir, backup_dir);
-        } else if submodule_dir_type.is_dir() {
-            log::debug!("Deleting {} because it's a file", home_submodule_dir);
-            fs::remove_dir_all(home_submodule_dir.to_path_buf())
-                .map_err(|e| fail!("Failed to remove {}: {}", home_submodule_dir, e))
-                .unwrap();
-        } else {
-            fail!(
-                "Not deleting {} because it has the unknown type {:?}",
-                home_submodule_dir,
-                submodule_dir_type
-            );
-        }
+        return Err(InstallError::CommandFailed {
+            command: name.to_string(),
+            status: output.status,
+        });
     }
 
-    log::info!("Linking submodules at {}", home_submodule_dir.rel_file());
-    unix::fs::symlink(repo_submodule_dir.as_path(), home_submodule_dir.to_path_buf())
-        .map_err(|e| fail!("Failed to symlink submodules: {}", e))
-        .unwrap();
-}
-
-fn run_install_scripts(dir: InstallScriptsPath, file_root: &DotfilesPath, repo_root: &Path) {
-    if !dir.as_path().is_dir() {
-        fail!(
-            "Install script path {} does not seem to be a directory! This may be caused by missing \
-            permissions.",
-            dir.as_path().display(),
-        )
-    }
-
-    let mut scripts = recurse_through_dir(dir.as_path(), |p| p);
-    scripts.sort();
-
-    for script in scripts {
-        let name = command_name(&script);
-        match unistd::access(&script, AccessFlags::X_OK) {
-            Ok(()) => {
-                log::info!("Running {}", name);
-                run_command(script, &[file_root.as_path()], repo_root);
-            }
-            Err(Errno::EACCES) => {
-                log::warn!("Skipping {} because it does not have execute permissions", name);
-            }
-            Err(errno) => fail!("Failed to check permissions of {}: {}", name, errno.desc()),
-        }
-    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
-    use tempfile::{Builder, TempDir};
-
-    use crate::logging;
+    use std::os::unix::fs::MetadataExt;
 
-    struct Fixture(TempDir);
+    use crate::test_util::Fixture;
 
-    impl Fixture {
-        pub fn new() -> io::Result<Self> {
-            logging::init_test();
-            let temp_dir = Builder::new()
-                .prefix("dotfile-installer-test-tmp-")
-                .rand_bytes(8)
-                .tempdir_in(".")?;
-            log::debug!("Created temp dir {} for testing", temp_dir.path().to_string_lossy());
-            Ok(Self(temp_dir))
-        }
-
-        pub fn file<T: FilePath>(&self, name: &str, contents: &str) -> io::Result<T> {
-            let path = T::new(&<T as FilePath>::Prefix::new(self.0.path()), &RelPath::new(name));
-            log::debug!("Creating temp file {} with contents '{}'", path, contents);
-            fs::write(path.to_path_buf(), contents)?;
-            Ok(path)
-        }
+    fn equal(a: &Path, b: &Path) -> Result<bool> {
+        all_files_equal(&[(a.to_path_buf(), b.to_path_buf())])
+    }
 
-        pub fn nonexistent_file<T: FilePath>(&self, name: &str) -> T {
-            T::new(&T::Prefix::new(self.0.path()), &RelPath::new(name))
-        }
+    fn symlinks(file_dir: &DotfilesPath, files: &[RelPath]) -> Vec<Link> {
+        files
+            .iter()
+            .map(|file| Link {
+                source: file_dir.with_rel_file(file),
+                target: file.clone(),
+                mode: LinkMode::Symlink,
+            })
+            .collect()
+    }
 
-        pub fn symlink<T: FilePath, P: AsRef<Path>>(&self, name: &str, target: P) -> io::Result<T> {
-            let path = T::new(&T::Prefix::new(self.0.path()), &RelPath::new(name));
-            log::debug!("Creating symlink {} -> {}", path, target.as_ref().to_string_lossy());
-            unix::fs::symlink(target, path.to_path_buf())?;
-            Ok(path)
-        }
+    fn vars() -> Variables {
+        [("user".to_string(), "me".to_string())].into_iter().collect()
     }
 
     #[test]
     fn files_equal() -> io::Result<()> {
         let fixture = Fixture::new()?;
-        let a = fixture.file("a", "foo")?;
-        let b = fixture.file("b", "foo")?;
-        assert!(super::files_equal(&a, &b));
+        let a = fixture.file::<HomeFile>("a", "foo")?;
+        let b = fixture.file::<HomeFile>("b", "foo")?;
+        assert!(equal(&a.to_path_buf(), &b.to_path_buf()).unwrap());
         Ok(())
     }
 
     #[test]
     fn files_equal_same_file() -> io::Result<()> {
         let fixture = Fixture::new()?;
-        let a = fixture.file("a", "foo")?;
-        let b = fixture.file("a", "foo")?;
-        assert!(super::files_equal(&a, &b));
+        let a = fixture.file::<HomeFile>("a", "foo")?;
+        let b = fixture.file::<HomeFile>("a", "foo")?;
+        assert!(equal(&a.to_path_buf(), &b.to_path_buf()).unwrap());
         Ok(())
     }
 
     #[test]
     fn files_not_equal() -> io::Result<()> {
         let fixture = Fixture::new()?;
-        let a = fixture.file("a", "foo")?;
-        let b = fixture.file("b", "bar")?;
-        assert!(!super::files_equal(&a, &b));
+        let a = fixture.file::<HomeFile>("a", "foo")?;
+        let b = fixture.file::<HomeFile>("b", "bar")?;
+        assert!(!equal(&a.to_path_buf(), &b.to_path_buf()).unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn files_with_different_sizes_not_equal() -> io::Result<()> {
+        let fixture = Fixture::new()?;
+        let a = fixture.file::<HomeFile>("a", "foo")?;
+        let b = fixture.file::<HomeFile>("b", "foobar")?;
+        assert!(!equal(&a.to_path_buf(), &b.to_path_buf()).unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn big_files_differing_in_the_last_chunk_not_equal() -> io::Result<()> {
+        let fixture = Fixture::new()?;
+        let contents = "x".repeat(3 * COMPARE_CHUNK_SIZE + 10);
+        let a = fixture.file::<HomeFile>("a", &contents)?;
+        let b = fixture.file::<HomeFile>("b", &contents)?;
+        assert!(equal(&a.to_path_buf(), &b.to_path_buf()).unwrap());
+        fixture.file::<HomeFile>("b", &(contents[1..].to_string() + "y"))?;
+        assert!(!equal(&a.to_path_buf(), &b.to_path_buf()).unwrap());
         Ok(())
     }
 
     #[test]
-    #[should_panic]
-    fn file_equal_nonexistent_file() {
-        let fixture = Fixture::new().unwrap();
-        let a = fixture.file("a", "foo").unwrap();
-        let b = fixture.nonexistent_file("b");
-        assert!(!super::files_equal(&a, &b));
+    fn dirs_with_one_different_file_not_equal() -> io::Result<()> {
+        let fixture = Fixture::new()?;
+        for dir in ["a", "b", "a/sub", "b/sub"] {
+            fs::create_dir(fixture.nonexistent_file::<HomeFile>(dir).to_path_buf())?;
+        }
+        for i in 0..50 {
+            fixture.file::<HomeFile>(&format!("a/sub/{}", i), &i.to_string())?;
+            fixture.file::<HomeFile>(&format!("b/sub/{}", i), &i.to_string())?;
+        }
+        let a = fixture.nonexistent_file::<HomeFile>("a").to_path_buf();
+        let b = fixture.nonexistent_file::<HomeFile>("b").to_path_buf();
+        assert!(equal(&a, &b).unwrap());
+        fixture.file::<HomeFile>("b/sub/42", "24")?;
+        assert!(!equal(&a, &b).unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn unreadable_files_are_an_error() -> io::Result<()> {
+        let fixture = Fixture::new()?;
+        let a = fixture.nonexistent_file::<HomeFile>("a").to_path_buf();
+        let b = fixture.nonexistent_file::<HomeFile>("b").to_path_buf();
+        assert!(matches!(
+            super::files_equal_by_contents(&a, &b),
+            Err(InstallError::Io { path, .. }) if path == a
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn file_equal_nonexistent_file() -> io::Result<()> {
+        let fixture = Fixture::new()?;
+        let a = fixture.file::<HomeFile>("a", "foo")?;
+        let b = fixture.nonexistent_file::<HomeFile>("b");
+        assert!(matches!(
+            equal(&a.to_path_buf(), &b.to_path_buf()),
+            Err(InstallError::Io { path, .. }) if path == b.to_path_buf()
+        ));
+        Ok(())
     }
 
     #[test]
     fn relative_symlinks_equal() -> io::Result<()> {
         let fixture = Fixture::new()?;
-        let a = fixture.symlink("a", "foo/bar")?;
-        let b = fixture.symlink("b", "foo/bar")?;
-        assert!(super::files_equal(&a, &b));
+        let a = fixture.symlink::<HomeFile, _>("a", "foo/bar")?;
+        let b = fixture.symlink::<HomeFile, _>("b", "foo/bar")?;
+        assert!(equal(&a.to_path_buf(), &b.to_path_buf()).unwrap());
         Ok(())
     }
 
     #[test]
     fn absolute_symlinks_equal() -> io::Result<()> {
         let fixture = Fixture::new()?;
-        let a = fixture.symlink("a", "/foo/bar")?;
-        let b = fixture.symlink("b", "/foo/bar")?;
-        assert!(super::files_equal(&a, &b));
+        let a = fixture.symlink::<HomeFile, _>("a", "/foo/bar")?;
+        let b = fixture.symlink::<HomeFile, _>("b", "/foo/bar")?;
+        assert!(equal(&a.to_path_buf(), &b.to_path_buf()).unwrap());
         Ok(())
     }
 
     #[test]
     fn relative_symlinks_not_equal() -> io::Result<()> {
         let fixture = Fixture::new()?;
-        let a = fixture.symlink("a", "foo/bar")?;
-        let b = fixture.symlink("b", "bar/foo")?;
-        assert!(!super::files_equal(&a, &b));
+        let a = fixture.symlink::<HomeFile, _>("a", "foo/bar")?;
+        let b = fixture.symlink::<HomeFile, _>("b", "bar/foo")?;
+        assert!(!equal(&a.to_path_buf(), &b.to_path_buf()).unwrap());
         Ok(())
     }
 
     #[test]
     fn absolute_symlinks_not_equal() -> io::Result<()> {
         let fixture = Fixture::new()?;
-        let a = fixture.symlink("a", "/foo/bar")?;
-        let b = fixture.symlink("b", "/bar/foo")?;
-        assert!(!super::files_equal(&a, &b));
+        let a = fixture.symlink::<HomeFile, _>("a", "/foo/bar")?;
+        let b = fixture.symlink::<HomeFile, _>("b", "/bar/foo")?;
+        assert!(!equal(&a.to_path_buf(), &b.to_path_buf()).unwrap());
         Ok(())
     }
 
@@ -643,10 +972,181 @@ mod test {
         let target = fixture.file::<Dotfile>("target", "foo")?;
 
         log::trace!("{}", target);
-        let a = fixture.symlink("a", target.to_path_buf())?;
-        let b = fixture.symlink("b", "./target")?;
+        let a = fixture.symlink::<HomeFile, _>("a", target.to_path_buf())?;
+        let b = fixture.symlink::<HomeFile, _>("b", "./target")?;
+
+        assert!(!equal(&a.to_path_buf(), &b.to_path_buf()).unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn plan_symlinks_creates_missing_dirs_once() -> io::Result<()> {
+        let fixture = Fixture::new()?;
+        let home_dir: HomePath = fixture.prefix();
+        let file_dir = DotfilesPath::new("/dotfiles/files");
+        let backup = BackupStore::new("/backup").new_backup();
+        let files = [RelPath::new("a/b/x"), RelPath::new("a/b/y"), RelPath::new("a/z")];
+
+        let mut plan = Plan::new();
+        plan_links(
+            &mut plan,
+            &home_dir,
+            &backup,
+            &symlinks(&file_dir, &files),
+            &[],
+            &[],
+            &vars(),
+        )
+        .unwrap();
+
+        let link = |f: &RelPath| Action::Symlink {
+            target: file_dir.with_rel_file(f).to_path_buf(),
+            link: home_dir.with_rel_file(f),
+        };
+        assert_eq!(
+            plan.actions(),
+            [
+                Action::CreateDir(home_dir.with_rel_file(&RelPath::new("a/b"))),
+                link(&files[0]),
+                link(&files[1]),
+                link(&files[2]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn plan_backs_up_and_removes_existing_files() -> io::Result<()> {
+        let fixture = Fixture::new()?;
+        let home_dir: HomePath = fixture.prefix();
+        let existing: HomeFile = fixture.file("existing", "foo")?;
+        let missing = RelPath::new("missing");
+        let file_dir = DotfilesPath::new("/dotfiles/files");
+        let backup = BackupStore::new("/backup").new_backup();
+        let files = [existing.rel_file().clone(), missing.clone()];
+        let run = Run::new("test", &Repo::new("/dotfiles"));
+
+        let mut plan = Plan::new();
+        backup::plan_backup(&mut plan, &home_dir, &backup, &run, &files, &[]).unwrap();
+        plan_links(
+            &mut plan,
+            &home_dir,
+            &backup,
+            &symlinks(&file_dir, &files),
+            &[],
+            &[],
+            &vars(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            plan.actions(),
+            [
+                Action::CreateBackup(backup.clone(), run),
+                Action::Backup(existing.clone(), backup.clone()),
+                Action::Remove(existing.clone(), backup),
+                Action::Symlink {
+                    target: file_dir.with_rel_file(existing.rel_file()).to_path_buf(),
+                    link: existing,
+                },
+                Action::Symlink {
+                    target: file_dir.with_rel_file(&missing).to_path_buf(),
+                    link: home_dir.with_rel_file(&missing),
+                },
+            ]
+        );
+        Ok(())
+    }
 
-        assert!(!super::files_equal(&a, &b));
+    #[test]
+    fn failed_install_is_rolled_back() -> io::Result<()> {
+        let fixture = Fixture::new()?;
+        let home_dir: HomePath = fixture.prefix();
+        let existing: HomeFile = fixture.file("existing", "foo")?;
+        let store = BackupStore::new(home_dir.as_path().join("backups"));
+        let backup = store.new_backup();
+        let file_dir = DotfilesPath::new("/dotfiles/files");
+        let files = [existing.rel_file().clone(), RelPath::new("a/b/new")];
+
+        let mut plan = Plan::new();
+        backup::plan_backup(
+            &mut plan,
+            &home_dir,
+            &backup,
+            &Run::new("test", &Repo::new("/dotfiles")),
+            &files,
+            &[],
+        )
+        .unwrap();
+        plan_links(
+            &mut plan,
+            &home_dir,
+            &backup,
+            &symlinks(&file_dir, &files),
+            &[],
+            &[],
+            &vars(),
+        )
+        .unwrap();
+        plan.push(Action::RunScript {
+            script: PathBuf::from("false"),
+            file_root: file_dir,
+            repo_root: home_dir.as_path().to_path_buf(),
+        });
+
+        assert!(matches!(
+            plan.execute(),
+            Err(InstallError::RolledBack(e)) if matches!(*e, InstallError::CommandFailed { .. })
+        ));
+        assert!(!is_symlink(&existing).unwrap());
+        assert_eq!(fs::read_to_string(existing.to_path_buf())?, "foo");
+        assert!(!file_exists(&home_dir.with_rel_file(&RelPath::new("a"))));
+        assert!(store.backups().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn copies_hard_links_and_renders() -> io::Result<()> {
+        let repo = Fixture::new()?;
+        let home = Fixture::new()?;
+        let home_dir: HomePath = home.prefix();
+        let copied: Dotfile = repo.file("copied", "foo")?;
+        let hard_linked: Dotfile = repo.file("hard-linked", "foo")?;
+        let template: Dotfile = repo.file("template", "user = {{ user }}")?;
+        let links: Vec<_> = [
+            (&copied, LinkMode::Copy),
+            (&hard_linked, LinkMode::Hardlink),
+            (&template, LinkMode::Template),
+        ]
+        .into_iter()
+        .map(|(source, mode)| Link {
+            source: source.clone(),
+            target: RelPath::new(format!("dir/{}", source.rel_file())),
+            mode,
+        })
+        .collect();
+
+        let mut plan = Plan::new();
+        plan_links(
+            &mut plan,
+            &home_dir,
+            &BackupStore::new("/backup").new_backup(),
+            &links,
+            &[],
+            &[],
+            &vars(),
+        )
+        .unwrap();
+        plan.execute().unwrap();
+
+        let installed = |name: &str| home.path().join("dir").join(name);
+        assert!(!installed("copied").is_symlink());
+        assert_eq!(fs::read_to_string(installed("copied"))?, "foo");
+        assert_eq!(
+            installed("hard-linked").metadata()?.ino(),
+            hard_linked.to_path_buf().metadata()?.ino()
+        );
+        assert_eq!(fs::read_to_string(installed("template"))?, "user = me");
         Ok(())
     }
 }
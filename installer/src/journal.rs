@@ -0,0 +1,137 @@
+use std::{
+    fs::{self, Permissions},
+    os::unix::{self, fs::PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use crate::backup::Backup;
+use crate::error::{InstallError, Result};
+use crate::install::{self, file_exists, get_file_type};
+use crate::path::*;
+use crate::restore;
+
+/// How to undo a completed step of an install.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Undo {
+    /// Deletes a file, symlink, or directory tree that the step created.
+    Delete(HomeFile),
+    /// Deletes a directory that the step created, as long as it's empty.
+    DeleteDir(PathBuf),
+    /// Puts back something that the step deleted from its backup.
+    Restore(Backup, HomeFile),
+    /// Puts back an empty directory that the step deleted.
+    CreateDir(HomeFile),
+    /// Puts back a file that the step deleted by writing its contents and mode. Hard links come back
+    /// as copies.
+    Write {
+        file: HomeFile,
+        contents: Vec<u8>,
+        mode: u32,
+    },
+    /// Puts back a symlink that the step deleted.
+    Relink { link: HomeFile, target: PathBuf },
+    /// Moves a file that the step moved into the repo back to the home directory.
+    MoveBack(Dotfile, HomeFile),
+    /// Deletes the backup that the step created.
+    DeleteBackup(Backup),
+}
+
+/// A record of how to undo every step that has been completed so far, in the order in which the
+/// steps were completed.
+#[derive(Debug, Default)]
+pub struct Journal(Vec<Undo>);
+
+impl Journal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, undo: Undo) {
+        log::trace!("Journaling {:?}", undo);
+        self.0.push(undo);
+    }
+
+    pub fn undos(&self) -> &[Undo] {
+        &self.0
+    }
+
+    /// Undoes all steps in reverse order. Returns `false` if any of them couldn't be undone. The
+    /// backup is kept in that case, since it may be the only copy of some files.
+    pub fn rollback(self) -> bool {
+        let mut ok = true;
+
+        for undo in self.0.into_iter().rev() {
+            if let Undo::DeleteBackup(backup) = &undo {
+                if !ok {
+                    log::warn!("Keeping backup {} since not everything could be rolled back", backup);
+                    continue;
+                }
+            }
+
+            log::debug!("Undoing with {:?}", undo);
+            if let Err(e) = undo_step(&undo) {
+                log::error!("{}", e);
+                ok = false;
+            }
+        }
+
+        ok
+    }
+}
+
+fn undo_step(undo: &Undo) -> Result<()> {
+    match undo {
+        Undo::Delete(file) => {
+            log::info!("Deleting {}", file.rel_file());
+            delete(file)
+        }
+        Undo::DeleteDir(dir) => fs::remove_dir(dir).map_err(InstallError::io("remove directory", dir)),
+        Undo::Restore(backup, file) => {
+            log::info!("Restoring {}", file.rel_file());
+            if file_exists(file) {
+                delete(file)?;
+            }
+            restore::restore_file(backup, file)
+        }
+        Undo::CreateDir(dir) => {
+            log::info!("Recreating {}", dir.rel_file());
+            fs::create_dir(dir.to_path_buf()).map_err(InstallError::io("create directory", dir.to_path_buf()))
+        }
+        Undo::Write { file, contents, mode } => {
+            log::info!("Rewriting {}", file.rel_file());
+            write(file, contents, *mode)
+        }
+        Undo::Relink { link, target } => {
+            log::info!("Relinking {}", link.rel_file());
+            relink(link, target)
+        }
+        Undo::MoveBack(dotfile, file) => {
+            log::info!("Moving {} back", file.rel_file());
+            install::move_file(&dotfile.to_path_buf(), &file.to_path_buf())
+                .map_err(InstallError::io("move back", dotfile.to_path_buf()))
+        }
+        Undo::DeleteBackup(backup) => {
+            log::info!("Deleting backup {}", backup);
+            backup.delete()
+        }
+    }
+}
+
+fn delete(file: &HomeFile) -> Result<()> {
+    if get_file_type(file)?.is_dir() {
+        fs::remove_dir_all(file.to_path_buf())
+    } else {
+        fs::remove_file(file.to_path_buf())
+    }
+    .map_err(InstallError::io("remove", file.to_path_buf()))
+}
+
+fn write(file: &HomeFile, contents: &[u8], mode: u32) -> Result<()> {
+    let path = file.to_path_buf();
+    fs::write(&path, contents).map_err(InstallError::io("write", &path))?;
+    fs::set_permissions(&path, Permissions::from_mode(mode)).map_err(InstallError::io("set permissions of", &path))
+}
+
+fn relink(link: &HomeFile, target: &Path) -> Result<()> {
+    unix::fs::symlink(target, link.to_path_buf()).map_err(InstallError::io("create link", link.to_path_buf()))
+}
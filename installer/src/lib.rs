@@ -0,0 +1,41 @@
+//! Installs dotfiles by symlinking them from a dotfile repository into the home directory.
+//!
+//! Everything that changes the home directory is first planned as a [`Plan`], which can be logged
+//! for a dry run or executed. If executing a plan fails, the steps that were already done are
+//! rolled back.
+
+pub mod adopt;
+mod archive;
+pub mod backup;
+pub mod conflict;
+pub mod error;
+pub mod fold;
+mod git;
+pub mod host;
+pub mod install;
+mod journal;
+pub mod logging;
+pub mod manifest;
+pub mod path;
+pub mod plan;
+pub mod prune;
+pub mod relink;
+pub mod repo;
+pub mod restore;
+pub mod status;
+pub mod submodule;
+pub mod sync;
+pub mod template;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod uninstall;
+
+pub use backup::{Backup, BackupStore, LegacyBackup};
+pub use error::{InstallError, Result};
+pub use host::Host;
+pub use manifest::{Link, LinkMode, Manifest};
+pub use path::{
+    Dotfile, DotfilesPath, FilePath, HomeFile, HomePath, InstallScriptsPath, Prefix, RelPath, SubmodulesPath,
+};
+pub use plan::{Action, Plan};
+pub use repo::Repo;
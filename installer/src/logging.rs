@@ -8,13 +8,14 @@ use simplelog::{
     WriteLogger,
 };
 
-use crate::fail;
+use crate::error::{InstallError, Result};
 
 const LOG_FILE_FORMAT: &str = "dotfiles-installer_%Y-%m-%d_%H:%M:%S.log";
 
 static INITIALIZED: Once = Once::new();
 
-pub fn init(stdout_level: LevelFilter) {
+pub fn init(stdout_level: LevelFilter) -> Result<()> {
+    let mut result = Ok(());
     INITIALIZED.call_once(|| {
         let config = make_config();
         let mut loggers: Vec<Box<dyn SharedLogger>> = Vec::with_capacity(2);
@@ -33,9 +34,10 @@ pub fn init(stdout_level: LevelFilter) {
             })
             .err();
 
-        CombinedLogger::init(loggers)
-            .map_err(|e| fail!("Failed to initialize logging: {}", e))
-            .unwrap();
+        if let Err(e) = CombinedLogger::init(loggers) {
+            result = Err(InstallError::Logging(e));
+            return;
+        }
 
         if let Some(e) = file_err {
             log::error!("Failed to create log file {}: {}", log_file, e);
@@ -44,6 +46,7 @@ pub fn init(stdout_level: LevelFilter) {
             log::info!("Logging to {}", log_file);
         }
     });
+    result
 }
 
 #[cfg(test)]
@@ -1,10 +1,29 @@
-mod fail;
-mod install;
-mod logging;
-mod path;
+use std::{
+    env,
+    ffi::OsStr,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process,
+};
 
-use clap::{Arg, ArgAction, Command};
+use chrono::{Local, NaiveDateTime, TimeDelta};
+use clap::{
+    builder::{PossibleValuesParser, TypedValueParser},
+    Arg, ArgAction, ArgGroup, Command,
+};
+use dotfile_installer::{
+    adopt,
+    backup::{BackupFormat, Summary},
+    install, logging,
+    prune::{self, Retention},
+    relink, restore,
+    status::{self, LinkState, LinkStatus},
+    submodule::{self, SubmoduleState, SubmoduleStatus},
+    sync, uninstall, Backup, BackupStore, DotfilesPath, FilePath, HomePath, Host, InstallError, LegacyBackup, Plan,
+    Prefix, Repo, Result,
+};
 use simplelog::LevelFilter;
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
 fn main() {
     // Force a recompile if Cargo.toml is changed since clap::command reads values from Cargo.toml.
@@ -22,8 +41,231 @@ fn main() {
                 .long("quiet")
                 .help("Decreases verbosity")
                 .action(ArgAction::Count),
+            Arg::new("profile")
+                .short('p')
+                .long("profile")
+                .value_name("PROFILE")
+                .help("Uses the host-specific dotfiles in hosts/PROFILE. Defaults to the hostname")
+                .global(true),
+            Arg::new("repo")
+                .long("repo")
+                .value_name("PATH")
+                .help("Uses the dotfile repo at PATH. Defaults to the repo that the current directory is in")
+                .value_parser(clap::value_parser!(PathBuf))
+                .global(true),
         ])
-        .subcommand(Command::new("install").about("Creates symlinks for all dotfiles in your home directory"))
+        .subcommand(
+            Command::new("install")
+                .about("Creates symlinks for all dotfiles in your home directory")
+                .args(&[
+                    Arg::new("backup-format")
+                        .long("backup-format")
+                        .value_name("FORMAT")
+                        .help("How to store the backup of the files that get replaced")
+                        .value_parser(
+                            PossibleValuesParser::new(["dir", "tar.zst", "tar.gz"])
+                                .map(|format| format.parse::<BackupFormat>().unwrap()),
+                        )
+                        .default_value("dir"),
+                    Arg::new("dry-run")
+                        .short('n')
+                        .long("dry-run")
+                        .help("Prints what would be done without changing anything")
+                        .action(ArgAction::SetTrue),
+                ]),
+        )
+        .subcommand(
+            Command::new("uninstall")
+                .about("Removes the symlinks created by install")
+                .args(&[
+                    Arg::new("restore")
+                        .short('r')
+                        .long("restore")
+                        .help("Restores the original files from the most recent backup")
+                        .action(ArgAction::SetTrue),
+                    Arg::new("dry-run")
+                        .short('n')
+                        .long("dry-run")
+                        .help("Prints what would be done without changing anything")
+                        .action(ArgAction::SetTrue),
+                ]),
+        )
+        .subcommand(
+            Command::new("relink")
+                .about("Points the symlinks created by install at the repo again after it has moved")
+                .args(&[Arg::new("dry-run")
+                    .short('n')
+                    .long("dry-run")
+                    .help("Prints what would be done without changing anything")
+                    .action(ArgAction::SetTrue)]),
+        )
+        .subcommand(
+            Command::new("restore")
+                .about("Restores the files in a backup to your home directory")
+                .args(&[
+                    Arg::new("backup")
+                        .value_name("BACKUP")
+                        .help("The backup to restore. Defaults to the newest one"),
+                    Arg::new("list")
+                        .short('l')
+                        .long("list")
+                        .help("Only lists the available backups")
+                        .action(ArgAction::SetTrue),
+                    Arg::new("dry-run")
+                        .short('n')
+                        .long("dry-run")
+                        .help("Prints what would be done without changing anything")
+                        .action(ArgAction::SetTrue),
+                ]),
+        )
+        .subcommand(
+            Command::new("backup")
+                .about("Manages the backups of the files that the installer replaced")
+                .subcommand_required(true)
+                .subcommand(Command::new("list").about("Lists the backups with their dates, sizes and file counts"))
+                .subcommand(
+                    Command::new("prune")
+                        .about("Deletes old backups")
+                        .args(&[
+                            Arg::new("keep-last")
+                                .long("keep-last")
+                                .value_name("N")
+                                .help("Keeps the N newest backups")
+                                .value_parser(clap::value_parser!(usize)),
+                            Arg::new("keep-within")
+                                .long("keep-within")
+                                .value_name("AGE")
+                                .help("Keeps the backups made within AGE, like 30d, 12h or 2w")
+                                .value_parser(prune::parse_age),
+                            Arg::new("force")
+                                .short('f')
+                                .long("force")
+                                .help("Deletes backups even if they're the only copy of a file")
+                                .action(ArgAction::SetTrue),
+                            Arg::new("dry-run")
+                                .short('n')
+                                .long("dry-run")
+                                .help("Prints what would be done without changing anything")
+                                .action(ArgAction::SetTrue),
+                        ])
+                        .group(
+                            ArgGroup::new("retention")
+                                .args(["keep-last", "keep-within"])
+                                .multiple(true)
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    Command::new("verify")
+                        .about("Checks that the files in backups are still intact")
+                        .arg(
+                            Arg::new("backup")
+                                .value_name("BACKUP")
+                                .help("The backup to check. Defaults to checking all of them"),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("status")
+                .about("Shows the state of every dotfile link. Exits with an error if any of them are broken"),
+        )
+        .subcommand(
+            Command::new("submodules")
+                .about("Manages the git submodules in the repo")
+                .subcommand_required(true)
+                .subcommand(Command::new("status").about(
+                    "Shows the state of every submodule. Exits with an error if any of them aren't at their pinned \
+                     commit or have changes",
+                ))
+                .subcommand(
+                    Command::new("update")
+                        .about(
+                            "Moves submodules to the tip of the branch they follow and stages them, showing the new \
+                             commits",
+                        )
+                        .args(&[
+                            Arg::new("names")
+                                .value_name("NAME")
+                                .help("The submodules to update. Defaults to all of them")
+                                .num_args(0..),
+                            Arg::new("check")
+                                .long("check")
+                                .help("Only reports which submodules can be updated")
+                                .action(ArgAction::SetTrue),
+                        ]),
+                ),
+        )
+        .subcommand(
+            Command::new("sync")
+                .about("Moves changes between this computer's branch and main")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("commit-on-main")
+                        .about("Commits changes on main and merges main into the current branch")
+                        .args(&[
+                            Arg::new("files")
+                                .value_name("FILES")
+                                .help("The files whose changes to commit. Defaults to all changes")
+                                .value_parser(clap::value_parser!(PathBuf))
+                                .num_args(0..),
+                            Arg::new("message")
+                                .short('m')
+                                .long("message")
+                                .value_name("MESSAGE")
+                                .help("The commit message. Without it, git asks for one"),
+                        ]),
+                )
+                .subcommand(Command::new("merge-main").about(
+                    "Pulls main and merges it into the current branch, keeping changes that haven't been committed",
+                ))
+                .subcommand(
+                    Command::new("push").about("Pushes the current branch and main").arg(
+                        Arg::new("remote")
+                            .value_name("REMOTE")
+                            .help("The remote to push to")
+                            .default_value("origin"),
+                    ),
+                ),
+        )
+        .subcommand(
+            Command::new("adopt")
+                .about("Moves a file from your home directory into the repo and links it")
+                .args(&[
+                    Arg::new("path")
+                        .value_name("PATH")
+                        .help("The file to adopt")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .required(true),
+                    Arg::new("force")
+                        .short('f')
+                        .long("force")
+                        .help("Replaces the dotfile in the repo if there already is one")
+                        .action(ArgAction::SetTrue),
+                    Arg::new("host")
+                        .long("host")
+                        .help("Adds the file to the host-specific dotfiles of the current profile")
+                        .action(ArgAction::SetTrue),
+                    Arg::new("git-add")
+                        .short('g')
+                        .long("git-add")
+                        .help("Stages the new dotfile in git")
+                        .action(ArgAction::SetTrue),
+                    Arg::new("backup-format")
+                        .long("backup-format")
+                        .value_name("FORMAT")
+                        .help("How to store the backup of the files that get replaced")
+                        .value_parser(
+                            PossibleValuesParser::new(["dir", "tar.zst", "tar.gz"])
+                                .map(|format| format.parse::<BackupFormat>().unwrap()),
+                        )
+                        .default_value("dir"),
+                    Arg::new("dry-run")
+                        .short('n')
+                        .long("dry-run")
+                        .help("Prints what would be done without changing anything")
+                        .action(ArgAction::SetTrue),
+                ]),
+        )
         .get_matches();
 
     let log_levels: Vec<_> = LevelFilter::iter().collect();
@@ -33,10 +275,598 @@ fn main() {
     let log_level_index = (default_log_level + verbose - quiet).clamp(0, log_levels.len() - 1);
     let log_level = log_levels[log_level_index];
 
-    logging::init(log_level);
+    if let Err(e) = logging::init(log_level) {
+        eprintln!("{}", e);
+        process::exit(exit_code(&e));
+    }
+
+    let globals = Globals {
+        profile: args.get_one::<String>("profile").map(String::as_str),
+        repo: args.get_one::<PathBuf>("repo").map(PathBuf::as_path),
+    };
+    let result = match args.subcommand() {
+        Some(("install", args)) => run_install(
+            &globals,
+            *args.get_one::<BackupFormat>("backup-format").unwrap(),
+            args.get_flag("dry-run"),
+        ),
+        Some(("uninstall", args)) => run_uninstall(&globals, args.get_flag("restore"), args.get_flag("dry-run")),
+        Some(("relink", args)) => run_relink(&globals, args.get_flag("dry-run")),
+        Some(("restore", args)) => run_restore(
+            &globals,
+            args.get_one::<String>("backup").map(String::as_str),
+            args.get_flag("list"),
+            args.get_flag("dry-run"),
+        ),
+        Some(("backup", args)) => match args.subcommand() {
+            Some(("list", _)) => run_backup_list(&globals),
+            Some(("prune", args)) => run_backup_prune(
+                &globals,
+                &Retention {
+                    keep_last: args.get_one::<usize>("keep-last").copied(),
+                    keep_within: args.get_one::<TimeDelta>("keep-within").copied(),
+                },
+                args.get_flag("force"),
+                args.get_flag("dry-run"),
+            ),
+            Some(("verify", args)) => run_backup_verify(&globals, args.get_one::<String>("backup").map(String::as_str)),
+            _ => unreachable!("Couldn't determine backup subcommand!"),
+        },
+        Some(("adopt", args)) => run_adopt(
+            &globals,
+            args.get_one::<PathBuf>("path").unwrap(),
+            args.get_flag("force"),
+            args.get_flag("host"),
+            args.get_flag("git-add"),
+            *args.get_one::<BackupFormat>("backup-format").unwrap(),
+            args.get_flag("dry-run"),
+        ),
+        Some(("status", _)) => match run_status(&globals) {
+            Ok(true) => Ok(()),
+            Ok(false) => process::exit(1),
+            Err(e) => Err(e),
+        },
+        Some(("submodules", args)) => match args.subcommand() {
+            Some(("status", _)) => match run_submodules_status(&globals) {
+                Ok(true) => Ok(()),
+                Ok(false) => process::exit(1),
+                Err(e) => Err(e),
+            },
+            Some(("update", args)) => run_submodules_update(
+                &globals,
+                &args
+                    .get_many::<String>("names")
+                    .map_or_else(Vec::new, |names| names.cloned().collect::<Vec<_>>()),
+                args.get_flag("check"),
+            ),
+            _ => unreachable!("Couldn't determine submodules subcommand!"),
+        },
+        Some(("sync", args)) => match args.subcommand() {
+            Some(("commit-on-main", args)) => run_sync_commit_on_main(
+                &globals,
+                &args
+                    .get_many::<PathBuf>("files")
+                    .map_or_else(Vec::new, |files| files.cloned().collect::<Vec<_>>()),
+                args.get_one::<String>("message").map(String::as_str),
+            ),
+            Some(("merge-main", _)) => run_sync_merge_main(&globals),
+            Some(("push", args)) => run_sync_push(&globals, args.get_one::<String>("remote").unwrap()),
+            _ => unreachable!("Couldn't determine sync subcommand!"),
+        },
+        _ => unreachable!("Couldn't determine subcommand!"),
+    };
+
+    if let Err(e) = result {
+        log::error!("{}", e);
+        process::exit(exit_code(&e));
+    }
+}
+
+/// The options that apply to every subcommand.
+struct Globals<'a> {
+    profile: Option<&'a str>,
+    /// The dotfile repo. Found with [`Repo::find`] if it isn't given.
+    repo: Option<&'a Path>,
+}
+
+fn find_dirs(globals: &Globals) -> Result<(HomePath, Repo, Host, BackupStore)> {
+    let home_dir = install::find_home_dir()?;
+    log::info!("Found home directory: {}", home_dir);
+
+    let repo = Repo::find(globals.repo)?;
+    log::info!("Found dotfile repo: {}", repo.root().to_string_lossy());
+    log::info!("Dotfile directory: {}", repo.files());
+
+    let host = Host::current(globals.profile)?;
+    log::info!("Using profile {}", host.profile);
+
+    let store = BackupStore::find(&home_dir);
+    log::info!("Backup store: {}", store);
+    Ok((home_dir, repo, host, store))
+}
+
+/// Executes `plan`, or just logs it if `dry_run` is set.
+fn execute(plan: &Plan, dry_run: bool) -> Result<()> {
+    if dry_run {
+        log::info!("Dry run, so not changing anything. Would:");
+        plan.log();
+        Ok(())
+    } else {
+        plan.execute()
+    }
+}
+
+fn run_install(globals: &Globals, backup_format: BackupFormat, dry_run: bool) -> Result<()> {
+    let (home_dir, repo, host, store) = find_dirs(globals)?;
+    let store = store.with_format(backup_format);
+
+    log::info!("Planning install");
+    let plan = install::plan_install(&home_dir, &repo, &host, &store)?;
+    if !plan.changes_home() {
+        // Don't check out submodules or run the install scripts either, so that nothing changes.
+        log::info!("Nothing to do, every dotfile is already installed");
+        return Ok(());
+    }
+    execute(&plan, dry_run)?;
+
+    if !dry_run {
+        log::info!("");
+        log::info!("Happy hacking!");
+    }
+    Ok(())
+}
+
+fn run_uninstall(globals: &Globals, restore: bool, dry_run: bool) -> Result<()> {
+    let (home_dir, repo, host, store) = find_dirs(globals)?;
+
+    let backup = if restore {
+        let backup = store.backups()?.pop();
+        match &backup {
+            Some(backup) => log::info!("Restoring from backup {}", backup),
+            None => log::warn!("Not restoring anything since there are no backups in {}", store),
+        }
+        backup
+    } else {
+        None
+    };
+
+    log::info!("Planning uninstall");
+    let plan = uninstall::plan_uninstall(&home_dir, &repo, &host, backup.as_ref())?;
+    execute(&plan, dry_run)
+}
+
+fn run_relink(globals: &Globals, dry_run: bool) -> Result<()> {
+    let (home_dir, repo, host, _) = find_dirs(globals)?;
+
+    log::info!("Planning relink to {}", repo.root().to_string_lossy());
+    let plan = relink::plan_relink(&home_dir, &repo, &host)?;
+    if !plan.changes_home() {
+        log::info!("Nothing to do, every link already points into the repo");
+    }
+    execute(&plan, dry_run)
+}
+
+fn run_restore(globals: &Globals, backup: Option<&str>, list: bool, dry_run: bool) -> Result<()> {
+    let (home_dir, repo, _, store) = find_dirs(globals)?;
+
+    // Backups that older versions of the installer left in the home directory are older than the
+    // ones in the store, and are named by their path.
+    let legacy_backups = LegacyBackup::find(&home_dir)?;
+    let backups = store.backups()?;
+    let names: Vec<_> = legacy_backups
+        .iter()
+        .map(LegacyBackup::to_string)
+        .chain(backups.iter().map(Backup::to_string))
+        .collect();
+    if names.is_empty() {
+        return Err(InstallError::NoBackups(store.as_path().to_path_buf()));
+    }
+
+    log::info!("Available backups:");
+    for (i, name) in names.iter().enumerate().rev() {
+        let newest = if i == names.len() - 1 { " (newest)" } else { "" };
+        log::info!("  {}{}", name, newest);
+    }
+
+    if list {
+        return Ok(());
+    }
+
+    let legacy_backup = match backup {
+        Some(name) => legacy_backups.into_iter().find(|legacy| {
+            legacy.as_path() == Path::new(name) || legacy.as_path().file_name() == Some(OsStr::new(name))
+        }),
+        None if backups.is_empty() => legacy_backups.into_iter().last(),
+        None => None,
+    };
+    let plan = match legacy_backup {
+        Some(legacy_backup) => {
+            log::info!("Restoring from old backup {}", legacy_backup);
+            restore::plan_restore_legacy_backup(&home_dir, &repo, &legacy_backup)?
+        }
+        None => {
+            let backup = store.find_backup(backup)?;
+            log::info!("Restoring from backup {}", backup);
+            restore::plan_restore_backup(&home_dir, &repo, &backup)?
+        }
+    };
+    if plan.actions().is_empty() {
+        log::info!("Nothing to restore");
+    }
+    execute(&plan, dry_run)
+}
+
+fn run_backup_list(globals: &Globals) -> Result<()> {
+    let (home_dir, _, _, store) = find_dirs(globals)?;
+
+    // Backups that older versions of the installer left in the home directory are listed by their
+    // path, since they aren't in the store.
+    let mut rows = Vec::new();
+    for backup in LegacyBackup::find(&home_dir)? {
+        rows.push((backup.to_string(), Some(backup.timestamp()), backup.summary()?));
+    }
+    for backup in store.backups()? {
+        rows.push((backup.name().to_string(), backup.timestamp(), backup.summary()?));
+    }
+    if rows.is_empty() {
+        return Err(InstallError::NoBackups(store.as_path().to_path_buf()));
+    }
+
+    print_backup_table(&rows).map_err(InstallError::io("write to", "stdout"))
+}
+
+fn print_backup_table(rows: &[(String, Option<NaiveDateTime>, Summary)]) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    let name_width = rows.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0);
+
+    for (name, timestamp, summary) in rows {
+        let date = timestamp.map_or_else(String::new, |timestamp| {
+            timestamp.format("%a %e %b %Y %H:%M").to_string()
+        });
+        writeln!(
+            stdout,
+            "{:name_width$}  {}  {:>9}  {} files",
+            name,
+            date,
+            format_size(summary.size),
+            summary.files,
+            name_width = name_width
+        )?;
+    }
+
+    Ok(())
+}
+
+fn run_backup_prune(globals: &Globals, retention: &Retention, force: bool, dry_run: bool) -> Result<()> {
+    let (home_dir, _, _, store) = find_dirs(globals)?;
+
+    log::info!("Planning pruning backups");
+    let plan = prune::plan_prune(&home_dir, &store, retention, force, Local::now().naive_local())?;
+    if plan.actions().is_empty() {
+        log::info!("Nothing to prune");
+    }
+    execute(&plan, dry_run)
+}
+
+fn run_backup_verify(globals: &Globals, backup: Option<&str>) -> Result<()> {
+    let (_, _, _, store) = find_dirs(globals)?;
+
+    let backups = match backup {
+        Some(name) => vec![store.find_backup(Some(name))?],
+        None => store.backups()?,
+    };
+    if backups.is_empty() {
+        return Err(InstallError::NoBackups(store.as_path().to_path_buf()));
+    }
+
+    let mut damaged = Vec::new();
+    for backup in backups {
+        match backup.run()? {
+            Some(run) => log::info!(
+                "Checking backup {}, made by {} as {} on {} at {}",
+                backup,
+                run.command,
+                run.user,
+                run.hostname,
+                run.started
+            ),
+            None => log::info!("Checking backup {}", backup),
+        }
+        let damage = backup.verify()?;
+        for damage in &damage {
+            log::error!("  {}", damage);
+        }
+        if damage.is_empty() {
+            log::info!("  ok, {} entries", backup.entries()?.len());
+        } else {
+            damaged.push(backup.name().to_string());
+        }
+    }
+
+    if damaged.is_empty() {
+        Ok(())
+    } else {
+        Err(InstallError::DamagedBackups(damaged))
+    }
+}
+
+fn run_adopt(
+    globals: &Globals,
+    path: &Path,
+    force: bool,
+    host_specific: bool,
+    git_add: bool,
+    backup_format: BackupFormat,
+    dry_run: bool,
+) -> Result<()> {
+    let (home_dir, repo, host, store) = find_dirs(globals)?;
+    let store = store.with_format(backup_format);
+
+    let layer = if host_specific {
+        DotfilesPath::new(repo.hosts().join(&host.profile))
+    } else {
+        repo.files().clone()
+    };
+    log::info!("Planning adopting {}", path.to_string_lossy());
+    let plan = adopt::plan_adopt(&home_dir, &repo, &store, &layer, path, force, git_add)?;
+    execute(&plan, dry_run)
+}
+
+/// Prints the state of every link and returns `true` if all of them are ok.
+fn run_status(globals: &Globals) -> Result<bool> {
+    let home_dir = install::find_home_dir()?;
+    log::debug!("Found home directory: {}", home_dir);
+    let repo = Repo::find(globals.repo)?;
+    log::debug!("Dotfile directory: {}", repo.files());
+    let host = Host::current(globals.profile)?;
+    log::debug!("Using profile {}", host.profile);
+
+    let statuses = status::status(&home_dir, &repo, &host)?;
+    print_status_table(&statuses).map_err(InstallError::io("write to", "stdout"))?;
+
+    let not_ok = statuses.iter().filter(|status| !status.state.is_ok()).count();
+    if not_ok > 0 {
+        log::warn!("{} of {} links are not ok", not_ok, statuses.len());
+    }
+    Ok(not_ok == 0)
+}
+
+/// Commits the changes to `files`, or all changes, on main and merges main back into the current branch.
+fn run_sync_commit_on_main(globals: &Globals, files: &[PathBuf], message: Option<&str>) -> Result<()> {
+    let repo = Repo::find(globals.repo)?;
+    log::debug!("Found dotfile repo: {}", repo.root().to_string_lossy());
+    // Git takes paths relative to the directory it runs in, which is the root of the repo.
+    let cwd = env::current_dir().map_err(InstallError::io("get", "the current directory"))?;
+    let files: Vec<_> = files.iter().map(|file| cwd.join(file)).collect();
+
+    sync::commit_on_main(repo.root(), &files, message)?;
+    log::info!("All done!");
+    Ok(())
+}
+
+fn run_sync_merge_main(globals: &Globals) -> Result<()> {
+    let repo = Repo::find(globals.repo)?;
+    log::debug!("Found dotfile repo: {}", repo.root().to_string_lossy());
+
+    sync::merge_main(repo.root())?;
+    log::info!("All done!");
+    Ok(())
+}
+
+fn run_sync_push(globals: &Globals, remote: &str) -> Result<()> {
+    let repo = Repo::find(globals.repo)?;
+    log::debug!("Found dotfile repo: {}", repo.root().to_string_lossy());
+
+    sync::push(repo.root(), remote)?;
+    log::info!("All done!");
+    Ok(())
+}
+
+/// Prints the state of every submodule and returns `true` if all of them are at their pinned commit
+/// without changes.
+fn run_submodules_status(globals: &Globals) -> Result<bool> {
+    let repo = Repo::find(globals.repo)?;
+    log::debug!("Found dotfile repo: {}", repo.root().to_string_lossy());
+
+    let statuses = submodule::status(repo.root())?;
+    print_submodule_table(&statuses).map_err(InstallError::io("write to", "stdout"))?;
+
+    let not_ok = statuses
+        .iter()
+        .filter(|status| status.state != SubmoduleState::Pinned || status.dirty)
+        .count();
+    if not_ok > 0 {
+        log::warn!(
+            "{} of {} submodules are not at their pinned commit",
+            not_ok,
+            statuses.len()
+        );
+    }
+    Ok(not_ok == 0)
+}
+
+/// Updates the submodules called `names`, or all of them, to the tip of the branch they follow. With
+/// `check`, only reports which of them can be updated, as GitHub Actions warnings when running in CI.
+fn run_submodules_update(globals: &Globals, names: &[String], check: bool) -> Result<()> {
+    let repo = Repo::find(globals.repo)?;
+    log::debug!("Found dotfile repo: {}", repo.root().to_string_lossy());
+    let in_ci = env::var_os("CI").map_or(false, |ci| ci == "true");
+
+    let mut updated = Vec::new();
+    let mut failed = Vec::new();
+    for submodule in submodule::select(repo.root(), names)? {
+        if !submodule.is_initialized(repo.root()) {
+            log::error!(
+                "Submodule {} isn't checked out. Run the installer first",
+                submodule.name
+            );
+            failed.push(submodule.name);
+            continue;
+        }
+        let update = match submodule.fetch_update(repo.root()) {
+            Ok(update) => update,
+            Err(e) => {
+                log::error!("Failed to fetch submodule {}: {}", submodule.name, e);
+                failed.push(submodule.name);
+                continue;
+            }
+        };
+        if update.is_up_to_date() {
+            log::info!("Submodule {} is already at {}", submodule.name, update.rev);
+            continue;
+        }
+
+        let path = submodule.path.to_string_lossy();
+        if check {
+            if in_ci {
+                println!("::warning file={}::{} submodule can be updated", path, submodule.name);
+            } else {
+                log::warn!(
+                    "Submodule {} can be updated to {} with {} new commits",
+                    submodule.name,
+                    update.rev,
+                    update.log.len()
+                );
+            }
+            continue;
+        }
+        if let Err(e) = submodule.apply_update(repo.root(), &update) {
+            log::error!("Failed to update submodule {}: {}", submodule.name, e);
+            failed.push(submodule.name);
+            continue;
+        }
+        println!(
+            "{}: {}..{}",
+            path,
+            update.old.as_deref().map_or("(none)", submodule::short),
+            submodule::short(&update.new)
+        );
+        for line in &update.log {
+            println!("    {}", line);
+        }
+        updated.push(submodule.name);
+    }
+
+    if !updated.is_empty() {
+        log::info!(
+            "Updated and staged {}. Make sure to commit the updates!",
+            updated.join(", ")
+        );
+    }
+    if !failed.is_empty() {
+        return Err(InstallError::SubmoduleUpdatesFailed(failed));
+    }
+    Ok(())
+}
+
+fn print_submodule_table(statuses: &[SubmoduleStatus]) -> io::Result<()> {
+    let mut stdout = StandardStream::stdout(color_choice());
+    let width = statuses
+        .iter()
+        .map(|status| status.state.to_string().len())
+        .max()
+        .unwrap_or(0);
+
+    for status in statuses {
+        let color = match status.state {
+            SubmoduleState::Pinned => Color::Green,
+            SubmoduleState::Moved { .. } => Color::Yellow,
+            SubmoduleState::Uninitialized | SubmoduleState::Unfetched => Color::Red,
+        };
+        stdout.set_color(ColorSpec::new().set_fg(Some(color)).set_bold(true))?;
+        write!(stdout, "{:width$}", status.state.to_string(), width = width)?;
+        stdout.reset()?;
+        write!(
+            stdout,
+            "  {}  {}",
+            status.pinned.as_deref().map_or("-", submodule::short),
+            status.submodule.path.to_string_lossy()
+        )?;
+        if status.dirty {
+            stdout.set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))?;
+            write!(stdout, " (dirty)")?;
+            stdout.reset()?;
+        }
+        writeln!(stdout)?;
+    }
+
+    Ok(())
+}
+
+fn color_choice() -> ColorChoice {
+    if io::stdout().is_terminal() {
+        ColorChoice::Auto
+    } else {
+        ColorChoice::Never
+    }
+}
+
+fn print_status_table(statuses: &[LinkStatus]) -> io::Result<()> {
+    let mut stdout = StandardStream::stdout(color_choice());
+    let width = "conflict-file".len();
+    let layer_width = statuses.iter().map(|status| status.layer.len()).max().unwrap_or(0);
+
+    for LinkStatus { file, layer, state } in statuses {
+        stdout.set_color(ColorSpec::new().set_fg(Some(state_color(state))).set_bold(true))?;
+        write!(stdout, "{:width$}", state, width = width)?;
+        stdout.reset()?;
+        write!(
+            stdout,
+            "  {:layer_width$}  {}",
+            layer,
+            file.rel_file(),
+            layer_width = layer_width
+        )?;
+        match state {
+            LinkState::ForeignLink(target) | LinkState::BrokenLink(target) => {
+                writeln!(stdout, " -> {}", target.to_string_lossy())?
+            }
+            _ => writeln!(stdout)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Formats a number of bytes with a binary unit, like `1.5 MiB`.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn state_color(state: &LinkState) -> Color {
+    match state {
+        LinkState::Ok => Color::Green,
+        LinkState::Missing | LinkState::Modified => Color::Yellow,
+        LinkState::ForeignLink(_) => Color::Magenta,
+        LinkState::ConflictFile | LinkState::ConflictDir | LinkState::BrokenLink(_) => Color::Red,
+    }
+}
 
-    match args.subcommand() {
-        Some(("install", _)) => install::install(),
-        _ => fail!("Couldn't determine subcommand!"),
+/// Picks the exit code for an error. Errors that leave the home directory half-installed get their
+/// own exit code so that scripts can tell them apart from errors that didn't change anything.
+fn exit_code(error: &InstallError) -> i32 {
+    match error {
+        InstallError::RepoNotFound
+        | InstallError::Parse { .. }
+        | InstallError::MissingDotfile(_)
+        | InstallError::CantAdopt { .. }
+        | InstallError::DotfileExists(_)
+        | InstallError::NoBackups(_)
+        | InstallError::BackupNotFound(_)
+        | InstallError::SubmoduleNotFound(_)
+        | InstallError::CantLinkSubmodule { .. }
+        | InstallError::NotSafeToSync(_) => 2,
+        InstallError::RollbackFailed(_) => 3,
+        _ => 1,
     }
 }
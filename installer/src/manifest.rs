@@ -0,0 +1,600 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    iter,
+    ops::Range,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::error::{InstallError, Result};
+use crate::host::Host;
+use crate::path::*;
+use crate::repo::Repo;
+use crate::template::{self, Variables, BUILTIN_VARIABLES, TEMPLATE_SUFFIX};
+
+pub const MANIFEST_FILE: &str = "dotfiles.toml";
+
+/// The values that `os` conditions can have. These are the possible values of
+/// [`std::env::consts::OS`] for the systems we might plausibly run on.
+const KNOWN_OSES: &[&str] = &[
+    "linux",
+    "macos",
+    "freebsd",
+    "dragonfly",
+    "netbsd",
+    "openbsd",
+    "solaris",
+    "illumos",
+    "android",
+];
+
+/// How a dotfile gets into the home directory.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkMode {
+    /// Symlinked to the file in the repo.
+    #[default]
+    Symlink,
+    /// Copied from the repo.
+    Copy,
+    /// Hard linked to the file in the repo. The repo and the home directory have to be on the same
+    /// file system.
+    Hardlink,
+    /// Rendered from a template in the repo.
+    Template,
+}
+
+/// A dotfile and where and how it gets installed.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Link {
+    /// The dotfile. Its prefix is the layer that it comes from.
+    pub source: Dotfile,
+    /// The path of the installed file in the home directory.
+    pub target: RelPath,
+    pub mode: LinkMode,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Defaults {
+    #[serde(default)]
+    mode: LinkMode,
+    /// Whether directories get folded into a single link. See [`crate::fold`].
+    #[serde(default)]
+    fold: bool,
+}
+
+/// An `[[entry]]` in the manifest. `source` can be a file or a directory, in which case the entry
+/// applies to everything in it. An entry inside another one inherits the `target`, `hosts` and `os`
+/// that it doesn't set itself from the closest enclosing entry that does.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    source: Spanned<PathBuf>,
+    target: Option<Spanned<PathBuf>>,
+    mode: Option<LinkMode>,
+    #[serde(default = "default_required")]
+    required: bool,
+    hosts: Option<Vec<String>>,
+    os: Option<Vec<Spanned<String>>>,
+    fold: Option<bool>,
+}
+
+fn default_required() -> bool {
+    true
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawManifest {
+    #[serde(default)]
+    defaults: Defaults,
+    #[serde(default, rename = "entry")]
+    entries: Vec<Spanned<Entry>>,
+    /// Template variables for every profile.
+    #[serde(default)]
+    vars: BTreeMap<String, Spanned<String>>,
+    /// Template variables for specific profiles. These override the ones in `vars`.
+    #[serde(default)]
+    profiles: BTreeMap<String, BTreeMap<String, Spanned<String>>>,
+}
+
+/// The optional `dotfiles.toml` in the root of the repo, which says where and how dotfiles get
+/// installed. Without it, every dotfile is symlinked to the same path in the home directory.
+#[derive(Debug, Default)]
+pub struct Manifest {
+    path: PathBuf,
+    text: String,
+    raw: RawManifest,
+}
+
+impl Manifest {
+    /// Loads and validates the manifest in `repo`, or returns an empty manifest if there isn't one.
+    pub fn load(repo: &Repo) -> Result<Self> {
+        let path = repo.root().join(MANIFEST_FILE);
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                log::debug!("Loading manifest {}", path.to_string_lossy());
+                Self::parse(path, text)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                log::debug!("No manifest at {}, using the defaults", path.to_string_lossy());
+                Ok(Self::default())
+            }
+            Err(e) => Err(InstallError::io("read", &path)(e)),
+        }
+    }
+
+    /// Parses and validates a manifest. `path` is only used in error messages.
+    pub fn parse<P: AsRef<Path>>(path: P, text: String) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let raw = toml::from_str(&text).map_err(|e| {
+            let offset = e.span().map_or(0, |span| span.start);
+            InstallError::parse(&path, &text, offset, e.message())
+        })?;
+        let manifest = Self { path, text, raw };
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    fn error<S: Into<String>>(&self, span: Range<usize>, message: S) -> InstallError {
+        InstallError::parse(&self.path, &self.text, span.start, message)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut sources = BTreeMap::new();
+
+        for vars in iter::once(&self.raw.vars).chain(self.raw.profiles.values()) {
+            if let Some((name, value)) = vars.iter().find(|(name, _)| BUILTIN_VARIABLES.contains(&name.as_str())) {
+                return Err(self.error(
+                    value.span(),
+                    format!("Can't redefine the built-in variable \"{}\"", name),
+                ));
+            }
+        }
+
+        for entry in &self.raw.entries {
+            let entry = entry.get_ref();
+            check_relative(entry.source.get_ref()).map_err(|message| self.error(entry.source.span(), message))?;
+            if let Some(target) = &entry.target {
+                check_relative(target.get_ref()).map_err(|message| self.error(target.span(), message))?;
+            }
+            for os in entry.os.iter().flatten() {
+                if !KNOWN_OSES.contains(&os.get_ref().as_str()) {
+                    return Err(self.error(
+                        os.span(),
+                        format!(
+                            "Unknown OS \"{}\", expected one of: {}",
+                            os.get_ref(),
+                            KNOWN_OSES.join(", ")
+                        ),
+                    ));
+                }
+            }
+            if sources.insert(entry.source.get_ref(), ()).is_some() {
+                return Err(self.error(
+                    entry.source.span(),
+                    format!("Duplicate entry for {}", entry.source.get_ref().to_string_lossy()),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gets the template variables for `host`: the built-in ones, the ones in `[vars]`, and the ones
+    /// for its profile in `[profiles.<profile>]`.
+    pub fn variables(&self, host: &Host, home_dir: &HomePath) -> Variables {
+        let mut vars = template::builtin_variables(host, home_dir);
+        let profile_vars = self.raw.profiles.get(&host.profile);
+        for (name, value) in self.raw.vars.iter().chain(profile_vars.into_iter().flatten()) {
+            vars.insert(name.clone(), value.get_ref().clone());
+        }
+        vars
+    }
+
+    /// Checks whether the directory `dir` in a layer should be folded into a single link, according
+    /// to the most specific entry that says so, or `[defaults]`.
+    pub fn folds(&self, dir: &Path) -> bool {
+        self.raw
+            .entries
+            .iter()
+            .map(Spanned::get_ref)
+            .filter(|entry| entry.fold.is_some() && dir.starts_with(entry.source.get_ref()))
+            .max_by_key(|entry| entry.source.get_ref().components().count())
+            .and_then(|entry| entry.fold)
+            .unwrap_or(self.raw.defaults.fold)
+    }
+
+    /// Works out where and how each of `files` gets installed on `host`. Files in entries whose
+    /// conditions don't match `host` are left out. Entries match files by their path in their layer.
+    pub fn links(&self, files: &[Dotfile], host: &Host) -> Result<Vec<Link>> {
+        let mut links = Vec::new();
+        // Maps each target to the entry that produced it, if any.
+        let mut targets = BTreeMap::new();
+
+        for file in files {
+            let path = file.rel_file().as_path_buf();
+            let enclosing = self.enclosing_entries(path);
+            let entry = enclosing.first().copied();
+
+            let is_template = path.to_string_lossy().ends_with(TEMPLATE_SUFFIX);
+            let default_mode = if is_template {
+                LinkMode::Template
+            } else {
+                self.raw.defaults.mode
+            };
+
+            let link = match entry {
+                None => Link {
+                    source: file.clone(),
+                    target: target_path(path, default_mode),
+                    mode: default_mode,
+                },
+                Some(_) if !matches(&enclosing, host) => {
+                    log::debug!("Skipping {} since it isn't for this host", file);
+                    continue;
+                }
+                Some(entry) => {
+                    let mode = entry.mode.unwrap_or(default_mode);
+                    let target = match enclosing.iter().find_map(|entry| Some((entry, entry.target.as_ref()?))) {
+                        Some((entry, target)) => {
+                            let rest = path.strip_prefix(entry.source.get_ref()).unwrap();
+                            if rest.as_os_str().is_empty() {
+                                RelPath::new(target.get_ref())
+                            } else {
+                                target_path(&target.get_ref().join(rest), mode)
+                            }
+                        }
+                        None => target_path(path, mode),
+                    };
+                    Link {
+                        source: file.clone(),
+                        target,
+                        mode,
+                    }
+                }
+            };
+
+            if let Some(other) = targets.insert(link.target.clone(), entry) {
+                let span = entry.or(other).map_or(0..0, |entry| entry.source.span());
+                return Err(self.error(
+                    span,
+                    format!("More than one dotfile would be installed to {}", link.target),
+                ));
+            }
+            links.push(link);
+        }
+
+        for entry in self.raw.entries.iter().map(Spanned::get_ref) {
+            let source = entry.source.get_ref();
+            if !matches(&self.enclosing_entries(source), host)
+                || files
+                    .iter()
+                    .any(|file| file.rel_file().as_path_buf().starts_with(source))
+            {
+                continue;
+            }
+            if entry.required {
+                return Err(InstallError::MissingDotfile(source.clone()));
+            }
+            log::info!("Skipping {} since it doesn't exist", source.to_string_lossy());
+        }
+
+        Ok(links)
+    }
+
+    /// Gets the entries that apply to `path` in a layer, most specific first.
+    fn enclosing_entries(&self, path: &Path) -> Vec<&Entry> {
+        let mut entries: Vec<_> = self
+            .raw
+            .entries
+            .iter()
+            .map(Spanned::get_ref)
+            .filter(|entry| path.starts_with(entry.source.get_ref()))
+            .collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.source.get_ref().components().count()));
+        entries
+    }
+}
+
+/// Checks whether the conditions of the most specific of `entries` that have them match `host`.
+fn matches(entries: &[&Entry], host: &Host) -> bool {
+    let host_matches = entries
+        .iter()
+        .find_map(|entry| entry.hosts.as_ref())
+        .map_or(true, |hosts| hosts.contains(&host.profile));
+    let os_matches = entries
+        .iter()
+        .find_map(|entry| entry.os.as_ref())
+        .map_or(true, |oses| oses.iter().any(|os| *os.get_ref() == host.os));
+    host_matches && os_matches
+}
+
+/// Gets the path that a dotfile at `path` gets installed to by default, which is the same path
+/// without the template suffix for templates.
+fn target_path(path: &Path, mode: LinkMode) -> RelPath {
+    if mode == LinkMode::Template {
+        RelPath::new(template::strip_suffix(path))
+    } else {
+        RelPath::new(path)
+    }
+}
+
+/// Checks that `path` is a relative path that stays inside the directory that it's relative to.
+pub(crate) fn check_relative(path: &Path) -> Result<(), String> {
+    if path.as_os_str().is_empty() {
+        Err("Paths can't be empty".to_string())
+    } else if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        Err(format!(
+            "{} must be a relative path without any `.` or `..` in it",
+            path.to_string_lossy()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn host() -> Host {
+        Host {
+            name: "laptop".to_string(),
+            os: "linux".to_string(),
+            user: "user".to_string(),
+            profile: "laptop".to_string(),
+        }
+    }
+
+    fn file(name: &str) -> Dotfile {
+        DotfilesPath::new("/dotfiles/files").with_rel_file(&RelPath::new(name))
+    }
+
+    fn files(names: &[&str]) -> Vec<Dotfile> {
+        names.iter().map(|name| file(name)).collect()
+    }
+
+    fn parse_error(text: &str) -> (usize, usize, String) {
+        match Manifest::parse("dotfiles.toml", text.to_string()) {
+            Err(InstallError::Parse {
+                line, column, message, ..
+            }) => (line, column, message),
+            other => panic!("Expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn no_manifest_links_everything() {
+        let files = files(&[".zshrc", ".config/foo"]);
+        let links = Manifest::default().links(&files, &host()).unwrap();
+        assert_eq!(
+            links,
+            files
+                .iter()
+                .map(|f| Link {
+                    source: f.clone(),
+                    target: f.rel_file().clone(),
+                    mode: LinkMode::Symlink,
+                })
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn entries_override_defaults() {
+        let manifest = Manifest::parse(
+            "dotfiles.toml",
+            r#"
+                [defaults]
+                mode = "copy"
+
+                [[entry]]
+                source = "nvim"
+                target = ".config/nvim"
+                mode = "symlink"
+
+                [[entry]]
+                source = "nvim/local.lua"
+                mode = "hardlink"
+
+                [[entry]]
+                source = "tmux"
+                target = ".config/tmux"
+                hosts = ["desktop"]
+
+                [[entry]]
+                source = "tmux/tmux.conf"
+                mode = "hardlink"
+
+                [[entry]]
+                source = "git"
+                target = ".config/git"
+                hosts = ["desktop"]
+
+                [[entry]]
+                source = "git/config"
+                hosts = ["laptop"]
+
+                [[entry]]
+                source = ".work"
+                hosts = ["desktop"]
+
+                [[entry]]
+                source = ".linux-only"
+                os = ["linux"]
+
+                [[entry]]
+                source = ".missing"
+                required = false
+            "#
+            .to_string(),
+        )
+        .unwrap();
+        let files = files(&[
+            ".zshrc",
+            "nvim/init.lua",
+            "nvim/local.lua",
+            "tmux/tmux.conf",
+            "git/config",
+            ".work",
+            ".linux-only",
+        ]);
+
+        let links = manifest.links(&files, &host()).unwrap();
+
+        let link = |source: &str, target: &str, mode| Link {
+            source: file(source),
+            target: RelPath::new(target),
+            mode,
+        };
+        assert_eq!(
+            links,
+            [
+                link(".zshrc", ".zshrc", LinkMode::Copy),
+                link("nvim/init.lua", ".config/nvim/init.lua", LinkMode::Symlink),
+                link("nvim/local.lua", ".config/nvim/local.lua", LinkMode::Hardlink),
+                link("git/config", ".config/git/config", LinkMode::Copy),
+                link(".linux-only", ".linux-only", LinkMode::Copy),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_have_locations() {
+        assert_eq!(
+            parse_error("[[entry]]\nsource = \".a\"\nmode = \"bogus\"\n").0,
+            3,
+            "unknown mode"
+        );
+        assert_eq!(parse_error("[[entry]]\nsourse = \".a\"\n").0, 2, "unknown field");
+        assert_eq!(
+            parse_error("[[entry]]\nsource = \".a\"\ntarget = \"../a\"\n"),
+            (
+                3,
+                10,
+                "../a must be a relative path without any `.` or `..` in it".to_string()
+            )
+        );
+        assert_eq!(
+            parse_error("[[entry]]\nsource = \".a\"\nos = [\"linux\", \"temple\"]\n").0,
+            3
+        );
+        assert_eq!(
+            parse_error("[[entry]]\nsource = \".a\"\n[[entry]]\nsource = \".a\"\n").0,
+            4,
+            "duplicate entry"
+        );
+    }
+
+    #[test]
+    fn missing_required_dotfile() {
+        let manifest = Manifest::parse("dotfiles.toml", "[[entry]]\nsource = \".a\"\n".to_string()).unwrap();
+        assert!(matches!(
+            manifest.links(&[], &host()),
+            Err(InstallError::MissingDotfile(path)) if path == Path::new(".a")
+        ));
+    }
+
+    #[test]
+    fn conflicting_targets() {
+        let manifest = Manifest::parse(
+            "dotfiles.toml",
+            "[[entry]]\nsource = \".a\"\ntarget = \".b\"\n".to_string(),
+        )
+        .unwrap();
+        assert!(matches!(
+            manifest.links(&files(&[".a", ".b"]), &host()),
+            Err(InstallError::Parse { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn templates_lose_their_suffix() {
+        let manifest = Manifest::parse(
+            "dotfiles.toml",
+            "[[entry]]\nsource = \"git\"\ntarget = \".config/git\"\n".to_string(),
+        )
+        .unwrap();
+        let links = manifest
+            .links(&files(&[".zshrc.local.tmpl", "git/config.tmpl", ".tmpl"]), &host())
+            .unwrap();
+        let targets: Vec<_> = links.iter().map(|link| (link.target.to_string(), link.mode)).collect();
+        assert_eq!(
+            targets,
+            [
+                (".zshrc.local".to_string(), LinkMode::Template),
+                (".config/git/config".to_string(), LinkMode::Template),
+                (".tmpl".to_string(), LinkMode::Template),
+            ]
+        );
+    }
+
+    #[test]
+    fn profile_variables() {
+        let manifest = Manifest::parse(
+            "dotfiles.toml",
+            r#"
+                [vars]
+                email = "me@example.com"
+                editor = "nvim"
+
+                [profiles.laptop]
+                email = "me@laptop.example.com"
+
+                [profiles.desktop]
+                email = "me@desktop.example.com"
+            "#
+            .to_string(),
+        )
+        .unwrap();
+
+        let vars = manifest.variables(&host(), &HomePath::new("/home/user"));
+
+        assert_eq!(vars["email"], "me@laptop.example.com");
+        assert_eq!(vars["editor"], "nvim");
+        assert_eq!(vars["profile"], "laptop");
+        assert_eq!(vars["home"], "/home/user");
+    }
+
+    #[test]
+    fn most_specific_fold_setting_wins() {
+        let manifest = Manifest::parse(
+            "dotfiles.toml",
+            r#"
+                [defaults]
+                fold = true
+
+                [[entry]]
+                source = ".config"
+                fold = false
+
+                [[entry]]
+                source = ".config/nvim"
+                fold = true
+            "#
+            .to_string(),
+        )
+        .unwrap();
+
+        assert!(manifest.folds(Path::new(".vim")));
+        assert!(!manifest.folds(Path::new(".config/git")));
+        assert!(manifest.folds(Path::new(".config/nvim/lua")));
+        assert!(!Manifest::default().folds(Path::new(".vim")));
+    }
+
+    #[test]
+    fn builtin_variables_cant_be_redefined() {
+        assert_eq!(
+            parse_error("[vars]\nuser = \"root\"\n").0,
+            2,
+            "redefined built-in variable"
+        );
+        assert_eq!(parse_error("[vars]\nuser = 1\n").0, 2, "non-string variable");
+    }
+}
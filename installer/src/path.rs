@@ -50,10 +50,6 @@ pub trait Prefix: Display + Sized {
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct HomeFile(HomePath, RelPath);
 
-/// An absolute path to a file in the backup directory.
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub struct BackupFile(BackupPath, RelPath);
-
 /// An absolute path to a dotfile in the dotfiles repository.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Dotfile(DotfilesPath, RelPath);
@@ -62,10 +58,6 @@ pub struct Dotfile(DotfilesPath, RelPath);
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct HomePath(PathBuf);
 
-/// The path to the backup directory.
-#[derive(Debug, PartialEq, Eq, Clone)]
-pub struct BackupPath(PathBuf);
-
 /// The path to the dotfiles in the dotfiles repository.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct DotfilesPath(PathBuf);
@@ -75,7 +67,7 @@ pub struct DotfilesPath(PathBuf);
 pub struct SubmodulesPath(PathBuf);
 
 /// A relative path to a file.
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub struct RelPath(PathBuf);
 
 /// The path to the install scripts in the dotfiles repository.
@@ -127,7 +119,6 @@ macro_rules! impl_file_path {
 }
 
 impl_file_path!(HomeFile, HomePath);
-impl_file_path!(BackupFile, BackupPath);
 impl_file_path!(Dotfile, DotfilesPath);
 
 macro_rules! impl_prefix {
@@ -151,7 +142,6 @@ macro_rules! impl_prefix {
 }
 
 impl_prefix!(HomePath, HomeFile);
-impl_prefix!(BackupPath, BackupFile);
 impl_prefix!(DotfilesPath, Dotfile);
 
 macro_rules! impl_display {
@@ -166,10 +156,8 @@ macro_rules! impl_display {
 
 impl_display!(RelPath);
 impl_display!(HomeFile);
-impl_display!(BackupFile);
 impl_display!(Dotfile);
 impl_display!(HomePath);
-impl_display!(BackupPath);
 impl_display!(DotfilesPath);
 impl_display!(SubmodulesPath);
 
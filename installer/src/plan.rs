@@ -0,0 +1,159 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    path::PathBuf,
+};
+
+use crate::backup::{Backup, LegacyBackup, Run};
+use crate::error::{InstallError, Result};
+use crate::install;
+use crate::journal::Journal;
+use crate::path::*;
+
+/// A single step of an install. Computing these up front lets us show exactly what an install will
+/// do before doing it.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Action {
+    /// Clones and checks out the git submodules in the repo that are missing.
+    CheckoutSubmodules(PathBuf),
+    /// Points the submodules in the repo at their clones with relative paths after the repo has
+    /// moved.
+    FixSubmodules(PathBuf),
+    /// Creates a backup in the backup store, recording which run of the installer made it.
+    CreateBackup(Backup, Run),
+    /// Deletes a backup from the backup store. This can't be undone.
+    DeleteBackup(Backup),
+    /// Deletes a backup directory that an older version of the installer made in the home
+    /// directory. This can't be undone.
+    DeleteLegacyBackup(LegacyBackup),
+    /// Adds a file, symlink, or directory in the home directory to a backup.
+    Backup(HomeFile, Backup),
+    /// Removes a file from the home directory after checking that it matches its backup.
+    Remove(HomeFile, Backup),
+    /// Removes a symlink created by the installer from the home directory.
+    Unlink(HomeFile),
+    /// Removes a copy, hard link, or rendered template created by the installer from the home
+    /// directory.
+    RemoveCopy(HomeFile),
+    /// Copies a file, symlink, or directory from a backup back into the home directory.
+    Restore(Backup, HomeFile),
+    /// Copies a file, symlink, or directory from a backup directory that an older version of the
+    /// installer made back into the home directory.
+    RestoreLegacy(LegacyBackup, HomeFile),
+    /// Removes a directory that only has empty directories left in it, like one that the installer
+    /// made for dotfiles that have been removed.
+    RemoveEmptyDir(HomeFile),
+    /// Creates a directory and all of its parents in the home directory.
+    CreateDir(HomeFile),
+    /// Creates a symlink in the home directory.
+    Symlink { target: PathBuf, link: HomeFile },
+    /// Copies a dotfile into the home directory.
+    Copy(Dotfile, HomeFile),
+    /// Hard links a dotfile into the home directory.
+    Hardlink(Dotfile, HomeFile),
+    /// Writes a rendered template to the home directory.
+    Render {
+        template: Dotfile,
+        file: HomeFile,
+        contents: String,
+    },
+    /// Moves a file from the home directory into the repo, replacing any dotfile that's already
+    /// there.
+    Adopt(HomeFile, Dotfile),
+    /// Stages a file in the repo with `git add`.
+    GitAdd { repo_root: PathBuf, file: PathBuf },
+    /// Runs an install script in the repo root, passing it the dotfile directory.
+    RunScript {
+        script: PathBuf,
+        file_root: DotfilesPath,
+        repo_root: PathBuf,
+    },
+}
+
+/// An ordered list of actions.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Plan(Vec<Action>);
+
+impl Plan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, action: Action) {
+        self.0.push(action);
+    }
+
+    pub fn actions(&self) -> &[Action] {
+        &self.0
+    }
+
+    /// Checks whether executing the plan would change anything in the home directory. Checking out
+    /// or fixing submodules and running install scripts doesn't count.
+    pub fn changes_home(&self) -> bool {
+        self.0.iter().any(|action| {
+            !matches!(
+                action,
+                Action::CheckoutSubmodules(_) | Action::FixSubmodules(_) | Action::RunScript { .. }
+            )
+        })
+    }
+
+    /// Executes every action in the plan. If an action fails, the actions before it are undone so
+    /// that the home directory is left as it was.
+    pub fn execute(&self) -> Result<()> {
+        log::debug!("Executing {} actions", self.0.len());
+        let mut journal = Journal::new();
+
+        for action in &self.0 {
+            if let Err(e) = install::execute(action, &mut journal) {
+                log::error!("{}", e);
+                log::warn!("Rolling back {} completed steps", journal.undos().len());
+                return Err(if journal.rollback() {
+                    InstallError::RolledBack(Box::new(e))
+                } else {
+                    InstallError::RollbackFailed(Box::new(e))
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Logs every action in the plan.
+    pub fn log(&self) {
+        let width = self.0.len().to_string().len();
+        for (i, action) in self.0.iter().enumerate() {
+            log::info!("{:>width$}. {}", i + 1, action, width = width);
+        }
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::CheckoutSubmodules(repo_root) => {
+                write!(f, "Check out missing submodules in {}", repo_root.to_string_lossy())
+            }
+            Action::FixSubmodules(repo_root) => {
+                write!(f, "Fix the paths of the submodules in {}", repo_root.to_string_lossy())
+            }
+            Action::CreateBackup(backup, _) => write!(f, "Create backup {} in {}", backup, backup.store()),
+            Action::DeleteBackup(backup) => write!(f, "Delete backup {} from {}", backup, backup.store()),
+            Action::DeleteLegacyBackup(backup) => write!(f, "Delete old backup {}", backup),
+            Action::Backup(file, backup) => write!(f, "Back up {} to backup {}", file, backup),
+            Action::Remove(file, _) => write!(f, "Delete {}", file),
+            Action::Unlink(link) => write!(f, "Unlink {}", link),
+            Action::RemoveCopy(file) => write!(f, "Delete {}", file),
+            Action::Restore(backup, file) => write!(f, "Restore {} from backup {}", file, backup),
+            Action::RestoreLegacy(backup, file) => write!(f, "Restore {} from old backup {}", file, backup),
+            Action::RemoveEmptyDir(dir) => write!(f, "Delete empty directory {}", dir),
+            Action::CreateDir(dir) => write!(f, "Create directory {}", dir),
+            Action::Symlink { target, link } => write!(f, "Link {} -> {}", link, target.to_string_lossy()),
+            Action::Copy(source, file) => write!(f, "Copy {} to {}", source, file),
+            Action::Hardlink(source, file) => write!(f, "Hard link {} to {}", file, source),
+            Action::Render { template, file, .. } => write!(f, "Render {} to {}", template, file),
+            Action::Adopt(file, dotfile) => write!(f, "Move {} to {}", file, dotfile),
+            Action::GitAdd { file, .. } => write!(f, "Stage {} in git", file.to_string_lossy()),
+            Action::RunScript { script, .. } => write!(f, "Run {}", script.to_string_lossy()),
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    collections::BTreeSet,
+    fmt::{self, Display, Formatter},
+};
+
+use chrono::{NaiveDateTime, TimeDelta};
+
+use crate::backup::{Backup, BackupStore, Entry, EntryKind, LegacyBackup};
+use crate::error::Result;
+use crate::path::HomePath;
+use crate::plan::{Action, Plan};
+
+/// Which backups to keep when pruning. A backup is kept if any of the rules keeps it.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct Retention {
+    /// Keep this many of the newest backups.
+    pub keep_last: Option<usize>,
+    /// Keep the backups made less than this long ago.
+    pub keep_within: Option<TimeDelta>,
+}
+
+/// A backup that can be pruned, either from the store or from the home directory.
+enum Prunable {
+    Store(Backup),
+    Legacy(LegacyBackup),
+}
+
+impl Prunable {
+    fn timestamp(&self) -> Option<NaiveDateTime> {
+        match self {
+            Prunable::Store(backup) => backup.timestamp(),
+            Prunable::Legacy(backup) => Some(backup.timestamp()),
+        }
+    }
+
+    fn entries(&self) -> Result<Vec<Entry>> {
+        match self {
+            Prunable::Store(backup) => backup.entries(),
+            Prunable::Legacy(backup) => backup.entries(),
+        }
+    }
+
+    fn delete(self) -> Action {
+        match self {
+            Prunable::Store(backup) => Action::DeleteBackup(backup),
+            Prunable::Legacy(backup) => Action::DeleteLegacyBackup(backup),
+        }
+    }
+}
+
+impl Display for Prunable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Prunable::Store(backup) => write!(f, "{}", backup),
+            Prunable::Legacy(backup) => write!(f, "{}", backup),
+        }
+    }
+}
+
+/// Plans deleting the backups in `store` and the legacy backups in `home_dir` that `retention`
+/// doesn't keep, as of `now`. Both kinds count towards the same retention rules. Backups that are
+/// the only copy of a file's contents are kept anyway unless `force` is set, since deleting them
+/// would lose the file for good.
+pub fn plan_prune(
+    home_dir: &HomePath,
+    store: &BackupStore,
+    retention: &Retention,
+    force: bool,
+    now: NaiveDateTime,
+) -> Result<Plan> {
+    let mut backups: Vec<_> = LegacyBackup::find(home_dir)?
+        .into_iter()
+        .map(Prunable::Legacy)
+        .chain(store.backups()?.into_iter().map(Prunable::Store))
+        .collect();
+    backups.sort_by_key(Prunable::timestamp);
+    let keep_last_from = backups.len().saturating_sub(retention.keep_last.unwrap_or(0));
+
+    let mut kept = Vec::new();
+    let mut candidates = Vec::new();
+    for (i, backup) in backups.into_iter().enumerate() {
+        let recent = match (retention.keep_within, backup.timestamp()) {
+            (Some(within), Some(timestamp)) => now - timestamp < within,
+            _ => false,
+        };
+        if i >= keep_last_from || recent {
+            log::debug!("Keeping backup {}", backup);
+            kept.push(backup);
+        } else {
+            candidates.push(backup);
+        }
+    }
+
+    let mut kept_contents = BTreeSet::new();
+    for backup in &kept {
+        kept_contents.extend(backup.entries()?.iter().filter_map(contents));
+    }
+
+    // Go from oldest to newest, so that the oldest copy of something is the one that's kept.
+    let mut plan = Plan::new();
+    for backup in candidates {
+        let entries = backup.entries()?;
+        let only_copy = entries
+            .iter()
+            .find(|entry| contents(entry).map_or(false, |contents| !kept_contents.contains(&contents)));
+        match only_copy {
+            Some(entry) if !force => {
+                log::warn!(
+                    "Keeping backup {} since it's the only copy of {}. Use --force to delete it anyway",
+                    backup,
+                    entry.path.to_string_lossy()
+                );
+                kept_contents.extend(entries.iter().filter_map(contents));
+            }
+            _ => plan.push(backup.delete()),
+        }
+    }
+    Ok(plan)
+}
+
+/// Parses an age like `30d`. The units are `s`, `m`, `h`, `d` and `w`.
+pub fn parse_age(age: &str) -> Result<TimeDelta, String> {
+    let invalid = || format!("invalid age {:?}, expected something like 30d or 12h", age);
+    let split = age.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let (number, unit) = age.split_at(split);
+    let number: i64 = number.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "s" => Some(number),
+        "m" => number.checked_mul(60),
+        "h" => number.checked_mul(60 * 60),
+        "d" => number.checked_mul(24 * 60 * 60),
+        "w" => number.checked_mul(7 * 24 * 60 * 60),
+        _ => None,
+    };
+    seconds.and_then(TimeDelta::try_seconds).ok_or_else(invalid)
+}
+
+/// Gets what an entry holds that would be lost if it was deleted. Directories don't hold anything
+/// by themselves.
+fn contents(entry: &Entry) -> Option<String> {
+    match &entry.kind {
+        EntryKind::File { sha256, .. } => Some(sha256.clone()),
+        EntryKind::Symlink { target } => Some(format!("symlink to {}", target.to_string_lossy())),
+        EntryKind::Dir { .. } => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io};
+
+    use super::*;
+    use crate::backup::Backup;
+    use crate::path::*;
+    use crate::test_util::{self, Fixture};
+
+    /// Makes a backup of a file called `name` with `contents` in it.
+    fn make_backup(store: &BackupStore, home: &Fixture, name: &str, contents: &str) -> Backup {
+        fs::write(home.path().join(name), contents).unwrap();
+        let backup = store.new_backup();
+        backup.create(&test_util::run()).unwrap();
+        backup.add(&home.home_dir().with_rel_file(&RelPath::new(name))).unwrap();
+        backup
+    }
+
+    fn deleted(plan: &Plan) -> Vec<String> {
+        plan.actions()
+            .iter()
+            .map(|action| match action {
+                Action::DeleteBackup(backup) => backup.name().to_string(),
+                Action::DeleteLegacyBackup(backup) => {
+                    backup.as_path().file_name().unwrap().to_string_lossy().into_owned()
+                }
+                action => panic!("Unexpected action {}", action),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_only_copies_unless_forced() -> io::Result<()> {
+        let home = Fixture::new()?;
+        let store_dir = Fixture::new()?;
+        let store = store_dir.store();
+        let unique = make_backup(&store, &home, "a", "only here");
+        let duplicate = make_backup(&store, &home, "b", "twice");
+        let newest = make_backup(&store, &home, "c", "twice");
+        let retention = Retention {
+            keep_last: Some(1),
+            keep_within: None,
+        };
+        let now = newest.timestamp().unwrap();
+
+        let plan = plan_prune(&home.home_dir(), &store, &retention, false, now).unwrap();
+        assert_eq!(deleted(&plan), [duplicate.name()]);
+
+        let plan = plan_prune(&home.home_dir(), &store, &retention, true, now).unwrap();
+        assert_eq!(deleted(&plan), [unique.name(), duplicate.name()]);
+
+        plan.execute().unwrap();
+        assert_eq!(store.backups().unwrap(), [newest]);
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_recent_backups() -> io::Result<()> {
+        let home = Fixture::new()?;
+        let store_dir = Fixture::new()?;
+        let store = store_dir.store();
+        let backup = make_backup(&store, &home, "a", "foo");
+        let made = backup.timestamp().unwrap();
+        let retention = Retention {
+            keep_last: None,
+            keep_within: Some(parse_age("1d").unwrap()),
+        };
+
+        assert_eq!(
+            deleted(&plan_prune(&home.home_dir(), &store, &retention, true, made).unwrap()),
+            [] as [&str; 0]
+        );
+        let later = made + parse_age("2d").unwrap();
+        assert_eq!(
+            deleted(&plan_prune(&home.home_dir(), &store, &retention, true, later).unwrap()),
+            [backup.name()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn prunes_legacy_backups_in_the_home_directory() -> io::Result<()> {
+        let home = Fixture::new()?;
+        let store_dir = Fixture::new()?;
+        let store = store_dir.store();
+        for (name, contents) in [
+            ("dotfiles-backup_2020-01-01_12:00:00", "old"),
+            ("dotfiles-backup_2021-01-01_12:00:00", "twice"),
+        ] {
+            fs::create_dir_all(home.path().join(name).join(".config"))?;
+            fs::write(home.path().join(name).join(".config/foo"), contents)?;
+            fs::write(home.path().join(name).join("README.md"), "This is a backup directory")?;
+        }
+        fs::create_dir(home.path().join("dotfiles-backup_not-a-date"))?;
+        let newest = make_backup(&store, &home, "foo", "twice");
+        let retention = Retention {
+            keep_last: Some(1),
+            keep_within: None,
+        };
+        let now = newest.timestamp().unwrap();
+
+        let plan = plan_prune(&home.home_dir(), &store, &retention, false, now).unwrap();
+        assert_eq!(deleted(&plan), ["dotfiles-backup_2021-01-01_12:00:00"]);
+
+        let plan = plan_prune(&home.home_dir(), &store, &retention, true, now).unwrap();
+        assert_eq!(
+            deleted(&plan),
+            [
+                "dotfiles-backup_2020-01-01_12:00:00",
+                "dotfiles-backup_2021-01-01_12:00:00"
+            ]
+        );
+        plan.execute().unwrap();
+        assert!(LegacyBackup::find(&home.home_dir()).unwrap().is_empty());
+        assert!(home.path().join("dotfiles-backup_not-a-date").is_dir());
+        Ok(())
+    }
+
+    #[test]
+    fn parses_ages() {
+        assert_eq!(parse_age("30d"), Ok(TimeDelta::days(30)));
+        assert_eq!(parse_age("12h"), Ok(TimeDelta::hours(12)));
+        assert_eq!(parse_age("2w"), Ok(TimeDelta::weeks(2)));
+        assert!(parse_age("30").is_err());
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("1y").is_err());
+    }
+}
@@ -0,0 +1,49 @@
+use crate::error::{InstallError, Result};
+use crate::fold;
+use crate::host::Host;
+use crate::install::{self, is_symlink};
+use crate::manifest::{LinkMode, Manifest};
+use crate::path::*;
+use crate::plan::{Action, Plan};
+use crate::repo::Repo;
+
+/// Plans pointing the links in `home_dir` at `repo` again after the repo has moved, and fixing the
+/// paths of its submodules. A symlink is relinked if it's dangling and points to where its dotfile
+/// would be in the repo's old location. Links into other clones or worktrees of the repo still work,
+/// so they're left alone.
+pub fn plan_relink(home_dir: &HomePath, repo: &Repo, host: &Host) -> Result<Plan> {
+    let manifest = Manifest::load(repo)?;
+    let links = fold::fold_links(home_dir, repo, &manifest, install::find_links(repo, &manifest, host)?)?;
+
+    let mut plan = Plan::new();
+    plan.push(Action::FixSubmodules(repo.root().to_path_buf()));
+    for link in links.iter().filter(|link| link.mode == LinkMode::Symlink) {
+        let file = home_dir.with_rel_file(&link.target);
+        if !is_symlink(&file)? {
+            continue;
+        }
+        let current = file
+            .to_path_buf()
+            .read_link()
+            .map_err(InstallError::io("read symlink", file.to_path_buf()))?;
+        let source = link.source.to_path_buf();
+        let Ok(rel_source) = source.strip_prefix(repo.root()) else {
+            continue;
+        };
+        if current == source || !current.ends_with(rel_source) {
+            continue;
+        }
+        if current.exists() {
+            log::debug!("{} points into another checkout at {}", file, current.to_string_lossy());
+            continue;
+        }
+
+        log::debug!("{} points to {} in the old repo", file, current.to_string_lossy());
+        plan.push(Action::Unlink(file.clone()));
+        plan.push(Action::Symlink {
+            target: source,
+            link: file,
+        });
+    }
+    Ok(plan)
+}
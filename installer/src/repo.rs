@@ -0,0 +1,125 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+use crate::error::{InstallError, Result};
+use crate::git::git;
+use crate::path::*;
+
+const FILES_DIR: &str = "files";
+const HOSTS_DIR: &str = "hosts";
+const SCRIPTS_DIR: &str = "installer/scripts";
+const SUBMODULES_DIR: &str = "submodules";
+
+/// The paths to the interesting parts of the dotfile repository.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Repo {
+    root: PathBuf,
+    files: DotfilesPath,
+    hosts: PathBuf,
+    submodules: SubmodulesPath,
+    scripts: InstallScriptsPath,
+}
+
+impl Repo {
+    /// Makes a `Repo` for the repository at `root`.
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        let root = root.as_ref().to_path_buf();
+        Self {
+            files: DotfilesPath::new(root.join(FILES_DIR)),
+            hosts: root.join(HOSTS_DIR),
+            submodules: SubmodulesPath::new(root.join(SUBMODULES_DIR)),
+            scripts: InstallScriptsPath::new(root.join(SCRIPTS_DIR)),
+            root,
+        }
+    }
+
+    /// Finds the repository. If `root` is given, that's the repository, or a directory inside it.
+    /// Otherwise it's the repository that the current directory is in, or as a last resort, the one
+    /// that contains the running binary.
+    pub fn find(root: Option<&Path>) -> Result<Self> {
+        log::debug!("Finding repo root");
+        if let Some(root) = root {
+            return Self::toplevel(root).ok_or(InstallError::RepoNotFound);
+        }
+        if let Some(repo) = env::current_dir().ok().and_then(|dir| Self::toplevel(&dir)) {
+            return Ok(repo);
+        }
+
+        let binary_path = env::current_exe().expect("Failed to get the path to the binary!");
+        // env::current_exe *seems* to always return an absolute path, but let's make sure.
+        assert!(binary_path.is_absolute());
+
+        for dir in binary_path.parent().unwrap().ancestors() {
+            log::debug!("Checking {}", dir.to_string_lossy());
+            // .git is a file in worktrees and submodules.
+            if dir.join(".git").exists() && Self::is_dotfile_repo(dir) {
+                return Ok(Self::new(dir));
+            }
+        }
+
+        Err(InstallError::RepoNotFound)
+    }
+
+    /// Finds the root of the worktree that `dir` is in, if it's a dotfile repository.
+    fn toplevel(dir: &Path) -> Option<Self> {
+        log::debug!("Checking {}", dir.to_string_lossy());
+        let root = PathBuf::from(git(dir, &["rev-parse", "--show-toplevel"]).ok()?);
+        Self::is_dotfile_repo(&root).then(|| Self::new(root))
+    }
+
+    /// Checks that `dir` looks like the dotfile repository and not some other repository.
+    fn is_dotfile_repo(dir: &Path) -> bool {
+        dir.join(FILES_DIR).is_dir()
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn files(&self) -> &DotfilesPath {
+        &self.files
+    }
+
+    /// The directory that contains a directory of host-specific dotfiles for each profile.
+    pub fn hosts(&self) -> &Path {
+        &self.hosts
+    }
+
+    /// The directories of dotfiles that apply to `profile`, from the least to the most specific. Files
+    /// in later layers override the ones in earlier layers.
+    pub fn layers(&self, profile: &str) -> Vec<DotfilesPath> {
+        let mut layers = vec![self.files.clone()];
+        let host_dir = self.hosts.join(profile);
+        if host_dir.is_dir() {
+            layers.push(DotfilesPath::new(host_dir));
+        } else {
+            log::debug!("No host-specific dotfiles in {}", host_dir.to_string_lossy());
+        }
+        layers
+    }
+
+    /// Gets a short name for `layer`, like `files` or `hosts/laptop`.
+    pub fn layer_name(&self, layer: &DotfilesPath) -> String {
+        layer
+            .as_path()
+            .strip_prefix(&self.root)
+            .unwrap_or(layer.as_path())
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    pub fn submodules(&self) -> &SubmodulesPath {
+        &self.submodules
+    }
+
+    pub fn scripts(&self) -> &InstallScriptsPath {
+        &self.scripts
+    }
+
+    /// The directories that the installer links to. Symlinks into these were made by the installer.
+    pub fn owned_dirs(&self) -> [&Path; 3] {
+        [self.files.as_path(), &self.hosts, self.submodules.as_path()]
+    }
+}
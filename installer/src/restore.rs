@@ -0,0 +1,432 @@
+use std::{
+    fs::{self, Permissions},
+    io::{self, ErrorKind},
+    os::unix::{
+        self,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use nix::sys::{
+    stat::{self, UtimensatFlags},
+    time::TimeSpec,
+};
+
+use crate::backup::{xattrs_unsupported, Backup, Entry, EntryKind, LegacyBackup};
+use crate::error::{InstallError, Result};
+use crate::install::{file_exists, get_file_type, links_into};
+use crate::path::*;
+use crate::plan::{Action, Plan};
+use crate::repo::Repo;
+
+/// Plans restoring `backup` into `home_dir`, replacing only links into `repo`.
+pub fn plan_restore_backup(home_dir: &HomePath, repo: &Repo, backup: &Backup) -> Result<Plan> {
+    let mut plan = Plan::new();
+    plan_restore(&mut plan, home_dir, backup, &repo.owned_dirs())?;
+    Ok(plan)
+}
+
+/// Plans restoring a backup directory that an older version of the installer made into `home_dir`,
+/// the same way as a backup in the store.
+pub fn plan_restore_legacy_backup(home_dir: &HomePath, repo: &Repo, backup: &LegacyBackup) -> Result<Plan> {
+    let mut plan = Plan::new();
+    let restore = |file| Action::RestoreLegacy(backup.clone(), file);
+    plan_restore_entries(&mut plan, home_dir, backup.entries()?, restore, &repo.owned_dirs())?;
+    Ok(plan)
+}
+
+/// Plans restoring the contents of `backup` into the home directory. Things in the home directory
+/// are only replaced if they're symlinks into one of `owned_dirs`, i.e. if they were created by the
+/// installer. Likewise, symlinks into `owned_dirs` in the backup are skipped, since they were made by
+/// an earlier install and aren't the user's original files.
+pub fn plan_restore(plan: &mut Plan, home_dir: &HomePath, backup: &Backup, owned_dirs: &[&Path]) -> Result<()> {
+    let restore = |file| Action::Restore(backup.clone(), file);
+    plan_restore_entries(plan, home_dir, backup.entries()?, restore, owned_dirs)
+}
+
+/// Plans restoring the files in `entries` of a backup like [`plan_restore`]. `restore` makes the
+/// action that restores a file from the backup.
+fn plan_restore_entries<F: Fn(HomeFile) -> Action>(
+    plan: &mut Plan,
+    home_dir: &HomePath,
+    entries: Vec<Entry>,
+    restore: F,
+    owned_dirs: &[&Path],
+) -> Result<()> {
+    // Entries in directories that are restored or skipped as a whole don't need to be looked at.
+    let mut done: Vec<PathBuf> = Vec::new();
+
+    for entry in entries {
+        if done.iter().any(|dir| entry.path.starts_with(dir)) {
+            continue;
+        }
+        let home_file = home_dir.with_rel_file(&RelPath::new(&entry.path));
+
+        if entry_links_into_any(&entry, owned_dirs) {
+            log::debug!("Not restoring {} since it was created by the installer", home_file);
+        } else if will_be_free(plan, &home_file) {
+            plan.push(restore(home_file));
+        } else if links_into_any(&home_file, owned_dirs)? {
+            plan.push(Action::Unlink(home_file.clone()));
+            plan.push(restore(home_file));
+        } else if matches!(entry.kind, EntryKind::Dir { .. }) && get_file_type(&home_file)?.is_dir() {
+            // Restore the things in the directory one by one instead.
+            continue;
+        } else if will_be_empty(plan, &home_file)? {
+            // The installer made the directory for dotfiles where a file used to be.
+            plan.push(Action::RemoveEmptyDir(home_file.clone()));
+            plan.push(restore(home_file));
+        } else {
+            log::warn!(
+                "Not restoring {} because it's in the way and wasn't created by the installer",
+                home_file
+            );
+        }
+        done.push(entry.path);
+    }
+
+    Ok(())
+}
+
+pub(crate) fn links_into_any<P: FilePath>(path: &P, dirs: &[&Path]) -> Result<bool> {
+    for dir in dirs {
+        if links_into(path, dir)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn entry_links_into_any(entry: &Entry, dirs: &[&Path]) -> bool {
+    matches!(&entry.kind, EntryKind::Symlink { target } if dirs.iter().any(|dir| target.starts_with(dir)))
+}
+
+/// Checks whether nothing will be at `file` once the actions in `plan` have been executed.
+fn will_be_free(plan: &Plan, file: &HomeFile) -> bool {
+    let mut ancestor = Some(file.clone());
+    while let Some(f) = ancestor {
+        if plan.actions().contains(&Action::Unlink(f.clone()))
+            || plan.actions().contains(&Action::RemoveCopy(f.clone()))
+        {
+            return true;
+        }
+        ancestor = f.parent();
+    }
+    !file_exists(file)
+}
+
+/// Checks whether `dir` is a directory that will only have empty directories left in it once the
+/// actions in `plan` have been executed.
+fn will_be_empty(plan: &Plan, dir: &HomeFile) -> Result<bool> {
+    if !get_file_type(dir)?.is_dir() {
+        return Ok(false);
+    }
+    let path = dir.to_path_buf();
+    for entry in path.read_dir().map_err(InstallError::io("read directory", &path))? {
+        let name = entry
+            .map_err(InstallError::io("get directory entry in", &path))?
+            .file_name();
+        let child = dir
+            .prefix()
+            .with_rel_file(&RelPath::new(dir.rel_file().as_path_buf().join(name)));
+        if !will_be_free(plan, &child) && !will_be_empty(plan, &child)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Puts a file, symlink, or directory tree from `backup` back into the home directory, keeping its
+/// type, mode, times, extended attributes and, if allowed, its owner.
+pub(crate) fn restore_file(backup: &Backup, file: &HomeFile) -> Result<()> {
+    log::trace!("Restoring {} from {}", file, backup);
+    let entries = backup.entries_for(file.rel_file())?;
+    restore_entries(&entries, file, backup.name(), |entry, path| {
+        backup.copy_file(entry, path)
+    })
+}
+
+/// Puts a file, symlink, or directory tree from a backup directory that an older version of the
+/// installer made back into the home directory, like [`restore_file`].
+pub(crate) fn restore_legacy_file(backup: &LegacyBackup, file: &HomeFile) -> Result<()> {
+    log::trace!("Restoring {} from {}", file, backup);
+    let entries = backup.entries_for(file.rel_file())?;
+    restore_entries(&entries, file, &backup.to_string(), |entry, path| {
+        fs::copy(backup.as_path().join(&entry.path), path).map(|_| ())
+    })
+}
+
+/// Restores `entries`, which are `file` and everything in it, from the backup called `backup`.
+/// `copy_file` copies the contents of a file out of the backup.
+fn restore_entries<F>(entries: &[Entry], file: &HomeFile, backup: &str, copy_file: F) -> Result<()>
+where
+    F: Fn(&Entry, &Path) -> io::Result<()>,
+{
+    if entries.is_empty() {
+        return Err(InstallError::NotInBackup {
+            file: file.to_path_buf(),
+            backup: backup.to_string(),
+        });
+    }
+
+    let home_dir = file.prefix().as_path();
+    for entry in entries {
+        let path = home_dir.join(&entry.path);
+        restore_entry(entry, &path, &copy_file).map_err(InstallError::io("restore", &path))?;
+    }
+
+    // Set the permissions and times of directories last, since restoring the things in them changes
+    // their modification times and they might not be writable.
+    for entry in entries.iter().rev() {
+        if let EntryKind::Dir { mode } = entry.kind {
+            let path = home_dir.join(&entry.path);
+            set_xattrs(entry, &path)
+                .and_then(|_| set_owner(entry, &path))
+                .and_then(|_| fs::set_permissions(&path, Permissions::from_mode(mode)))
+                .and_then(|_| set_times(entry, &path))
+                .map_err(InstallError::io("restore", &path))?;
+        }
+    }
+    Ok(())
+}
+
+fn restore_entry<F: Fn(&Entry, &Path) -> io::Result<()>>(entry: &Entry, path: &Path, copy_file: F) -> io::Result<()> {
+    match &entry.kind {
+        EntryKind::File { mode, .. } => {
+            copy_file(entry, path)?;
+            set_xattrs(entry, path)?;
+            // Changing the owner clears the setuid and setgid bits, so it has to come first.
+            set_owner(entry, path)?;
+            fs::set_permissions(path, Permissions::from_mode(*mode))?;
+            set_times(entry, path)
+        }
+        EntryKind::Symlink { target } => {
+            unix::fs::symlink(target, path)?;
+            set_owner(entry, path)?;
+            set_times(entry, path)
+        }
+        EntryKind::Dir { .. } => fs::create_dir(path),
+    }
+}
+
+/// Sets the access and modification times of `path` to the ones in `entry`, without following
+/// symlinks. Backups made before access times were recorded leave the access time alone.
+fn set_times(entry: &Entry, path: &Path) -> io::Result<()> {
+    let atime = match (entry.atime, entry.atime_nsec) {
+        (0, 0) => TimeSpec::UTIME_OMIT,
+        (sec, nsec) => TimeSpec::new(sec, nsec),
+    };
+    let mtime = TimeSpec::new(entry.mtime, entry.mtime_nsec);
+    stat::utimensat(None, path, &atime, &mtime, UtimensatFlags::NoFollowSymlink)?;
+    Ok(())
+}
+
+/// Gives `path` the owner and group in `entry`, without following symlinks. Only root can give files
+/// to other users, so not being allowed to is only a warning.
+fn set_owner(entry: &Entry, path: &Path) -> io::Result<()> {
+    let metadata = path.symlink_metadata()?;
+    if metadata.uid() == entry.uid && metadata.gid() == entry.gid {
+        return Ok(());
+    }
+    match unix::fs::lchown(path, Some(entry.uid), Some(entry.gid)) {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            log::warn!(
+                "Couldn't give {} back to user {} and group {}: {}",
+                path.to_string_lossy(),
+                entry.uid,
+                entry.gid,
+                e
+            );
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Sets the extended attributes in `entry` on `path`. File systems that don't support them only get
+/// a warning.
+fn set_xattrs(entry: &Entry, path: &Path) -> io::Result<()> {
+    for (name, value) in entry.decoded_xattrs()? {
+        match xattr::set(path, name, &value) {
+            Err(e) if xattrs_unsupported(&e) => {
+                log::warn!("Couldn't set {} on {}: {}", name, path.to_string_lossy(), e);
+                return Ok(());
+            }
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::backup::BackupFormat;
+    use crate::test_util::{self, Fixture};
+
+    #[test]
+    fn plan_restore_replaces_only_owned_links() -> io::Result<()> {
+        let home = Fixture::new()?;
+        let store_dir = Fixture::new()?;
+        let home_dir = home.home_dir();
+        fs::create_dir(home.path().join("dir"))?;
+        fs::write(home.path().join("dir/original"), "foo")?;
+        fs::write(home.path().join("in-the-way"), "foo")?;
+        fs::write(home.path().join("linked"), "foo")?;
+        unix::fs::symlink("/dotfiles/files/owned", home.path().join("owned"))?;
+        let backup = store_dir.store().new_backup();
+        backup.create(&test_util::run()).unwrap();
+        for file in ["dir", "in-the-way", "linked", "owned"] {
+            backup.add(&home_dir.with_rel_file(&RelPath::new(file))).unwrap();
+        }
+        fs::remove_file(home.path().join("dir/original"))?;
+        fs::write(home.path().join("in-the-way"), "bar")?;
+        fs::remove_file(home.path().join("linked"))?;
+        unix::fs::symlink("/dotfiles/files/linked", home.path().join("linked"))?;
+        fs::remove_file(home.path().join("owned"))?;
+
+        let mut plan = Plan::new();
+        plan_restore(&mut plan, &home_dir, &backup, &[Path::new("/dotfiles/files")]).unwrap();
+
+        let original = home_dir.with_rel_file(&RelPath::new("dir/original"));
+        let linked = home_dir.with_rel_file(&RelPath::new("linked"));
+        assert_eq!(
+            plan.actions(),
+            [
+                Action::Restore(backup.clone(), original),
+                Action::Unlink(linked.clone()),
+                Action::Restore(backup, linked),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn restores_legacy_backups() -> io::Result<()> {
+        let home = Fixture::new()?;
+        let home_dir = home.home_dir();
+        let backup_dir = home.path().join("dotfiles-backup_2023-01-02_03:04:05");
+        fs::create_dir_all(backup_dir.join("dir"))?;
+        fs::write(backup_dir.join("README.md"), "Don't restore me")?;
+        fs::write(backup_dir.join("dir/original"), "foo")?;
+        fs::set_permissions(backup_dir.join("dir/original"), Permissions::from_mode(0o600))?;
+        fs::write(backup_dir.join("in-the-way"), "foo")?;
+        fs::write(backup_dir.join("linked"), "foo")?;
+        unix::fs::symlink("/dotfiles/files/owned", backup_dir.join("owned"))?;
+        fs::create_dir(home.path().join("dir"))?;
+        fs::write(home.path().join("in-the-way"), "bar")?;
+        unix::fs::symlink("/dotfiles/files/linked", home.path().join("linked"))?;
+        let backup = LegacyBackup::find(&home_dir).unwrap().pop().unwrap();
+
+        let plan = plan_restore_legacy_backup(&home_dir, &Repo::new("/dotfiles"), &backup).unwrap();
+
+        let original = home_dir.with_rel_file(&RelPath::new("dir/original"));
+        let linked = home_dir.with_rel_file(&RelPath::new("linked"));
+        assert_eq!(
+            plan.actions(),
+            [
+                Action::RestoreLegacy(backup.clone(), original),
+                Action::Unlink(linked.clone()),
+                Action::RestoreLegacy(backup, linked),
+            ]
+        );
+        plan.execute().unwrap();
+        assert_eq!(fs::read_to_string(home.path().join("dir/original"))?, "foo");
+        assert_eq!(home.path().join("dir/original").metadata()?.mode() & 0o777, 0o600);
+        assert_eq!(fs::read_to_string(home.path().join("linked"))?, "foo");
+        assert_eq!(fs::read_to_string(home.path().join("in-the-way"))?, "bar");
+        assert!(!home.path().join("README.md").exists());
+        assert!(!file_exists(&home_dir.with_rel_file(&RelPath::new("owned"))));
+        Ok(())
+    }
+
+    #[test]
+    fn restore_file_keeps_type_mode_and_times() -> io::Result<()> {
+        let home = Fixture::new()?;
+        let store_dir = Fixture::new()?;
+        let dir = home.home_dir().with_rel_file(&RelPath::new("dir"));
+        fs::create_dir(home.path().join("dir"))?;
+        fs::write(home.path().join("dir/file"), "foo")?;
+        fs::set_permissions(home.path().join("dir/file"), Permissions::from_mode(0o600))?;
+        unix::fs::symlink("file", home.path().join("dir/link"))?;
+        fs::set_permissions(home.path().join("dir"), Permissions::from_mode(0o750))?;
+        let mtime = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        fs::File::open(home.path().join("dir/file"))?.set_modified(mtime)?;
+        let backup = store_dir.store().new_backup();
+        backup.create(&test_util::run()).unwrap();
+        backup.add(&dir).unwrap();
+        fs::remove_dir_all(home.path().join("dir"))?;
+
+        restore_file(&backup, &dir).unwrap();
+
+        let file = home.path().join("dir/file");
+        assert_eq!(fs::read_to_string(&file)?, "foo");
+        assert_eq!(file.metadata()?.permissions().mode() & 0o777, 0o600);
+        assert_eq!(file.metadata()?.modified()?, mtime);
+        assert_eq!(fs::read_link(home.path().join("dir/link"))?, Path::new("file"));
+        assert_eq!(home.path().join("dir").metadata()?.permissions().mode() & 0o777, 0o750);
+        Ok(())
+    }
+
+    #[test]
+    fn restore_round_trips_metadata_in_every_format() -> io::Result<()> {
+        for format in [BackupFormat::Dir, BackupFormat::TarZst, BackupFormat::TarGz] {
+            // Anything that lists a directory bumps its access time, and editors and file watchers
+            // scan the working tree, so keep out of it.
+            let home = Fixture::new_in(std::env::temp_dir())?;
+            let store_dir = Fixture::new_in(std::env::temp_dir())?;
+            let dir = home.home_dir().with_rel_file(&RelPath::new("dir"));
+            let file = home.path().join("dir/file");
+            fs::create_dir(home.path().join("dir"))?;
+            fs::write(&file, "foo")?;
+            let xattrs = match xattr::set(&file, "user.test", b"\0bar") {
+                Err(e) if xattrs_unsupported(&e) => false,
+                result => result.map(|_| true)?,
+            };
+            let root = nix::unistd::Uid::current().is_root();
+            if root {
+                unix::fs::lchown(&file, Some(12345), Some(12345))?;
+            }
+            fs::set_permissions(&file, Permissions::from_mode(0o640))?;
+            let atime = TimeSpec::new(1_000_000_000, 123);
+            let mtime = TimeSpec::new(1_100_000_000, 456);
+            for path in [&file, &home.path().join("dir")] {
+                stat::utimensat(None, path, &atime, &mtime, UtimensatFlags::NoFollowSymlink)?;
+            }
+            fs::set_permissions(home.path().join("dir"), Permissions::from_mode(0o710))?;
+            let before = (file.metadata()?, home.path().join("dir").metadata()?);
+            let backup = store_dir.store().with_format(format).new_backup();
+            backup.create(&test_util::run()).unwrap();
+            backup.add(&dir).unwrap();
+            fs::set_permissions(home.path().join("dir"), Permissions::from_mode(0o755))?;
+            fs::remove_dir_all(home.path().join("dir"))?;
+
+            restore_file(&backup, &dir).unwrap();
+
+            let after = (file.metadata()?, home.path().join("dir").metadata()?);
+            for (before, after) in [(&before.0, &after.0), (&before.1, &after.1)] {
+                assert_eq!(after.mode(), before.mode(), "{}", format);
+                assert_eq!(
+                    (after.atime(), after.atime_nsec(), after.mtime(), after.mtime_nsec()),
+                    (before.atime(), before.atime_nsec(), before.mtime(), before.mtime_nsec()),
+                    "{}",
+                    format
+                );
+                assert_eq!((after.uid(), after.gid()), (before.uid(), before.gid()), "{}", format);
+            }
+            assert_eq!(after.0.mode() & 0o7777, 0o640);
+            assert_eq!(after.1.mode() & 0o7777, 0o710);
+            if root {
+                assert_eq!((after.0.uid(), after.0.gid()), (12345, 12345));
+            }
+            if xattrs {
+                assert_eq!(xattr::get(&file, "user.test")?, Some(b"\0bar".to_vec()), "{}", format);
+            }
+            fs::set_permissions(home.path().join("dir"), Permissions::from_mode(0o755))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use crate::error::{InstallError, Result};
+use crate::fold;
+use crate::host::Host;
+use crate::install::{self, file_exists, get_file_type};
+use crate::manifest::{LinkMode, Manifest};
+use crate::path::*;
+use crate::repo::Repo;
+use crate::template::{self, Variables};
+
+/// The state of a single link in the home directory.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum LinkState {
+    /// The link exists and points to the right place.
+    Ok,
+    /// There's nothing where the link should be.
+    Missing,
+    /// There's a file where the link should be.
+    ConflictFile,
+    /// A copied dotfile or rendered template has different contents than it should.
+    Modified,
+    /// There's a directory where the link should be.
+    ConflictDir,
+    /// There's a link to somewhere else where the link should be.
+    ForeignLink(PathBuf),
+    /// There's a link to something that doesn't exist where the link should be.
+    BrokenLink(PathBuf),
+}
+
+impl LinkState {
+    pub fn is_ok(&self) -> bool {
+        *self == LinkState::Ok
+    }
+}
+
+impl Display for LinkState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LinkState::Ok => "ok",
+            LinkState::Missing => "missing",
+            LinkState::ConflictFile => "conflict-file",
+            LinkState::Modified => "modified",
+            LinkState::ConflictDir => "conflict-dir",
+            LinkState::ForeignLink(_) => "foreign-link",
+            LinkState::BrokenLink(_) => "broken-link",
+        };
+        // Use pad so that width specifiers work.
+        f.pad(name)
+    }
+}
+
+/// Figures out the state of `link`, which should be a symlink to `target`.
+pub fn link_state(link: &HomeFile, target: &Path) -> Result<LinkState> {
+    if !file_exists(link) {
+        return Ok(LinkState::Missing);
+    }
+
+    if get_file_type(link)?.is_symlink() && read_link(link)? == target {
+        Ok(LinkState::Ok)
+    } else {
+        conflict_state(link)
+    }
+}
+
+/// Figures out the state of `file`, which should have been installed from `source` with `mode`.
+pub fn installed_state(file: &HomeFile, source: &Dotfile, mode: LinkMode, vars: &Variables) -> Result<LinkState> {
+    match mode {
+        LinkMode::Symlink => link_state(file, &source.to_path_buf()),
+        LinkMode::Copy => {
+            let contents = fs::read(source.to_path_buf()).map_err(InstallError::io("read", source.to_path_buf()))?;
+            contents_state(file, &contents)
+        }
+        LinkMode::Hardlink => hardlink_state(file, source),
+        LinkMode::Template => contents_state(file, template::render_file(source, vars)?.as_bytes()),
+    }
+}
+
+fn contents_state(file: &HomeFile, expected: &[u8]) -> Result<LinkState> {
+    if !file_exists(file) {
+        return Ok(LinkState::Missing);
+    }
+
+    if !get_file_type(file)?.is_file() {
+        conflict_state(file)
+    } else if fs::read(file.to_path_buf()).map_err(InstallError::io("read", file.to_path_buf()))? == expected {
+        Ok(LinkState::Ok)
+    } else {
+        Ok(LinkState::Modified)
+    }
+}
+
+fn hardlink_state(file: &HomeFile, source: &Dotfile) -> Result<LinkState> {
+    if !file_exists(file) {
+        return Ok(LinkState::Missing);
+    }
+
+    let metadata = file
+        .to_path_buf()
+        .symlink_metadata()
+        .map_err(InstallError::io("get metadata for", file.to_path_buf()))?;
+    let source_metadata = source
+        .to_path_buf()
+        .metadata()
+        .map_err(InstallError::io("get metadata for", source.to_path_buf()))?;
+    if metadata.dev() == source_metadata.dev() && metadata.ino() == source_metadata.ino() {
+        Ok(LinkState::Ok)
+    } else {
+        conflict_state(file)
+    }
+}
+
+/// Figures out what's in the way at `file`.
+fn conflict_state(file: &HomeFile) -> Result<LinkState> {
+    let file_type = get_file_type(file)?;
+    Ok(if file_type.is_symlink() {
+        let target = read_link(file)?;
+        if file.to_path_buf().exists() {
+            LinkState::ForeignLink(target)
+        } else {
+            LinkState::BrokenLink(target)
+        }
+    } else if file_type.is_dir() {
+        LinkState::ConflictDir
+    } else {
+        LinkState::ConflictFile
+    })
+}
+
+fn read_link(link: &HomeFile) -> Result<PathBuf> {
+    link.to_path_buf()
+        .read_link()
+        .map_err(InstallError::io("read symlink", link.to_path_buf()))
+}
+
+/// The state of one of the things that the installer installs.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LinkStatus {
+    pub file: HomeFile,
+    /// The part of the repo that the file comes from, like `files` or `hosts/laptop`.
+    pub layer: String,
+    pub state: LinkState,
+}
+
+/// Gets the state of every dotfile that the installer installs in `home_dir` on `host`, sorted by
+/// path.
+pub fn status(home_dir: &HomePath, repo: &Repo, host: &Host) -> Result<Vec<LinkStatus>> {
+    let manifest = Manifest::load(repo)?;
+    let mut links = fold::fold_links(home_dir, repo, &manifest, install::find_links(repo, &manifest, host)?)?;
+    links.sort_by(|a, b| a.target.cmp(&b.target));
+    let vars = manifest.variables(host, home_dir);
+
+    links
+        .iter()
+        .map(|link| {
+            let file = home_dir.with_rel_file(&link.target);
+            let state = installed_state(&file, &link.source, link.mode, &vars)?;
+            Ok(LinkStatus {
+                file,
+                layer: repo.layer_name(link.source.prefix()),
+                state,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io, os::unix};
+
+    use super::*;
+    use crate::test_util::Fixture;
+
+    #[test]
+    fn link_states() -> io::Result<()> {
+        let home = Fixture::new()?;
+        let home_dir = home.home_dir();
+        let target = home.path().join("target");
+        let other = home.path().join("other");
+        let nowhere = home.path().join("nowhere");
+        fs::write(&target, "foo")?;
+        fs::write(&other, "foo")?;
+        fs::create_dir(home.path().join("dir"))?;
+        unix::fs::symlink(&target, home.path().join("ok"))?;
+        unix::fs::symlink(&other, home.path().join("foreign"))?;
+        unix::fs::symlink(&nowhere, home.path().join("broken"))?;
+
+        let state = |name: &str| link_state(&home_dir.with_rel_file(&RelPath::new(name)), &target).unwrap();
+        assert_eq!(state("ok"), LinkState::Ok);
+        assert_eq!(state("missing"), LinkState::Missing);
+        assert_eq!(state("other"), LinkState::ConflictFile);
+        assert_eq!(state("dir"), LinkState::ConflictDir);
+        assert_eq!(state("foreign"), LinkState::ForeignLink(other));
+        assert_eq!(state("broken"), LinkState::BrokenLink(nowhere));
+        Ok(())
+    }
+
+    #[test]
+    fn template_drift() -> io::Result<()> {
+        let home = Fixture::new()?;
+        let home_dir = home.home_dir();
+        let template = DotfilesPath::new(home.path()).with_rel_file(&RelPath::new("template"));
+        let rendered = home_dir.with_rel_file(&RelPath::new("rendered"));
+        fs::write(template.to_path_buf(), "user = {{ user }}\n")?;
+        let vars: Variables = [("user".to_string(), "me".to_string())].into_iter().collect();
+        let state = || installed_state(&rendered, &template, LinkMode::Template, &vars).unwrap();
+
+        assert_eq!(state(), LinkState::Missing);
+        fs::write(rendered.to_path_buf(), "user = me\n")?;
+        assert_eq!(state(), LinkState::Ok);
+        fs::write(rendered.to_path_buf(), "user = me\nedited = true\n")?;
+        assert_eq!(state(), LinkState::Modified);
+        Ok(())
+    }
+}
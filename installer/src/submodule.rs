@@ -0,0 +1,851 @@
+use std::{
+    ffi::OsStr,
+    fmt::{self, Display, Formatter},
+    fs,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::error::{InstallError, Result};
+use crate::git::{git, git_succeeds};
+use crate::manifest;
+
+const GITMODULES_FILE: &str = ".gitmodules";
+
+/// A submodule of a git repository, as described by the repository's `.gitmodules`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Submodule {
+    /// The name of the submodule, which is usually the same as its path.
+    pub name: String,
+    /// Where the submodule is checked out, relative to the root of the repository.
+    pub path: PathBuf,
+    pub url: String,
+    /// The branch that the submodule follows, if `.gitmodules` names one.
+    pub branch: Option<String>,
+    /// What `submodules update` moves the submodule to, set by `follow` in `.gitmodules`.
+    pub follow: Follow,
+    /// Where the installer links the submodule to, relative to the home directory. Submodules
+    /// without a `target` in `.gitmodules` aren't linked.
+    pub target: Option<PathBuf>,
+}
+
+/// What a submodule is updated to.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum Follow {
+    /// The tip of the branch that the submodule follows.
+    #[default]
+    Branch,
+    /// The newest tag, by version number. Release candidates, which have `rc` in the tag, are left
+    /// out.
+    Tags,
+}
+
+/// How a submodule's checkout compares to the commit that the repository pins it to.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SubmoduleState {
+    /// The submodule hasn't been cloned.
+    Uninitialized,
+    /// The submodule is checked out at the pinned commit.
+    Pinned,
+    /// The submodule is checked out at another commit, which has `ahead` commits that the pinned
+    /// commit doesn't have and is missing `behind` commits that it has.
+    Moved { ahead: usize, behind: usize },
+    /// The pinned commit hasn't been fetched into the submodule's clone.
+    Unfetched,
+}
+
+/// A newer commit for a submodule, found by [`Submodule::fetch_update`].
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Update {
+    /// The branch or tag that the submodule follows, like `origin/main` or `v0.3.0`.
+    pub rev: String,
+    /// The commit that the repository pins the submodule to now.
+    pub old: Option<String>,
+    /// The commit that `rev` is at.
+    pub new: String,
+    /// The commits between `old` and `new`, newest first, as a short hash and the subject.
+    pub log: Vec<String>,
+}
+
+/// The state of a submodule, see [`status`].
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SubmoduleStatus {
+    pub submodule: Submodule,
+    /// The commit that the repository pins the submodule to, or `None` if the submodule is only in
+    /// `.gitmodules`.
+    pub pinned: Option<String>,
+    /// The commit that's checked out in the submodule, if it has been cloned.
+    pub head: Option<String>,
+    pub state: SubmoduleState,
+    /// Whether the submodule has changes that haven't been committed, or untracked files.
+    pub dirty: bool,
+}
+
+impl Submodule {
+    /// Gets the directory that the submodule is checked out in. `root` is the root of the
+    /// repository that the submodule is in.
+    pub fn dir(&self, root: &Path) -> PathBuf {
+        root.join(&self.path)
+    }
+
+    /// Gets the commit that the repository at `root` pins the submodule to in its index.
+    pub fn pinned_commit(&self, root: &Path) -> Result<Option<String>> {
+        let entry = git(
+            root,
+            &[
+                OsStr::new("ls-files"),
+                "--stage".as_ref(),
+                "--".as_ref(),
+                self.path.as_ref(),
+            ],
+        )?;
+        Ok(entry
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .find(|(_, path)| Path::new(path) == self.path)
+            .and_then(|(info, _)| match info.split_whitespace().collect::<Vec<_>>()[..] {
+                ["160000", commit, _] => Some(commit.to_string()),
+                _ => None,
+            }))
+    }
+
+    /// Checks whether the submodule has been cloned into the repository at `root`.
+    pub fn is_initialized(&self, root: &Path) -> bool {
+        self.dir(root).join(".git").symlink_metadata().is_ok()
+    }
+
+    /// Finds out the state of the submodule in the repository at `root`. This doesn't need the
+    /// network.
+    pub fn status(&self, root: &Path) -> Result<SubmoduleStatus> {
+        let pinned = self.pinned_commit(root)?;
+        let mut status = SubmoduleStatus {
+            submodule: self.clone(),
+            pinned: pinned.clone(),
+            head: None,
+            state: SubmoduleState::Uninitialized,
+            dirty: false,
+        };
+        if !self.is_initialized(root) {
+            return Ok(status);
+        }
+
+        let dir = self.dir(root);
+        let head = git(&dir, &["rev-parse", "--verify", "--quiet", "HEAD"]).ok();
+        status.dirty = !git(&dir, &["status", "--porcelain"])?.is_empty();
+        status.state = match (&head, &pinned) {
+            (Some(head), Some(pinned)) if head == pinned => SubmoduleState::Pinned,
+            (_, Some(pinned)) if !has_commit(&dir, pinned)? => SubmoduleState::Unfetched,
+            (Some(_), Some(pinned)) => {
+                let counts = git(
+                    &dir,
+                    &["rev-list", "--left-right", "--count", &format!("HEAD...{}", pinned)],
+                )?;
+                let (ahead, behind) = parse_counts(&counts).ok_or_else(|| InstallError::Git {
+                    args: vec!["rev-list".to_string()],
+                    dir: dir.clone(),
+                    message: format!("unexpected output {:?}", counts),
+                })?;
+                SubmoduleState::Moved { ahead, behind }
+            }
+            // Nothing is pinned, so wherever the submodule is is as good as anywhere.
+            (_, None) => SubmoduleState::Pinned,
+            (None, Some(_)) => SubmoduleState::Uninitialized,
+        };
+        status.head = head;
+        Ok(status)
+    }
+
+    /// Clones the submodule into the repository at `root` and checks out `commit`. The clone goes in
+    /// the repository's `modules` directory like `git submodule` puts it, so that git treats it like
+    /// any other submodule. A clone that's already there, like one left by `git submodule deinit`, is
+    /// reused instead. The checkout and the clone point at each other with relative paths, so that
+    /// they keep working when the repository moves.
+    fn init(&self, root: &Path, commit: &str) -> Result<()> {
+        let dir = self.dir(root);
+        let git_dir = self.git_dir(root)?;
+        if git_dir.is_dir() {
+            log::info!("Reusing the existing clone of submodule {}", self.name);
+            fs::create_dir_all(&dir).map_err(InstallError::io("create directory", &dir))?;
+        } else {
+            log::info!("Cloning submodule {} from {}", self.name, self.url);
+            let modules_dir = git_dir.parent().unwrap();
+            fs::create_dir_all(modules_dir).map_err(InstallError::io("create directory", modules_dir))?;
+            git(
+                root,
+                &[
+                    OsStr::new("clone"),
+                    "--quiet".as_ref(),
+                    "--no-checkout".as_ref(),
+                    "--separate-git-dir".as_ref(),
+                    git_dir.as_os_str(),
+                    self.url.as_ref(),
+                    dir.as_os_str(),
+                ],
+            )?;
+        }
+        // git clone writes absolute paths, so replace them.
+        self.link_git_dir(root, &git_dir)?;
+
+        if !has_commit(&dir, commit)? {
+            log::info!("Fetching commit {} of submodule {}", short(commit), self.name);
+            git(&dir, &["fetch", "--quiet", "origin", commit])?;
+        }
+        // The checkout is empty, but the index of a reused clone still lists what used to be in it,
+        // so force git to check out everything.
+        git(&dir, &["checkout", "--quiet", "--force", "--detach", commit])?;
+
+        // Mark the submodule as initialized, like `git submodule init` does.
+        git(root, &["config", &format!("submodule.{}.url", self.name), &self.url])?;
+        git(root, &["config", &format!("submodule.{}.active", self.name), "true"])?;
+        Ok(())
+    }
+
+    /// Gets where the clone of the submodule in the repository at `root` goes.
+    fn git_dir(&self, root: &Path) -> Result<PathBuf> {
+        Ok(root.join(git(
+            root,
+            &["rev-parse", "--git-path", &format!("modules/{}", self.name)],
+        )?))
+    }
+
+    /// Points the checkout of the submodule in the repository at `root` and its clone in `git_dir`
+    /// at each other with relative paths, like `git submodule absorbgitdirs` does.
+    fn link_git_dir(&self, root: &Path, git_dir: &Path) -> Result<()> {
+        let dir = self.dir(root);
+        let git_file = dir.join(".git");
+        fs::write(
+            &git_file,
+            format!("gitdir: {}\n", relative_path(&dir, git_dir)?.to_string_lossy()),
+        )
+        .map_err(InstallError::io("write", &git_file))?;
+        // Go through the config file, since git refuses to run in a checkout whose worktree is gone.
+        git(
+            root,
+            &[
+                OsStr::new("config"),
+                "--file".as_ref(),
+                git_dir.join("config").as_os_str(),
+                "core.worktree".as_ref(),
+                relative_path(git_dir, &dir)?.as_os_str(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Fetches what the submodule follows in the repository at `root` and finds the commit to update
+    /// it to. That's the newest tag if it follows tags, and otherwise the tip of the branch that
+    /// `.gitmodules` names, or of the default branch of the submodule's remote. The submodule has to
+    /// have been cloned.
+    pub fn fetch_update(&self, root: &Path) -> Result<Update> {
+        let dir = self.dir(root);
+        let rev = match (self.follow, &self.branch) {
+            (Follow::Tags, _) => {
+                log::info!("Fetching the tags of submodule {}", self.name);
+                git(&dir, &["fetch", "--quiet", "--tags", "origin"])?;
+                let tags = git(&dir, &["tag", "--list", "--sort=-version:refname"])?;
+                let tag = tags
+                    .lines()
+                    .find(|tag| !tag.contains("rc"))
+                    .ok_or_else(|| InstallError::Git {
+                        args: vec!["tag".to_string(), "--list".to_string()],
+                        dir: dir.clone(),
+                        message: "the submodule has no tags to update to".to_string(),
+                    })?;
+                format!("refs/tags/{}", tag)
+            }
+            (Follow::Branch, Some(branch)) => {
+                log::info!("Fetching branch {} of submodule {}", branch, self.name);
+                let refspec = format!("+refs/heads/{0}:refs/remotes/origin/{0}", branch);
+                git(&dir, &["fetch", "--quiet", "--tags", "origin", &refspec])?;
+                format!("origin/{}", branch)
+            }
+            (Follow::Branch, None) => {
+                log::info!("Fetching submodule {}", self.name);
+                git(&dir, &["fetch", "--quiet", "--tags", "origin"])?;
+                if !git_succeeds(&dir, &["rev-parse", "--verify", "--quiet", "refs/remotes/origin/HEAD"])? {
+                    git(&dir, &["remote", "set-head", "origin", "--auto"])?;
+                }
+                git(&dir, &["rev-parse", "--abbrev-ref", "origin/HEAD"])?
+            }
+        };
+
+        let new = git(&dir, &["rev-parse", &format!("{}^{{commit}}", rev)])?;
+        let old = self.pinned_commit(root)?;
+        let log = match &old {
+            Some(old) if has_commit(&dir, old)? => {
+                let range = format!("{}..{}", old, new);
+                git(&dir, &["log", "--format=%h %s", &range])?
+                    .lines()
+                    .map(str::to_string)
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        let rev = rev.strip_prefix("refs/tags/").unwrap_or(&rev).to_string();
+        Ok(Update { rev, old, new, log })
+    }
+
+    /// Checks out the new commit of `update` in the submodule and stages it in the repository at
+    /// `root`. Submodules with uncommitted changes aren't touched, since checking out another commit
+    /// could lose them.
+    pub fn apply_update(&self, root: &Path, update: &Update) -> Result<()> {
+        let dir = self.dir(root);
+        if !git(&dir, &["status", "--porcelain"])?.is_empty() {
+            return Err(InstallError::Git {
+                args: vec!["checkout".to_string(), update.new.clone()],
+                dir,
+                message: "the submodule has uncommitted changes".to_string(),
+            });
+        }
+        git(&dir, &["checkout", "--quiet", "--detach", &update.new])?;
+        git(root, &[OsStr::new("add"), "--".as_ref(), self.path.as_ref()])?;
+        Ok(())
+    }
+}
+
+impl Update {
+    pub fn is_up_to_date(&self) -> bool {
+        self.old.as_ref() == Some(&self.new)
+    }
+}
+
+impl Display for SubmoduleState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SubmoduleState::Uninitialized => f.write_str("uninitialized"),
+            SubmoduleState::Pinned => f.write_str("pinned"),
+            SubmoduleState::Moved { ahead, behind: 0 } => write!(f, "ahead {}", ahead),
+            SubmoduleState::Moved { ahead: 0, behind } => write!(f, "behind {}", behind),
+            SubmoduleState::Moved { ahead, behind } => write!(f, "ahead {}, behind {}", ahead, behind),
+            SubmoduleState::Unfetched => f.write_str("unfetched"),
+        }
+    }
+}
+
+/// Reads the submodules of the repository at `root` from its `.gitmodules`. A repository without
+/// a `.gitmodules` has no submodules.
+pub fn load(root: &Path) -> Result<Vec<Submodule>> {
+    let path = root.join(GITMODULES_FILE);
+    match fs::read_to_string(&path) {
+        Ok(text) => parse(&path, &text),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(InstallError::io("read", &path)(e)),
+    }
+}
+
+/// Parses `text`, the contents of the `.gitmodules` at `path`. Sections other than `submodule`
+/// sections and keys other than `path`, `url`, `branch`, `follow` and `target` are ignored. Git
+/// ignores `follow` and `target`, which are only there for the installer.
+pub fn parse(path: &Path, text: &str) -> Result<Vec<Submodule>> {
+    let mut sections: Vec<Section> = Vec::new();
+    let mut in_submodule = false;
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(['#', ';']) {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            let (header, _) = header
+                .split_once(']')
+                .ok_or_else(|| InstallError::parse(path, text, start, "expected ] after the section name"))?;
+            in_submodule = false;
+            if let Some(name) = header.strip_prefix("submodule") {
+                let name = name
+                    .trim()
+                    .strip_prefix('"')
+                    .and_then(|name| name.strip_suffix('"'))
+                    .ok_or_else(|| InstallError::parse(path, text, start, "expected a quoted submodule name"))?;
+                sections.push(Section {
+                    name: name.to_string(),
+                    start,
+                    keys: Vec::new(),
+                });
+                in_submodule = true;
+            }
+        } else if let (true, Some((key, value))) = (in_submodule, line.split_once('=')) {
+            sections
+                .last_mut()
+                .unwrap()
+                .keys
+                .push((key.trim().to_ascii_lowercase(), config_value(value)));
+        }
+    }
+
+    sections
+        .into_iter()
+        .map(|Section { name, start, keys }| {
+            let get = |key: &str| keys.iter().rev().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+            let missing =
+                |key: &str| InstallError::parse(path, text, start, format!("submodule {} has no {}", name, key));
+            let target = get("target").map(PathBuf::from);
+            if let Some(target) = &target {
+                manifest::check_relative(target).map_err(|message| {
+                    InstallError::parse(
+                        path,
+                        text,
+                        start,
+                        format!("submodule {} has a bad target: {}", name, message),
+                    )
+                })?;
+            }
+            let follow = match get("follow").as_deref() {
+                None | Some("branch") => Follow::Branch,
+                Some("tags") => Follow::Tags,
+                Some(follow) => {
+                    return Err(InstallError::parse(
+                        path,
+                        text,
+                        start,
+                        format!("submodule {} can't follow {:?}, only branch or tags", name, follow),
+                    ))
+                }
+            };
+            Ok(Submodule {
+                path: get("path").map(PathBuf::from).ok_or_else(|| missing("path"))?,
+                url: get("url").ok_or_else(|| missing("url"))?,
+                branch: get("branch"),
+                follow,
+                target,
+                name,
+            })
+        })
+        .collect()
+}
+
+/// A `submodule` section in a `.gitmodules`.
+struct Section {
+    name: String,
+    /// Where the section starts in the file.
+    start: usize,
+    keys: Vec<(String, String)>,
+}
+
+/// Finds the submodules of the repository at `root` called `names`, or all of them if `names` is
+/// empty. A submodule can be named by its name, its path, or the last part of its path, like `fzf`
+/// for `submodules/fzf`.
+pub fn select(root: &Path, names: &[String]) -> Result<Vec<Submodule>> {
+    let submodules = load(root)?;
+    if names.is_empty() {
+        return Ok(submodules);
+    }
+    names
+        .iter()
+        .map(|name| {
+            submodules
+                .iter()
+                .find(|submodule| {
+                    submodule.name == *name
+                        || submodule.path == Path::new(name)
+                        || submodule.path.file_name() == Some(OsStr::new(name))
+                })
+                .cloned()
+                .ok_or_else(|| InstallError::SubmoduleNotFound(name.clone()))
+        })
+        .collect()
+}
+
+/// Finds out the state of every submodule of the repository at `root`.
+pub fn status(root: &Path) -> Result<Vec<SubmoduleStatus>> {
+    load(root)?.iter().map(|submodule| submodule.status(root)).collect()
+}
+
+/// Clones and checks out the submodules of the repository at `root` that haven't been cloned yet,
+/// and then does the same in each submodule. Submodules that are already there are left as they
+/// are, so this works offline once everything has been cloned. A submodule that can't be cloned
+/// only gets a warning, so that a missing network connection doesn't stop an install.
+pub fn init_missing(root: &Path) -> Result<()> {
+    for submodule in load(root)? {
+        let status = submodule.status(root)?;
+        match (status.state, &status.pinned) {
+            (SubmoduleState::Uninitialized, Some(pinned)) => {
+                if let Err(e) = submodule.init(root, pinned) {
+                    log::warn!("Couldn't check out submodule {}: {}", submodule.name, e);
+                    continue;
+                }
+            }
+            (SubmoduleState::Uninitialized, None) => {
+                log::warn!(
+                    "Not checking out submodule {} since the repo doesn't pin it to a commit",
+                    submodule.name
+                );
+                continue;
+            }
+            (SubmoduleState::Pinned, _) => log::debug!("Submodule {} is already checked out", submodule.name),
+            (state, _) => log::info!("Leaving submodule {} alone since it's {}", submodule.name, state),
+        }
+        if status.dirty {
+            log::info!("Submodule {} has uncommitted changes", submodule.name);
+        }
+        init_missing(&submodule.dir(root))?;
+    }
+    Ok(())
+}
+
+/// Gets the path to `to` relative to the directory `from`. Both have to exist.
+fn relative_path(from: &Path, to: &Path) -> Result<PathBuf> {
+    let from = from.canonicalize().map_err(InstallError::io("resolve", from))?;
+    let to = to.canonicalize().map_err(InstallError::io("resolve", to))?;
+    let common = from
+        .components()
+        .zip(to.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut path: PathBuf = from.components().skip(common).map(|_| Component::ParentDir).collect();
+    path.extend(to.components().skip(common));
+    Ok(path)
+}
+
+/// Fixes the submodules of the repository at `root` whose checkout points at its clone with an
+/// absolute path, which breaks when the repository moves, and then does the same in each
+/// submodule. Older versions of the installer cloned submodules like that.
+pub fn fix_moved(root: &Path) -> Result<()> {
+    for submodule in load(root)? {
+        if !submodule.is_initialized(root) {
+            continue;
+        }
+        let dir = submodule.dir(root);
+        let absolute = fs::read_to_string(dir.join(".git")).map_or(false, |git_file| {
+            git_file
+                .strip_prefix("gitdir:")
+                .map_or(false, |git_dir| Path::new(git_dir.trim()).is_absolute())
+        });
+        if absolute {
+            let git_dir = submodule.git_dir(root)?;
+            if !git_dir.is_dir() {
+                log::warn!(
+                    "Can't fix submodule {} since its clone isn't in {}",
+                    submodule.name,
+                    git_dir.to_string_lossy()
+                );
+                continue;
+            }
+            log::info!(
+                "Pointing submodule {} at its clone with a relative path",
+                submodule.name
+            );
+            submodule.link_git_dir(root, &git_dir)?;
+        }
+        fix_moved(&dir)?;
+    }
+    Ok(())
+}
+
+/// Checks whether the repository at `dir` has `commit`.
+fn has_commit(dir: &Path, commit: &str) -> Result<bool> {
+    git_succeeds(dir, &["cat-file", "-e", &format!("{}^{{commit}}", commit)])
+}
+
+/// Parses the output of `git rev-list --left-right --count`.
+fn parse_counts(counts: &str) -> Option<(usize, usize)> {
+    let (left, right) = counts.split_once('\t')?;
+    Some((left.parse().ok()?, right.parse().ok()?))
+}
+
+/// Shortens a commit hash for logging.
+pub fn short(commit: &str) -> &str {
+    &commit[..commit.len().min(10)]
+}
+
+/// Gets the value of a key in a git config file, without quotes, escapes, or trailing comments.
+fn config_value(raw: &str) -> String {
+    let mut value = String::new();
+    let mut quoted = false;
+    let mut chars = raw.trim().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some(c) => value.push(c),
+                None => {}
+            },
+            '#' | ';' if !quoted => break,
+            c => value.push(c),
+        }
+    }
+    value.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use super::*;
+    use crate::test_util::Fixture;
+
+    fn commit(dir: &Path, file: &str, contents: &str) -> String {
+        fs::write(dir.join(file), contents).unwrap();
+        git(dir, &["add", file]).unwrap();
+        git(
+            dir,
+            &[
+                "-c",
+                "user.name=Test",
+                "-c",
+                "user.email=test@example.com",
+                "commit",
+                "--quiet",
+                "-m",
+                contents,
+            ],
+        )
+        .unwrap();
+        git(dir, &["rev-parse", "HEAD"]).unwrap()
+    }
+
+    /// Makes a bare repo at `dir/name.git` with two commits in it, and returns their hashes.
+    fn make_upstream(dir: &Path, name: &str) -> (PathBuf, String, String) {
+        let work = dir.join(name);
+        fs::create_dir(&work).unwrap();
+        git(&work, &["init", "--quiet", "--initial-branch=main"]).unwrap();
+        let first = commit(&work, "file", "first");
+        let second = commit(&work, "file", "second");
+        let bare = dir.join(format!("{}.git", name));
+        git(
+            dir,
+            &[
+                OsStr::new("clone"),
+                "--quiet".as_ref(),
+                "--bare".as_ref(),
+                work.as_os_str(),
+                bare.as_os_str(),
+            ],
+        )
+        .unwrap();
+        (bare, first, second)
+    }
+
+    /// Makes a repo at `root` with submodules that are pinned to the given commits, without cloning
+    /// them.
+    fn make_superproject(root: &Path, submodules: &[(&str, &Path, &str)]) {
+        git(root, &["init", "--quiet"]).unwrap();
+        let mut gitmodules = String::new();
+        for (name, url, commit) in submodules {
+            let path = format!("submodules/{}", name);
+            gitmodules += &format!(
+                "[submodule \"{}\"]\n\tpath = {}\n\turl = {}\n",
+                path,
+                path,
+                url.to_string_lossy()
+            );
+            git(
+                root,
+                &[
+                    "update-index",
+                    "--add",
+                    "--cacheinfo",
+                    &format!("160000,{},{}", commit, path),
+                ],
+            )
+            .unwrap();
+            fs::create_dir_all(root.join(&path)).unwrap();
+        }
+        fs::write(root.join(GITMODULES_FILE), gitmodules).unwrap();
+    }
+
+    #[test]
+    fn parses_gitmodules() {
+        let text = "\
+# A comment
+[core]
+\tpath = not-a-submodule
+[submodule \"submodules/fzf\"]
+\tpath = submodules/fzf
+\turl = \"https://github.com/junegunn/fzf.git\" ; trailing comment
+\tBranch = master
+\tfollow = tags
+\ttarget = .fzf
+#[submodule \"commented-out\"]
+#\tpath = commented-out
+";
+        assert_eq!(
+            parse(Path::new(".gitmodules"), text).unwrap(),
+            [Submodule {
+                name: "submodules/fzf".to_string(),
+                path: PathBuf::from("submodules/fzf"),
+                url: "https://github.com/junegunn/fzf.git".to_string(),
+                branch: Some("master".to_string()),
+                follow: Follow::Tags,
+                target: Some(PathBuf::from(".fzf")),
+            }]
+        );
+    }
+
+    #[test]
+    fn submodules_need_a_path_and_url() {
+        let text = "[submodule \"foo\"]\n\tpath = foo\n";
+        assert!(matches!(
+            parse(Path::new(".gitmodules"), text),
+            Err(InstallError::Parse { line: 1, message, .. }) if message == "submodule foo has no url"
+        ));
+    }
+
+    #[test]
+    fn targets_must_stay_in_the_home_directory() {
+        let text = "[submodule \"foo\"]\n\tpath = foo\n\turl = foo\n[submodule \"bar\"]\n\tpath = bar\n\turl = bar\n\ttarget = ../bar\n";
+        assert!(matches!(
+            parse(Path::new(".gitmodules"), text),
+            Err(InstallError::Parse { line: 4, message, .. })
+                if message == "submodule bar has a bad target: ../bar must be a relative path without any `.` or `..` in it"
+        ));
+    }
+
+    #[test]
+    fn init_missing_only_clones_whats_missing_and_works_offline() -> io::Result<()> {
+        let upstream_dir = Fixture::new()?;
+        let root_dir = Fixture::new()?;
+        let root = root_dir.path();
+        let (foo_url, foo_first, foo_second) = make_upstream(upstream_dir.path(), "foo");
+        let (bar_url, bar_first, _) = make_upstream(upstream_dir.path(), "bar");
+        make_superproject(root, &[("foo", &foo_url, &foo_first), ("bar", &bar_url, &bar_first)]);
+        let states = || -> Vec<_> { status(root).unwrap().into_iter().map(|status| status.state).collect() };
+        assert_eq!(states(), [SubmoduleState::Uninitialized, SubmoduleState::Uninitialized]);
+
+        init_missing(root).unwrap();
+        assert_eq!(states(), [SubmoduleState::Pinned, SubmoduleState::Pinned]);
+        assert_eq!(fs::read_to_string(root.join("submodules/foo/file"))?, "first");
+        let statuses = status(root).unwrap();
+        assert_eq!(statuses[0].head.as_ref(), Some(&foo_first));
+        assert!(!statuses[0].dirty);
+        assert!(git(root, &["submodule", "status"]).unwrap().starts_with(' '));
+
+        // Moving a submodule and making changes in it is left alone.
+        let foo = root.join("submodules/foo");
+        git(&foo, &["checkout", "--quiet", &foo_second]).unwrap();
+        fs::write(foo.join("file"), "changed")?;
+        fs::remove_dir_all(root.join("submodules/bar"))?;
+        fs::create_dir(root.join("submodules/bar"))?;
+        fs::rename(&foo_url, upstream_dir.path().join("gone"))?;
+
+        init_missing(root).unwrap();
+        let statuses = status(root).unwrap();
+        assert_eq!(statuses[0].state, SubmoduleState::Moved { ahead: 1, behind: 0 });
+        assert!(statuses[0].dirty);
+        assert_eq!(fs::read_to_string(foo.join("file"))?, "changed");
+        assert_eq!(statuses[1].state, SubmoduleState::Pinned);
+        Ok(())
+    }
+
+    #[test]
+    fn submodules_keep_working_when_the_repo_moves() -> io::Result<()> {
+        let upstream_dir = Fixture::new()?;
+        let root_dir = Fixture::new()?;
+        let (url, first, _) = make_upstream(upstream_dir.path(), "foo");
+        let old = root_dir.path().join("old");
+        fs::create_dir(&old)?;
+        make_superproject(&old, &[("foo", &url, &first)]);
+        init_missing(&old).unwrap();
+        let git_file = fs::read_to_string(old.join("submodules/foo/.git"))?;
+        assert_eq!(git_file, "gitdir: ../../.git/modules/submodules/foo\n");
+
+        let new = root_dir.path().join("new");
+        fs::rename(&old, &new)?;
+        let statuses = status(&new).unwrap();
+        assert_eq!(statuses[0].state, SubmoduleState::Pinned);
+        assert!(!statuses[0].dirty);
+
+        // The clone that's left when the checkout is gone is reused, and moves along too.
+        fs::remove_dir_all(new.join("submodules/foo"))?;
+        init_missing(&new).unwrap();
+        fs::rename(&new, &old)?;
+        init_missing(&old).unwrap();
+        assert_eq!(status(&old).unwrap()[0].state, SubmoduleState::Pinned);
+        assert_eq!(fs::read_to_string(old.join("submodules/foo/file"))?, "first");
+        Ok(())
+    }
+
+    #[test]
+    fn fix_moved_repairs_absolute_paths() -> io::Result<()> {
+        let upstream_dir = Fixture::new()?;
+        let root_dir = Fixture::new()?;
+        let (url, first, _) = make_upstream(upstream_dir.path(), "foo");
+        let old = root_dir.path().join("old");
+        fs::create_dir(&old)?;
+        make_superproject(&old, &[("foo", &url, &first)]);
+        init_missing(&old).unwrap();
+        // Make the paths absolute like older versions of the installer did.
+        let git_dir = old.join(".git/modules/submodules/foo");
+        fs::write(
+            old.join("submodules/foo/.git"),
+            format!("gitdir: {}\n", git_dir.to_string_lossy()),
+        )?;
+        let foo = old.join("submodules/foo");
+        git(&foo, &[OsStr::new("config"), "core.worktree".as_ref(), foo.as_os_str()]).unwrap();
+
+        let new = root_dir.path().join("new");
+        fs::rename(&old, &new)?;
+        assert!(status(&new).is_err());
+        fix_moved(&new).unwrap();
+
+        assert_eq!(status(&new).unwrap()[0].state, SubmoduleState::Pinned);
+        assert!(!status(&new).unwrap()[0].dirty);
+        Ok(())
+    }
+
+    #[test]
+    fn update_moves_to_the_tip_of_the_branch_and_stages_it() -> io::Result<()> {
+        let upstream_dir = Fixture::new()?;
+        let root_dir = Fixture::new()?;
+        let root = root_dir.path();
+        let (url, first, second) = make_upstream(upstream_dir.path(), "foo");
+        make_superproject(root, &[("foo", &url, &first)]);
+        init_missing(root).unwrap();
+        let submodule = select(root, &["foo".to_string()]).unwrap().remove(0);
+
+        let update = submodule.fetch_update(root).unwrap();
+        assert_eq!(update.rev, "origin/main");
+        assert_eq!((update.old.as_ref(), &update.new), (Some(&first), &second));
+        assert_eq!(update.log.len(), 1);
+        assert!(update.log[0].ends_with(" second"));
+        assert!(!update.is_up_to_date());
+
+        submodule.apply_update(root, &update).unwrap();
+        assert_eq!(submodule.pinned_commit(root).unwrap(), Some(second));
+        assert_eq!(submodule.status(root).unwrap().state, SubmoduleState::Pinned);
+        assert!(submodule.fetch_update(root).unwrap().is_up_to_date());
+        Ok(())
+    }
+
+    #[test]
+    fn update_moves_to_the_newest_tag_that_isnt_a_release_candidate() -> io::Result<()> {
+        let upstream_dir = Fixture::new()?;
+        let root_dir = Fixture::new()?;
+        let root = root_dir.path();
+        let (url, first, second) = make_upstream(upstream_dir.path(), "foo");
+        for (tag, commit) in [("v1.9", &second), ("v1.10", &first), ("v1.11-rc1", &second)] {
+            git(&url, &["tag", tag, commit]).unwrap();
+        }
+        make_superproject(root, &[("foo", &url, &second)]);
+        init_missing(root).unwrap();
+        let mut submodule = select(root, &["foo".to_string()]).unwrap().remove(0);
+        submodule.follow = Follow::Tags;
+
+        let update = submodule.fetch_update(root).unwrap();
+        assert_eq!(update.rev, "v1.10");
+        assert_eq!((update.old.as_ref(), &update.new), (Some(&second), &first));
+        Ok(())
+    }
+
+    #[test]
+    fn select_finds_submodules_by_name_path_or_dir_name() -> io::Result<()> {
+        let root = Fixture::new()?;
+        fs::write(
+            root.path().join(GITMODULES_FILE),
+            "[submodule \"fzf\"]\n\tpath = submodules/fzf\n\turl = /fzf\n",
+        )?;
+        for name in ["fzf", "submodules/fzf"] {
+            assert_eq!(select(root.path(), &[name.to_string()]).unwrap().len(), 1);
+        }
+        assert!(matches!(
+            select(root.path(), &["nope".to_string()]),
+            Err(InstallError::SubmoduleNotFound(name)) if name == "nope"
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,645 @@
+use std::{
+    ffi::OsStr,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{InstallError, Result};
+use crate::git::{git, git_interactive, git_succeeds};
+
+/// The branch that every computer shares. Any other branch is a computer's own branch.
+pub const MAIN_BRANCH: &str = "main";
+
+/// The file in the git directory where a sync keeps track of what it's doing, so that the next sync
+/// can clean up if it gets interrupted.
+const STATE_FILE: &str = "dotfiles-sync.toml";
+
+/// The directory in the git directory where main gets checked out while a sync works on it. Syncs
+/// never switch branches in the repo itself, so an interrupted sync can't leave it on main.
+const WORKTREE_DIR: &str = "dotfiles-sync-main";
+
+/// The files in the git directory that git leaves while it's in the middle of something, and what to
+/// do about each of them.
+const IN_PROGRESS: &[(&str, &str)] = &[
+    (
+        "MERGE_HEAD",
+        "a merge is in progress. Finish it with `git merge --continue` or `git merge --abort`",
+    ),
+    (
+        "rebase-merge",
+        "a rebase is in progress. Finish it with `git rebase --continue` or `git rebase --abort`",
+    ),
+    (
+        "rebase-apply",
+        "a rebase is in progress. Finish it with `git rebase --continue` or `git rebase --abort`",
+    ),
+    (
+        "CHERRY_PICK_HEAD",
+        "a cherry-pick is in progress. Finish it with `git cherry-pick --continue` or `git cherry-pick --abort`",
+    ),
+    (
+        "REVERT_HEAD",
+        "a revert is in progress. Finish it with `git revert --continue` or `git revert --abort`",
+    ),
+    (
+        "BISECT_LOG",
+        "a bisect is in progress. Finish it with `git bisect reset`",
+    ),
+];
+
+/// What a sync is in the middle of.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct State {
+    /// The sync command, like `merge-main`.
+    command: String,
+    /// The branch that was checked out when the sync started.
+    branch: String,
+    /// The message of the stash with the changes that the sync set aside, if it set any aside.
+    stash: Option<String>,
+    /// Whether the stashed changes have been committed on main, so they don't have to be put back.
+    committed: bool,
+}
+
+impl State {
+    fn new(command: &str, branch: &str) -> Self {
+        Self {
+            command: command.to_string(),
+            branch: branch.to_string(),
+            stash: None,
+            committed: false,
+        }
+    }
+}
+
+/// Commits the changes to `files` on main, or all changes if `files` is empty, and then merges main
+/// into the current branch of the repository at `root`. The commit gets `message`, or git asks for
+/// one. If the changes don't apply to main or the commit is aborted, nothing is committed and the
+/// changes are put back where they were.
+pub fn commit_on_main(root: &Path, files: &[PathBuf], message: Option<&str>) -> Result<()> {
+    let branch = prepare(root)?;
+    if branch == MAIN_BRANCH {
+        return Err(InstallError::NotSafeToSync(format!(
+            "{} is checked out. Just use git directly",
+            MAIN_BRANCH
+        )));
+    }
+    // git can't stash changes in the files of a submodule, only which commit it has checked out.
+    let mut status = vec![
+        OsStr::new("status"),
+        "--porcelain".as_ref(),
+        "--ignore-submodules=dirty".as_ref(),
+        "--".as_ref(),
+    ];
+    status.extend(files.iter().map(|file| file.as_os_str()));
+    if git(root, &status)?.is_empty() {
+        log::warn!("There are no changes to commit");
+        return Ok(());
+    }
+
+    let mut state = State::new("commit-on-main", &branch);
+    if files.is_empty() {
+        log::info!("Stashing all changes");
+    } else {
+        log::info!(
+            "Stashing changes to {}",
+            files
+                .iter()
+                .map(|file| file.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+    }
+    if !stash(root, &mut state, files)? {
+        clear_state(root)?;
+        log::warn!("There are no changes to commit");
+        return Ok(());
+    }
+
+    let committed = with_main_worktree(root, |worktree| {
+        let stash = find_stash(root, &state)?.expect("The stash that was just made is gone");
+        log::info!("Applying the changes to {}", MAIN_BRANCH);
+        if !git_succeeds(worktree, &["stash", "apply", "--quiet", &stash])? {
+            return Ok(false);
+        }
+        git(worktree, &["add", "--all"])?;
+        log::info!("Committing on {}", MAIN_BRANCH);
+        match message {
+            Some(message) => git(worktree, &["commit", "--quiet", "-m", message]).map(|_| ())?,
+            None => git_interactive(worktree, &["commit", "--quiet"])?,
+        }
+        Ok(true)
+    });
+    match committed {
+        Ok(true) => {}
+        Ok(false) => {
+            unstash(root, &state)?;
+            clear_state(root)?;
+            return Err(InstallError::SyncConflict(format!(
+                "The changes conflict with {}, so nothing was committed. They're back where they were. Run `sync \
+                 merge-main` first",
+                MAIN_BRANCH
+            )));
+        }
+        Err(e) => {
+            log::info!("Putting the changes back");
+            unstash(root, &state)?;
+            clear_state(root)?;
+            return Err(e);
+        }
+    }
+
+    state.committed = true;
+    save_state(root, &state)?;
+    if let Some(stash) = find_stash(root, &state)? {
+        git(root, &["stash", "drop", "--quiet", &stash])?;
+    }
+    clear_state(root)?;
+    merge_main_into(root, &branch, &state.command)
+}
+
+/// Pulls main and merges it into the current branch of the repository at `root`. Changes that haven't
+/// been committed are stashed first and put back afterwards.
+pub fn merge_main(root: &Path) -> Result<()> {
+    let branch = prepare(root)?;
+    if branch == MAIN_BRANCH {
+        return Err(InstallError::NotSafeToSync(format!(
+            "{} is checked out. Just use `git pull` directly",
+            MAIN_BRANCH
+        )));
+    }
+
+    let mut state = State::new("merge-main", &branch);
+    if git(root, &["status", "--porcelain", "--ignore-submodules=dirty"])?.is_empty() {
+        save_state(root, &state)?;
+    } else {
+        log::info!("Stashing all changes");
+        stash(root, &mut state, &[])?;
+    }
+
+    log::info!("Pulling {}", MAIN_BRANCH);
+    if let Err(e) = with_main_worktree(root, |worktree| {
+        git_interactive(worktree, &["pull", "--quiet", "--rebase"])
+    }) {
+        unstash(root, &state)?;
+        clear_state(root)?;
+        return Err(e);
+    }
+
+    match merge_main_into(root, &branch, &state.command) {
+        Ok(()) => {}
+        Err(InstallError::SyncConflict(message)) if state.stash.is_some() => {
+            // Keep the state, so that the next sync puts the changes back once the merge is done.
+            return Err(InstallError::SyncConflict(format!(
+                "{}. Then run `sync merge-main` again to put back the changes that were stashed",
+                message
+            )));
+        }
+        Err(e) => {
+            unstash(root, &state)?;
+            clear_state(root)?;
+            return Err(e);
+        }
+    }
+
+    if state.stash.is_some() {
+        log::info!("Putting back the stashed changes");
+    }
+    unstash(root, &state)?;
+    clear_state(root)
+}
+
+/// Pushes the current branch of the repository at `root` and main to `remote`.
+pub fn push(root: &Path, remote: &str) -> Result<()> {
+    let branch = prepare(root)?;
+    let url = git(root, &["remote", "get-url", "--push", remote])?;
+
+    let mut branches = vec![branch.as_str()];
+    if branch != MAIN_BRANCH {
+        branches.push(MAIN_BRANCH);
+    }
+    for branch in branches {
+        log::info!("Pushing {} to {} ({})", branch, remote, url);
+        git_interactive(root, &["push", "--quiet", remote, branch])?;
+    }
+    Ok(())
+}
+
+/// Checks that the repository at `root` is in a state where it's safe to sync, and cleans up after
+/// the last sync if it was interrupted. Returns the current branch.
+fn prepare(root: &Path) -> Result<String> {
+    if !git_succeeds(root, &["symbolic-ref", "--quiet", "HEAD"])? {
+        return Err(InstallError::NotSafeToSync(
+            "HEAD is detached. Check out a branch first".to_string(),
+        ));
+    }
+    let branch = git(root, &["symbolic-ref", "--short", "HEAD"])?;
+    if let Some(reason) = in_progress(root)? {
+        return Err(InstallError::NotSafeToSync(reason.to_string()));
+    }
+    if !git(root, &["ls-files", "--unmerged"])?.is_empty() {
+        return Err(InstallError::NotSafeToSync(
+            "some files have conflicts. Fix them and `git add` them first".to_string(),
+        ));
+    }
+    let main_ref = format!("refs/heads/{}", MAIN_BRANCH);
+    if !git_succeeds(root, &["rev-parse", "--verify", "--quiet", &main_ref])? {
+        return Err(InstallError::NotSafeToSync(format!(
+            "there's no {} branch",
+            MAIN_BRANCH
+        )));
+    }
+
+    if let Some(state) = load_state(root)? {
+        recover(root, &branch, &state)?;
+    }
+    Ok(branch)
+}
+
+/// Finds out whether git is in the middle of something in the repository at `root`, and returns
+/// what to do about it if it is.
+fn in_progress(root: &Path) -> Result<Option<&'static str>> {
+    for (file, reason) in IN_PROGRESS {
+        if git_path(root, file)?.symlink_metadata().is_ok() {
+            return Ok(Some(reason));
+        }
+    }
+    Ok(None)
+}
+
+/// Cleans up after a sync that was interrupted, as described by `state`. Changes that it stashed are
+/// put back, unless they've already been committed on main.
+fn recover(root: &Path, branch: &str, state: &State) -> Result<()> {
+    log::warn!("The last sync {} didn't finish, cleaning up after it", state.command);
+    remove_worktree(root, &git_path(root, WORKTREE_DIR)?)?;
+
+    if let Some(stash) = find_stash(root, state)? {
+        if state.branch != branch {
+            return Err(InstallError::NotSafeToSync(format!(
+                "the last sync stashed changes on {}. Switch back to it first",
+                state.branch
+            )));
+        }
+        if state.committed {
+            log::info!("Dropping {}, since it has been committed on {}", stash, MAIN_BRANCH);
+            git(root, &["stash", "drop", "--quiet", &stash])?;
+        } else {
+            log::info!("Putting back the changes in {}", stash);
+            unstash(root, state)?;
+        }
+    }
+    clear_state(root)
+}
+
+/// Stashes the changes to `files`, or all changes if `files` is empty, including untracked files.
+/// The stash is saved in `state` first, so that it can be found again if the sync gets interrupted.
+/// Returns whether there was anything to stash. If there wasn't, `state` is left without a stash.
+fn stash(root: &Path, state: &mut State, files: &[PathBuf]) -> Result<bool> {
+    let message = format!(
+        "Stash for sync {} on {} at {}",
+        state.command,
+        state.branch,
+        Local::now().format("%F %T%.f")
+    );
+    state.stash = Some(message.clone());
+    save_state(root, state)?;
+
+    let mut args = vec![
+        OsStr::new("stash"),
+        "push".as_ref(),
+        "--quiet".as_ref(),
+        "--include-untracked".as_ref(),
+        "-m".as_ref(),
+        message.as_ref(),
+        "--".as_ref(),
+    ];
+    args.extend(files.iter().map(|file| file.as_os_str()));
+    git(root, &args)?;
+
+    // git doesn't make a stash if the only changes are ones it can't stash, like untracked files in
+    // a submodule.
+    if find_stash(root, state)?.is_none() {
+        state.stash = None;
+        save_state(root, state)?;
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Finds the stash that `state` made, like `stash@{0}`, if it made one and it's still there.
+fn find_stash(root: &Path, state: &State) -> Result<Option<String>> {
+    let Some(message) = &state.stash else {
+        return Ok(None);
+    };
+    Ok(git(root, &["stash", "list", "--format=%gd%x09%gs"])?
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .find(|(_, subject)| subject.ends_with(message.as_str()))
+        .map(|(stash, _)| stash.to_string()))
+}
+
+/// Puts back the changes in the stash that `state` made, if there is one. If they conflict with
+/// what's checked out, the conflicts are left for the user to fix and the stash is kept.
+fn unstash(root: &Path, state: &State) -> Result<()> {
+    let Some(stash) = find_stash(root, state)? else {
+        return Ok(());
+    };
+    match git(root, &["stash", "pop", "--quiet", &stash]) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            clear_state(root)?;
+            if git(root, &["ls-files", "--unmerged"])?.is_empty() {
+                log::error!("{}", e);
+                Err(InstallError::SyncConflict(format!(
+                    "Couldn't put back the changes in {}. They're still there, apply them with `git stash pop`",
+                    stash
+                )))
+            } else {
+                Err(InstallError::SyncConflict(format!(
+                    "The changes in {} conflict with {}. Fix the conflicts, then run `git reset` and `git stash \
+                     drop`",
+                    stash, state.branch
+                )))
+            }
+        }
+    }
+}
+
+/// Merges main into `branch`, which is checked out in the repository at `root`.
+fn merge_main_into(root: &Path, branch: &str, command: &str) -> Result<()> {
+    let subject = git(root, &["log", "--format=%s", "--max-count=1", MAIN_BRANCH])?;
+    log::info!("Merging {} into {}", MAIN_BRANCH, branch);
+    let message = format!("Automatic merge of '{}' by sync {}", subject, command);
+    match git(root, &["merge", "--quiet", "--no-edit", "-m", &message, MAIN_BRANCH]) {
+        Ok(_) => Ok(()),
+        Err(_) if in_progress(root)?.is_some() => Err(InstallError::SyncConflict(format!(
+            "Merging {} into {} ran into conflicts. Fix them and run `git merge --continue`",
+            MAIN_BRANCH, branch
+        ))),
+        Err(e) => Err(e),
+    }
+}
+
+/// Checks out main in a worktree of its own and runs `f` in it. The worktree is removed afterwards.
+fn with_main_worktree<T, F>(root: &Path, f: F) -> Result<T>
+where
+    F: FnOnce(&Path) -> Result<T>,
+{
+    let worktree = git_path(root, WORKTREE_DIR)?;
+    remove_worktree(root, &worktree)?;
+    git(
+        root,
+        &[
+            OsStr::new("worktree"),
+            "add".as_ref(),
+            "--quiet".as_ref(),
+            worktree.as_os_str(),
+            MAIN_BRANCH.as_ref(),
+        ],
+    )?;
+    let result = f(&worktree);
+    let removed = remove_worktree(root, &worktree);
+    let result = result?;
+    removed.map(|_| result)
+}
+
+/// Removes the worktree at `worktree` if it's there, along with anything in it.
+fn remove_worktree(root: &Path, worktree: &Path) -> Result<()> {
+    if worktree.exists() {
+        git(
+            root,
+            &[
+                OsStr::new("worktree"),
+                "remove".as_ref(),
+                "--force".as_ref(),
+                worktree.as_os_str(),
+            ],
+        )?;
+    }
+    // Forget about the worktree if its directory has been deleted some other way.
+    git(root, &["worktree", "prune"])?;
+    Ok(())
+}
+
+/// Gets the absolute path of `name` in the git directory of the repository at `root`.
+fn git_path(root: &Path, name: &str) -> Result<PathBuf> {
+    Ok(PathBuf::from(git(
+        root,
+        &["rev-parse", "--path-format=absolute", "--git-path", name],
+    )?))
+}
+
+fn load_state(root: &Path) -> Result<Option<State>> {
+    let path = git_path(root, STATE_FILE)?;
+    match fs::read_to_string(&path) {
+        Ok(text) => toml::from_str(&text).map(Some).map_err(|e| {
+            let offset = e.span().map_or(0, |span| span.start);
+            InstallError::parse(&path, &text, offset, e.message())
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(InstallError::io("read", &path)(e)),
+    }
+}
+
+fn save_state(root: &Path, state: &State) -> Result<()> {
+    let path = git_path(root, STATE_FILE)?;
+    let text = toml::to_string(state).expect("Failed to serialize the sync state");
+    fs::write(&path, text).map_err(InstallError::io("write", &path))
+}
+
+fn clear_state(root: &Path) -> Result<()> {
+    let path = git_path(root, STATE_FILE)?;
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(InstallError::io("remove", &path)(e)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use super::*;
+    use crate::test_util::Fixture;
+
+    fn commit(dir: &Path, file: &str, contents: &str) {
+        fs::write(dir.join(file), contents).unwrap();
+        git(dir, &["add", file]).unwrap();
+        git(dir, &["commit", "--quiet", "-m", contents]).unwrap();
+    }
+
+    /// Clones `origin` into `dir/name` and sets up a committer.
+    fn clone(dir: &Path, origin: &Path, name: &str) -> PathBuf {
+        let clone = dir.join(name);
+        git(
+            dir,
+            &[
+                OsStr::new("clone"),
+                "--quiet".as_ref(),
+                origin.as_os_str(),
+                clone.as_os_str(),
+            ],
+        )
+        .unwrap();
+        git(&clone, &["config", "user.name", "Test"]).unwrap();
+        git(&clone, &["config", "user.email", "test@example.com"]).unwrap();
+        clone
+    }
+
+    /// Makes a bare repo at `dir/origin.git` with two files on main, and a clone of it at
+    /// `dir/laptop` that has a local branch called `laptop` checked out. Returns the paths to both.
+    fn make_repos(dir: &Path) -> (PathBuf, PathBuf) {
+        let origin = dir.join("origin.git");
+        git(
+            dir,
+            &[
+                OsStr::new("init"),
+                "--quiet".as_ref(),
+                "--bare".as_ref(),
+                "--initial-branch=main".as_ref(),
+                origin.as_os_str(),
+            ],
+        )
+        .unwrap();
+        let laptop = clone(dir, &origin, "laptop");
+        git(&laptop, &["symbolic-ref", "HEAD", "refs/heads/main"]).unwrap();
+        commit(&laptop, "a", "a");
+        commit(&laptop, "b", "b");
+        git(&laptop, &["push", "--quiet", "--set-upstream", "origin", "main"]).unwrap();
+        git(&laptop, &["switch", "--quiet", "--create", "laptop"]).unwrap();
+        commit(&laptop, "local", "local");
+        (origin, laptop)
+    }
+
+    #[test]
+    fn commit_on_main_commits_only_the_given_files() -> io::Result<()> {
+        let dir = Fixture::new()?;
+        let (_, laptop) = make_repos(dir.path());
+        fs::write(laptop.join("a"), "changed")?;
+        fs::write(laptop.join("b"), "changed")?;
+        fs::write(laptop.join("new"), "new")?;
+
+        commit_on_main(&laptop, &[laptop.join("a"), laptop.join("new")], Some("Change a")).unwrap();
+
+        let committed = git(&laptop, &["show", "--name-only", "--format=", MAIN_BRANCH]).unwrap();
+        assert_eq!(committed.lines().collect::<Vec<_>>(), ["a", "new"]);
+        assert_eq!(git(&laptop, &["branch", "--show-current"]).unwrap(), "laptop");
+        assert!(git_succeeds(&laptop, &["merge-base", "--is-ancestor", MAIN_BRANCH, "laptop"]).unwrap());
+        assert_eq!(fs::read_to_string(laptop.join("a"))?, "changed");
+        assert_eq!(git(&laptop, &["status", "--porcelain"]).unwrap(), " M b");
+        assert_eq!(git(&laptop, &["stash", "list"]).unwrap(), "");
+        assert!(!git_path(&laptop, WORKTREE_DIR).unwrap().exists());
+        Ok(())
+    }
+
+    #[test]
+    fn commit_on_main_ignores_changes_it_cant_stash() -> io::Result<()> {
+        let dir = Fixture::new()?;
+        let (_, laptop) = make_repos(dir.path());
+        let sub = laptop.join("sub");
+        git(&laptop, &["init", "--quiet", "sub"]).unwrap();
+        git(&sub, &["config", "user.name", "Test"]).unwrap();
+        git(&sub, &["config", "user.email", "test@example.com"]).unwrap();
+        commit(&sub, "a", "a");
+        git(&laptop, &["add", "sub"]).unwrap();
+        git(&laptop, &["commit", "--quiet", "-m", "Add sub"]).unwrap();
+        fs::write(sub.join("untracked"), "untracked")?;
+        let main = git(&laptop, &["rev-parse", MAIN_BRANCH]).unwrap();
+
+        commit_on_main(&laptop, &[], Some("Nothing")).unwrap();
+        assert_eq!(git(&laptop, &["rev-parse", MAIN_BRANCH]).unwrap(), main);
+        assert_eq!(load_state(&laptop).unwrap(), None);
+
+        // Even if git only finds out that there's nothing to stash when it tries.
+        let mut state = State::new("commit-on-main", "laptop");
+        assert!(!stash(&laptop, &mut state, &[]).unwrap());
+        assert_eq!(
+            load_state(&laptop).unwrap(),
+            Some(State::new("commit-on-main", "laptop"))
+        );
+
+        assert_eq!(git(&laptop, &["stash", "list"]).unwrap(), "");
+        assert_eq!(git(&laptop, &["status", "--porcelain"]).unwrap(), " M sub");
+        assert!(sub.join("untracked").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn commit_on_main_puts_back_changes_that_conflict() -> io::Result<()> {
+        let dir = Fixture::new()?;
+        let (_, laptop) = make_repos(dir.path());
+        git(&laptop, &["switch", "--quiet", MAIN_BRANCH]).unwrap();
+        commit(&laptop, "a", "theirs");
+        git(&laptop, &["switch", "--quiet", "laptop"]).unwrap();
+        let main = git(&laptop, &["rev-parse", MAIN_BRANCH]).unwrap();
+        fs::write(laptop.join("a"), "mine")?;
+
+        let result = commit_on_main(&laptop, &[], Some("Change a"));
+
+        assert!(matches!(result, Err(InstallError::SyncConflict(_))));
+        assert_eq!(git(&laptop, &["rev-parse", MAIN_BRANCH]).unwrap(), main);
+        assert_eq!(fs::read_to_string(laptop.join("a"))?, "mine");
+        assert_eq!(git(&laptop, &["stash", "list"]).unwrap(), "");
+        assert!(load_state(&laptop).unwrap().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn merge_main_pulls_and_keeps_uncommitted_changes() -> io::Result<()> {
+        let dir = Fixture::new()?;
+        let (origin, laptop) = make_repos(dir.path());
+        let desktop = clone(dir.path(), &origin, "desktop");
+        commit(&desktop, "a", "from the desktop");
+        git(&desktop, &["push", "--quiet"]).unwrap();
+        fs::write(laptop.join("b"), "changed")?;
+        fs::write(laptop.join("new"), "new")?;
+
+        merge_main(&laptop).unwrap();
+
+        assert_eq!(fs::read_to_string(laptop.join("a"))?, "from the desktop");
+        assert_eq!(fs::read_to_string(laptop.join("b"))?, "changed");
+        assert_eq!(fs::read_to_string(laptop.join("new"))?, "new");
+        assert_eq!(git(&laptop, &["stash", "list"]).unwrap(), "");
+        Ok(())
+    }
+
+    #[test]
+    fn interrupted_sync_is_cleaned_up_and_refused_mid_merge() -> io::Result<()> {
+        let dir = Fixture::new()?;
+        let (origin, laptop) = make_repos(dir.path());
+        fs::write(laptop.join("b"), "changed")?;
+        let mut state = State::new("merge-main", "laptop");
+        stash(&laptop, &mut state, &[]).unwrap();
+        let worktree = git_path(&laptop, WORKTREE_DIR).unwrap();
+        git(
+            &laptop,
+            &[
+                OsStr::new("worktree"),
+                "add".as_ref(),
+                "--quiet".as_ref(),
+                worktree.as_os_str(),
+                MAIN_BRANCH.as_ref(),
+            ],
+        )
+        .unwrap();
+
+        push(&laptop, "origin").unwrap();
+
+        assert_eq!(fs::read_to_string(laptop.join("b"))?, "changed");
+        assert_eq!(git(&laptop, &["stash", "list"]).unwrap(), "");
+        assert!(!worktree.exists());
+        assert!(load_state(&laptop).unwrap().is_none());
+        assert!(git_succeeds(&origin, &["rev-parse", "--verify", "--quiet", "refs/heads/laptop"]).unwrap());
+
+        git(&laptop, &["commit", "--quiet", "--all", "-m", "b"]).unwrap();
+        git(&laptop, &["switch", "--quiet", MAIN_BRANCH]).unwrap();
+        commit(&laptop, "b", "theirs");
+        git(&laptop, &["switch", "--quiet", "laptop"]).unwrap();
+        assert!(!git_succeeds(&laptop, &["merge", "--quiet", MAIN_BRANCH]).unwrap());
+        assert!(matches!(merge_main(&laptop), Err(InstallError::NotSafeToSync(_))));
+        Ok(())
+    }
+}
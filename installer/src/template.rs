@@ -0,0 +1,117 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use crate::error::{InstallError, Result};
+use crate::host::Host;
+use crate::path::*;
+
+/// The suffix of dotfiles that are templates. It's removed from the name of the rendered file.
+pub const TEMPLATE_SUFFIX: &str = ".tmpl";
+
+/// The names of the variables that are always defined.
+pub const BUILTIN_VARIABLES: &[&str] = &["hostname", "user", "home", "os", "profile"];
+
+/// The variables that can be used in templates.
+pub type Variables = BTreeMap<String, String>;
+
+/// Gets the variables that describe `host`. These are the ones in [`BUILTIN_VARIABLES`].
+pub fn builtin_variables(host: &Host, home_dir: &HomePath) -> Variables {
+    [
+        ("hostname", host.name.clone()),
+        ("user", host.user.clone()),
+        ("home", home_dir.to_string_lossy().into_owned()),
+        ("os", host.os.clone()),
+        ("profile", host.profile.clone()),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value))
+    .collect()
+}
+
+/// Renders `template` by replacing every `{{ name }}` in it with the value of the variable `name`.
+/// `path` is only used in error messages.
+pub fn render<P: AsRef<Path>>(path: P, template: &str, vars: &Variables) -> Result<String> {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let offset = template.len() - rest.len() + start;
+        let Some(len) = rest[start..].find("}}") else {
+            return Err(InstallError::parse(path, template, offset, "Unclosed `{{`"));
+        };
+        let name = rest[start + 2..start + len].trim();
+        match vars.get(name) {
+            Some(value) => output.push_str(value),
+            None => {
+                return Err(InstallError::parse(
+                    path,
+                    template,
+                    offset,
+                    format!("Unknown variable \"{}\"", name),
+                ))
+            }
+        }
+        rest = &rest[start + len + 2..];
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Removes [`TEMPLATE_SUFFIX`] from the end of `path`, if it's there.
+pub fn strip_suffix(path: &Path) -> PathBuf {
+    let name = path.file_name().map(OsStrExt::as_bytes).unwrap_or_default();
+    match name.strip_suffix(TEMPLATE_SUFFIX.as_bytes()) {
+        Some(stem) if !stem.is_empty() => path.with_file_name(OsStr::from_bytes(stem)),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Reads and renders the template `template`.
+pub fn render_file(template: &Dotfile, vars: &Variables) -> Result<String> {
+    let path = template.to_path_buf();
+    let text = fs::read_to_string(&path).map_err(InstallError::io("read template", &path))?;
+    render(&path, &text, vars)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn vars() -> Variables {
+        [("user", "me"), ("home", "/home/me")]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn renders_variables() {
+        assert_eq!(
+            render("t", "name = {{user}}\npath = {{ home }}/bin\n", &vars()).unwrap(),
+            "name = me\npath = /home/me/bin\n"
+        );
+    }
+
+    #[test]
+    fn unclosed_braces() {
+        assert_eq!(
+            render("t", "name = {{user}}\npath = {{ home }}/bin {{", &vars()).map_err(|e| e.to_string()),
+            Err("t:2:23: Unclosed `{{`".to_string())
+        );
+    }
+
+    #[test]
+    fn unknown_variable() {
+        assert_eq!(
+            render("t", "a\nb {{ email }}", &vars()).map_err(|e| e.to_string()),
+            Err("t:2:3: Unknown variable \"email\"".to_string())
+        );
+    }
+}
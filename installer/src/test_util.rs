@@ -0,0 +1,79 @@
+//! Helpers for the tests.
+
+use std::{fs, io, os::unix, path::Path};
+
+use tempfile::{Builder, TempDir};
+
+use crate::backup::{BackupStore, Run};
+use crate::logging;
+use crate::path::*;
+use crate::repo::Repo;
+
+/// A temporary directory that's deleted again when the test is done.
+pub(crate) struct Fixture(TempDir);
+
+impl Fixture {
+    /// Makes a temporary directory in the current directory.
+    pub fn new() -> io::Result<Self> {
+        Self::new_in(".")
+    }
+
+    /// Makes a temporary directory in `dir`. Its path is absolute and has no symlinks in it.
+    pub fn new_in<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        logging::init_test();
+        let temp_dir = Builder::new()
+            .prefix("dotfile-installer-test-tmp-")
+            .rand_bytes(8)
+            .tempdir_in(dir.as_ref().canonicalize()?)?;
+        log::debug!("Created temp dir {} for testing", temp_dir.path().to_string_lossy());
+        Ok(Self(temp_dir))
+    }
+
+    pub fn path(&self) -> &Path {
+        self.0.path()
+    }
+
+    /// The directory as a home directory.
+    pub fn home_dir(&self) -> HomePath {
+        HomePath::new(self.path())
+    }
+
+    /// The directory as a backup store.
+    pub fn store(&self) -> BackupStore {
+        BackupStore::new(self.path())
+    }
+
+    /// Makes an empty dotfile repo in `name`.
+    pub fn repo(&self, name: &str) -> io::Result<Repo> {
+        let repo = Repo::new(self.path().join(name));
+        fs::create_dir_all(repo.files().as_path())?;
+        Ok(repo)
+    }
+
+    pub fn file<T: FilePath>(&self, name: &str, contents: &str) -> io::Result<T> {
+        let path = T::new(&<T as FilePath>::Prefix::new(self.path()), &RelPath::new(name));
+        log::debug!("Creating temp file {} with contents '{}'", path, contents);
+        fs::write(path.to_path_buf(), contents)?;
+        Ok(path)
+    }
+
+    pub fn nonexistent_file<T: FilePath>(&self, name: &str) -> T {
+        T::new(&T::Prefix::new(self.path()), &RelPath::new(name))
+    }
+
+    pub fn prefix<T: Prefix>(&self) -> T {
+        T::new(self.path())
+    }
+
+    pub fn symlink<T: FilePath, P: AsRef<Path>>(&self, name: &str, target: P) -> io::Result<T> {
+        let path = T::new(&T::Prefix::new(self.path()), &RelPath::new(name));
+        log::debug!("Creating symlink {} -> {}", path, target.as_ref().to_string_lossy());
+        unix::fs::symlink(target, path.to_path_buf())?;
+        Ok(path)
+    }
+}
+
+/// A run to make test backups with.
+pub(crate) fn run() -> Run {
+    Run::new("test", &Repo::new("/dotfiles"))
+}
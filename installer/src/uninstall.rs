@@ -0,0 +1,111 @@
+use std::path::Path;
+
+use crate::backup::Backup;
+use crate::error::Result;
+use crate::fold;
+use crate::host::Host;
+use crate::install::{self, file_exists};
+use crate::manifest::{Link, LinkMode, Manifest};
+use crate::path::*;
+use crate::plan::{Action, Plan};
+use crate::repo::Repo;
+use crate::restore;
+use crate::status::{self, LinkState};
+use crate::template::Variables;
+
+/// Plans removing the links that the installer created in `home_dir` on `host`, and then restoring
+/// the files in `backup` if it's given.
+pub fn plan_uninstall(home_dir: &HomePath, repo: &Repo, host: &Host, backup: Option<&Backup>) -> Result<Plan> {
+    let manifest = Manifest::load(repo)?;
+    let links = install::find_links(repo, &manifest, host)?;
+    let vars = manifest.variables(host, home_dir);
+
+    let mut plan = Plan::new();
+    plan_unlink(&mut plan, home_dir, &links, &repo.owned_dirs(), &vars)?;
+    install::plan_unlink_old_submodule_link(&mut plan, home_dir, repo)?;
+
+    if let Some(backup) = backup {
+        restore::plan_restore(&mut plan, home_dir, backup, &repo.owned_dirs())?;
+    }
+
+    Ok(plan)
+}
+
+/// Plans removing the installed `links`. Symlinks count as installed if they point into any of
+/// `owned_dirs`, so that links from other layers are removed too. Folded directories are unlinked
+/// as a whole.
+fn plan_unlink(
+    plan: &mut Plan,
+    home_dir: &HomePath,
+    links: &[Link],
+    owned_dirs: &[&Path],
+    vars: &Variables,
+) -> Result<()> {
+    let folded = fold::folded_parents(home_dir, links, owned_dirs)?;
+    for dir in &folded {
+        plan.push(Action::Unlink(dir.clone()));
+    }
+
+    for link in links {
+        let file = home_dir.with_rel_file(&link.target);
+
+        if fold::is_inside_any(&file, &folded) {
+            log::debug!("Not unlinking {} because its folded directory is unlinked", file);
+        } else if !file_exists(&file) {
+            log::debug!("Not unlinking {} because it doesn't exist", file);
+        } else if link.mode == LinkMode::Symlink {
+            if restore::links_into_any(&file, owned_dirs)? {
+                plan.push(Action::Unlink(file));
+            } else {
+                log::warn!("Leaving {} alone because it wasn't created by the installer", file);
+            }
+        } else if status::installed_state(&file, &link.source, link.mode, vars)? == LinkState::Ok {
+            plan.push(Action::RemoveCopy(file));
+        } else {
+            log::warn!(
+                "Leaving {} alone because it wasn't created by the installer or has been changed since",
+                file
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io, os::unix};
+
+    use super::*;
+    use crate::test_util::Fixture;
+
+    #[test]
+    fn only_unlinks_owned_links() -> io::Result<()> {
+        let home = Fixture::new()?;
+        let home_dir = home.home_dir();
+        let file_root = DotfilesPath::new("/dotfiles/files");
+        let files = [
+            RelPath::new("owned"),
+            RelPath::new("foreign"),
+            RelPath::new("file"),
+            RelPath::new("missing"),
+        ];
+        let links: Vec<_> = files
+            .iter()
+            .map(|file| Link {
+                source: file_root.with_rel_file(file),
+                target: file.clone(),
+                mode: LinkMode::Symlink,
+            })
+            .collect();
+        unix::fs::symlink("/dotfiles/files/owned", home.path().join("owned"))?;
+        unix::fs::symlink("/somewhere/else", home.path().join("foreign"))?;
+        fs::write(home.path().join("file"), "foo")?;
+
+        let mut plan = Plan::new();
+        plan_unlink(&mut plan, &home_dir, &links, &[file_root.as_path()], &Variables::new()).unwrap();
+
+        assert_eq!(plan.actions(), [Action::Unlink(home_dir.with_rel_file(&files[0]))]);
+        Ok(())
+    }
+}
@@ -0,0 +1,466 @@
+use std::{
+    fs::{self, Permissions},
+    io,
+    os::unix::{self, fs::PermissionsExt},
+    path::Path,
+    process::Command,
+};
+
+use dotfile_installer::{
+    fold::FOLD_MARKER_FILE, install, relink, status, status::LinkState, uninstall, Action, BackupStore, FilePath,
+    HomePath, Host, Prefix, Repo,
+};
+use tempfile::{Builder, TempDir};
+
+fn temp_dir() -> io::Result<TempDir> {
+    Builder::new()
+        .prefix("dotfile-installer-test-tmp-")
+        .rand_bytes(8)
+        .tempdir_in(".")
+}
+
+/// Makes a dotfile repo with a couple of dotfiles in it.
+fn make_repo(root: &Path) -> io::Result<Repo> {
+    fs::create_dir_all(root.join("files/.config/foo"))?;
+    fs::create_dir_all(root.join("submodules"))?;
+    fs::create_dir_all(root.join("installer/scripts"))?;
+    fs::write(root.join("files/.zshrc"), "new")?;
+    fs::write(root.join("files/.config/foo/bar.toml"), "new")?;
+    let status = Command::new("git").arg("init").arg("-q").arg(root).status()?;
+    assert!(status.success());
+    Ok(Repo::new(root))
+}
+
+fn host() -> Host {
+    Host {
+        name: "host".to_string(),
+        os: "linux".to_string(),
+        user: "user".to_string(),
+        profile: "host".to_string(),
+    }
+}
+
+#[test]
+fn install_and_uninstall() -> io::Result<()> {
+    let repo_dir = temp_dir()?;
+    let home = temp_dir()?;
+    let repo = make_repo(&repo_dir.path().canonicalize()?)?;
+    let home_dir = HomePath::new(home.path().canonicalize()?);
+    let store = BackupStore::new(repo_dir.path().join("backups"));
+    fs::write(home.path().join(".zshrc"), "old")?;
+
+    install::plan_install(&home_dir, &repo, &host(), &store)
+        .unwrap()
+        .execute()
+        .unwrap();
+
+    assert_eq!(fs::read_to_string(home.path().join(".zshrc"))?, "new");
+    let statuses = status::status(&home_dir, &repo, &host()).unwrap();
+    assert_eq!(statuses.len(), 2);
+    assert!(statuses.iter().all(|status| status.state.is_ok()));
+
+    let backup = store.find_backup(None).unwrap();
+    uninstall::plan_uninstall(&home_dir, &repo, &host(), Some(&backup))
+        .unwrap()
+        .execute()
+        .unwrap();
+
+    assert_eq!(fs::read_to_string(home.path().join(".zshrc"))?, "old");
+    assert!(!home.path().join(".config/foo/bar.toml").exists());
+    let statuses = status::status(&home_dir, &repo, &host()).unwrap();
+    assert!(statuses
+        .iter()
+        .all(|status| matches!(status.state, LinkState::Missing | LinkState::ConflictFile)));
+    Ok(())
+}
+
+#[test]
+fn host_files_override_shared_files() -> io::Result<()> {
+    let repo_dir = temp_dir()?;
+    let home = temp_dir()?;
+    let repo = make_repo(&repo_dir.path().canonicalize()?)?;
+    let home_dir = HomePath::new(home.path().canonicalize()?);
+    let store = BackupStore::new(repo_dir.path().join("backups"));
+    fs::create_dir_all(repo.hosts().join("host/.config"))?;
+    fs::write(repo.hosts().join("host/.zshrc"), "host")?;
+    fs::write(repo.hosts().join("host/.config/foo"), "host")?;
+    fs::write(repo.hosts().join("host/.host-only"), "host")?;
+
+    install::plan_install(&home_dir, &repo, &host(), &store)
+        .unwrap()
+        .execute()
+        .unwrap();
+
+    assert_eq!(fs::read_to_string(home.path().join(".zshrc"))?, "host");
+    assert_eq!(fs::read_to_string(home.path().join(".config/foo"))?, "host");
+    let layers: Vec<_> = status::status(&home_dir, &repo, &host())
+        .unwrap()
+        .into_iter()
+        .map(|status| (status.file.rel_file().to_string(), status.layer))
+        .collect();
+    assert_eq!(
+        layers,
+        [
+            (".config/foo".to_string(), "hosts/host".to_string()),
+            (".host-only".to_string(), "hosts/host".to_string()),
+            (".zshrc".to_string(), "hosts/host".to_string()),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn folded_dirs_are_unfolded_when_shared() -> io::Result<()> {
+    let repo_dir = temp_dir()?;
+    let home = temp_dir()?;
+    let repo = make_repo(&repo_dir.path().canonicalize()?)?;
+    let home_dir = HomePath::new(home.path().canonicalize()?);
+    let store = BackupStore::new(repo_dir.path().join("backups"));
+    fs::write(repo.files().as_path().join(".config/foo").join(FOLD_MARKER_FILE), "")?;
+    fs::create_dir(home.path().join(".config"))?;
+
+    install::plan_install(&home_dir, &repo, &host(), &store)
+        .unwrap()
+        .execute()
+        .unwrap();
+
+    let foo = home.path().join(".config/foo");
+    assert_eq!(fs::read_link(&foo)?, repo.files().as_path().join(".config/foo"));
+
+    fs::create_dir_all(repo.hosts().join("host/.config/foo"))?;
+    fs::write(repo.hosts().join("host/.config/foo/local.toml"), "host")?;
+    install::plan_install(&home_dir, &repo, &host(), &store)
+        .unwrap()
+        .execute()
+        .unwrap();
+
+    assert!(!foo.is_symlink());
+    assert_eq!(fs::read_to_string(foo.join("bar.toml"))?, "new");
+    assert_eq!(fs::read_to_string(foo.join("local.toml"))?, "host");
+    assert!(!repo.files().as_path().join(".config/foo/local.toml").exists());
+    let statuses = status::status(&home_dir, &repo, &host()).unwrap();
+    assert!(statuses.iter().all(|status| status.state.is_ok()));
+
+    uninstall::plan_uninstall(&home_dir, &repo, &host(), None)
+        .unwrap()
+        .execute()
+        .unwrap();
+    assert!(!foo.join("bar.toml").exists());
+    Ok(())
+}
+
+#[test]
+fn reinstalling_leaves_installed_links_alone() -> io::Result<()> {
+    let repo_dir = temp_dir()?;
+    let home = temp_dir()?;
+    let repo = make_repo(&repo_dir.path().canonicalize()?)?;
+    let home_dir = HomePath::new(home.path().canonicalize()?);
+    let store = BackupStore::new(repo_dir.path().join("backups"));
+
+    install::plan_install(&home_dir, &repo, &host(), &store)
+        .unwrap()
+        .execute()
+        .unwrap();
+    assert!(store.backups().unwrap().is_empty());
+
+    let plan = install::plan_install(&home_dir, &repo, &host(), &store).unwrap();
+    assert!(!plan.changes_home());
+    plan.execute().unwrap();
+    assert!(store.backups().unwrap().is_empty());
+
+    // A link into the repo that points to the wrong dotfile is relinked without backing it up.
+    let zshrc = home.path().join(".zshrc");
+    fs::remove_file(&zshrc)?;
+    unix::fs::symlink(repo.files().as_path().join(".config/foo/bar.toml"), &zshrc)?;
+    let plan = install::plan_install(&home_dir, &repo, &host(), &store).unwrap();
+    assert!(!plan
+        .actions()
+        .iter()
+        .any(|action| matches!(action, Action::CreateBackup(..) | Action::Backup(..))));
+    plan.execute().unwrap();
+    assert_eq!(fs::read_link(&zshrc)?, repo.files().as_path().join(".zshrc"));
+    assert!(store.backups().unwrap().is_empty());
+    Ok(())
+}
+
+/// Installs the repo from `make_repo` into `home`, checks the result with `installed`, then
+/// uninstalls it again with the backup that the install made, if any.
+fn install_and_restore(home: &TempDir, installed: impl FnOnce(&Repo) -> io::Result<()>) -> io::Result<()> {
+    let repo_dir = temp_dir()?;
+    let repo = make_repo(&repo_dir.path().canonicalize()?)?;
+    let home_dir = HomePath::new(home.path().canonicalize()?);
+    let store = BackupStore::new(repo_dir.path().join("backups"));
+
+    install::plan_install(&home_dir, &repo, &host(), &store)
+        .unwrap()
+        .execute()
+        .unwrap();
+    installed(&repo)?;
+    let statuses = status::status(&home_dir, &repo, &host()).unwrap();
+    assert!(statuses.iter().all(|status| status.state.is_ok()));
+
+    let backup = store.backups().unwrap().pop();
+    if let Some(backup) = &backup {
+        assert!(backup.verify().unwrap().is_empty());
+    }
+    uninstall::plan_uninstall(&home_dir, &repo, &host(), backup.as_ref())
+        .unwrap()
+        .execute()
+        .unwrap();
+    Ok(())
+}
+
+#[test]
+fn dir_in_the_way_of_a_dotfile_is_backed_up_and_replaced() -> io::Result<()> {
+    let home = temp_dir()?;
+    let zshrc = home.path().join(".zshrc");
+    fs::create_dir_all(zshrc.join("sub"))?;
+    fs::write(zshrc.join("sub/old"), "old")?;
+    fs::set_permissions(zshrc.join("sub"), Permissions::from_mode(0o700))?;
+
+    install_and_restore(&home, |_| {
+        assert_eq!(fs::read_to_string(&zshrc)?, "new");
+        Ok(())
+    })?;
+
+    assert_eq!(fs::read_to_string(zshrc.join("sub/old"))?, "old");
+    assert_eq!(zshrc.join("sub").metadata()?.permissions().mode() & 0o777, 0o700);
+    Ok(())
+}
+
+#[test]
+fn symlink_to_a_dir_in_the_way_of_a_dotfile_is_replaced() -> io::Result<()> {
+    let home = temp_dir()?;
+    let elsewhere = temp_dir()?;
+    let zshrc = home.path().join(".zshrc");
+    fs::write(elsewhere.path().join("old"), "old")?;
+    unix::fs::symlink(elsewhere.path(), &zshrc)?;
+
+    install_and_restore(&home, |_| {
+        assert_eq!(fs::read_to_string(&zshrc)?, "new");
+        assert_eq!(fs::read_to_string(elsewhere.path().join("old"))?, "old");
+        Ok(())
+    })?;
+
+    assert_eq!(fs::read_link(&zshrc)?, elsewhere.path());
+    Ok(())
+}
+
+#[test]
+fn file_in_the_way_of_a_dotfile_dir_is_backed_up_and_replaced() -> io::Result<()> {
+    let home = temp_dir()?;
+    let foo = home.path().join(".config/foo");
+    fs::create_dir(home.path().join(".config"))?;
+    fs::write(&foo, "old")?;
+
+    install_and_restore(&home, |_| {
+        assert_eq!(fs::read_to_string(foo.join("bar.toml"))?, "new");
+        Ok(())
+    })?;
+
+    assert_eq!(fs::read_to_string(&foo)?, "old");
+    Ok(())
+}
+
+#[test]
+fn symlink_to_a_dir_in_the_way_of_a_dotfile_dir_is_kept() -> io::Result<()> {
+    let home = temp_dir()?;
+    let elsewhere = temp_dir()?;
+    let config = home.path().join(".config");
+    unix::fs::symlink(elsewhere.path(), &config)?;
+
+    install_and_restore(&home, |repo| {
+        assert_eq!(fs::read_link(&config)?, elsewhere.path());
+        assert_eq!(
+            fs::read_link(elsewhere.path().join("foo/bar.toml"))?,
+            repo.files().as_path().join(".config/foo/bar.toml")
+        );
+        Ok(())
+    })?;
+
+    assert_eq!(fs::read_link(&config)?, elsewhere.path());
+    assert!(!elsewhere.path().join("foo/bar.toml").exists());
+    Ok(())
+}
+
+#[test]
+fn submodules_are_linked_to_their_targets() -> io::Result<()> {
+    let repo_dir = temp_dir()?;
+    let home = temp_dir()?;
+    let repo = make_repo(&repo_dir.path().canonicalize()?)?;
+    let home_dir = HomePath::new(home.path().canonicalize()?);
+    let store = BackupStore::new(repo_dir.path().join("backups"));
+    fs::write(
+        repo.root().join(".gitmodules"),
+        "[submodule \"submodules/fzf\"]\n\tpath = submodules/fzf\n\turl = https://example.com/fzf\n\ttarget = .fzf\n\
+         [submodule \"submodules/plugin\"]\n\tpath = submodules/plugin\n\turl = https://example.com/plugin\n\
+         \ttarget = .local/share/zsh/plugins/plugin\n\
+         [submodule \"submodules/unlinked\"]\n\tpath = submodules/unlinked\n\turl = https://example.com/unlinked\n",
+    )?;
+    for name in ["fzf", "plugin", "unlinked"] {
+        fs::create_dir(repo.submodules().as_path().join(name))?;
+    }
+    fs::create_dir(home.path().join(".fzf"))?;
+    fs::write(home.path().join(".fzf/old"), "old")?;
+    unix::fs::symlink(repo.submodules().as_path(), home.path().join(".dotfiles-submodules"))?;
+
+    install::plan_install(&home_dir, &repo, &host(), &store)
+        .unwrap()
+        .execute()
+        .unwrap();
+
+    let fzf = home.path().join(".fzf");
+    let plugin = home.path().join(".local/share/zsh/plugins/plugin");
+    assert_eq!(fs::read_link(&fzf)?, repo.submodules().as_path().join("fzf"));
+    assert_eq!(fs::read_link(&plugin)?, repo.submodules().as_path().join("plugin"));
+    assert!(!home.path().join(".dotfiles-submodules").is_symlink());
+    let statuses: Vec<_> = status::status(&home_dir, &repo, &host())
+        .unwrap()
+        .into_iter()
+        .filter(|status| status.layer == "submodules")
+        .map(|status| (status.file.rel_file().to_string(), status.state))
+        .collect();
+    assert_eq!(
+        statuses,
+        [
+            (".fzf".to_string(), LinkState::Ok),
+            (".local/share/zsh/plugins/plugin".to_string(), LinkState::Ok),
+        ]
+    );
+
+    let backup = store.find_backup(None).unwrap();
+    uninstall::plan_uninstall(&home_dir, &repo, &host(), Some(&backup))
+        .unwrap()
+        .execute()
+        .unwrap();
+
+    assert_eq!(fs::read_to_string(fzf.join("old"))?, "old");
+    assert!(!plugin.is_symlink());
+    Ok(())
+}
+
+#[test]
+fn relink_points_links_at_the_moved_repo() -> io::Result<()> {
+    let repo_dir = temp_dir()?;
+    let home = temp_dir()?;
+    let old_root = repo_dir.path().canonicalize()?.join("old");
+    let repo = make_repo(&old_root)?;
+    let home_dir = HomePath::new(home.path().canonicalize()?);
+    let store = BackupStore::new(repo_dir.path().join("backups"));
+    fs::write(repo.files().as_path().join(".config/foo").join(FOLD_MARKER_FILE), "")?;
+    fs::write(repo.files().as_path().join(".gitconfig"), "new")?;
+    let other_root = repo_dir.path().canonicalize()?.join("other");
+    make_repo(&other_root)?;
+    fs::write(other_root.join("files/.gitconfig"), "other")?;
+
+    install::plan_install(&home_dir, &repo, &host(), &store)
+        .unwrap()
+        .execute()
+        .unwrap();
+    // The user points one link at another clone of the dotfiles.
+    fs::remove_file(home.path().join(".gitconfig"))?;
+    unix::fs::symlink(other_root.join("files/.gitconfig"), home.path().join(".gitconfig"))?;
+    let new_root = repo_dir.path().canonicalize()?.join("new");
+    fs::rename(&old_root, &new_root)?;
+    let repo = Repo::new(&new_root);
+
+    relink::plan_relink(&home_dir, &repo, &host())
+        .unwrap()
+        .execute()
+        .unwrap();
+
+    assert_eq!(
+        fs::read_link(home.path().join(".zshrc"))?,
+        new_root.join("files/.zshrc")
+    );
+    assert_eq!(
+        fs::read_link(home.path().join(".config/foo"))?,
+        new_root.join("files/.config/foo")
+    );
+    assert_eq!(
+        fs::read_link(home.path().join(".gitconfig"))?,
+        other_root.join("files/.gitconfig")
+    );
+    Ok(())
+}
+
+#[test]
+fn relink_leaves_links_into_other_worktrees_alone() -> io::Result<()> {
+    let repo_dir = temp_dir()?;
+    let home = temp_dir()?;
+    let root = repo_dir.path().canonicalize()?;
+    let repo = make_repo(&root.join("main"))?;
+    let home_dir = HomePath::new(home.path().canonicalize()?);
+    let store = BackupStore::new(root.join("backups"));
+    let git = |args: &[&str]| -> io::Result<()> {
+        let status = Command::new("git")
+            .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(repo.root())
+            .status()?;
+        assert!(status.success());
+        Ok(())
+    };
+    git(&["add", "."])?;
+    git(&["commit", "-q", "-m", "Add dotfiles"])?;
+    git(&[
+        "worktree",
+        "add",
+        "-q",
+        "--detach",
+        root.join("worktree").to_str().unwrap(),
+    ])?;
+    install::plan_install(&home_dir, &repo, &host(), &store)
+        .unwrap()
+        .execute()
+        .unwrap();
+
+    relink::plan_relink(&home_dir, &Repo::new(root.join("worktree")), &host())
+        .unwrap()
+        .execute()
+        .unwrap();
+
+    assert_eq!(
+        fs::read_link(home.path().join(".zshrc"))?,
+        root.join("main/files/.zshrc")
+    );
+    Ok(())
+}
+
+#[test]
+fn repo_is_found_from_inside_a_worktree() -> io::Result<()> {
+    let repo_dir = temp_dir()?;
+    let root = repo_dir.path().canonicalize()?;
+    let repo = make_repo(&root.join("main"))?;
+    let git = |args: &[&str]| -> io::Result<()> {
+        let status = Command::new("git")
+            .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(repo.root())
+            .status()?;
+        assert!(status.success());
+        Ok(())
+    };
+    git(&["add", "."])?;
+    git(&["commit", "-q", "-m", "Add dotfiles"])?;
+    git(&[
+        "worktree",
+        "add",
+        "-q",
+        "--detach",
+        root.join("worktree").to_str().unwrap(),
+    ])?;
+    assert!(root.join("worktree/.git").is_file());
+
+    let found = Repo::find(Some(&root.join("worktree/files/.config"))).unwrap();
+
+    assert_eq!(found, Repo::new(root.join("worktree")));
+    let status = Command::new("git")
+        .arg("init")
+        .arg("-q")
+        .arg(root.join("other"))
+        .status()?;
+    assert!(status.success());
+    assert!(Repo::find(Some(&root.join("other"))).is_err());
+    Ok(())
+}